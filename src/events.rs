@@ -0,0 +1,89 @@
+use serde_json::json;
+use tokio::sync::broadcast::{self, Receiver, Sender};
+
+/// Something notable that happened, for reporting to monitoring clients.
+#[derive(Debug, Clone)]
+pub enum Event {
+    /// A stream subscriber was added. `subscribers` is the new total.
+    ClientConnected { subscribers: u64 },
+    /// A stream subscriber went away. `subscribers` is the new total.
+    ClientDisconnected { subscribers: u64 },
+    /// A stream fell behind and skipped some frames.
+    Lagged { skipped: u64 },
+    /// The pipeline changed state.
+    StateChanged { old: String, new: String },
+    /// An element of the pipeline reported an error.
+    Error { source: String, message: String },
+    /// An element of the pipeline reported a problem that it carried on from.
+    Warning { source: String, message: String },
+    /// The format of the encoded video changed while it was running, e.g. the camera switched
+    /// resolution by itself. `caps` describes the new format.
+    FormatChanged {
+        width: Option<i32>,
+        height: Option<i32>,
+        caps: String,
+    },
+}
+
+impl Event {
+    /// A short name for the kind of event.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Event::ClientConnected { .. } => "client-connected",
+            Event::ClientDisconnected { .. } => "client-disconnected",
+            Event::Lagged { .. } => "lagged",
+            Event::StateChanged { .. } => "state-changed",
+            Event::Error { .. } => "error",
+            Event::Warning { .. } => "warning",
+            Event::FormatChanged { .. } => "format-changed",
+        }
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        let mut value = match self {
+            Event::ClientConnected { subscribers } | Event::ClientDisconnected { subscribers } => {
+                json!({ "subscribers": subscribers })
+            }
+            Event::Lagged { skipped } => json!({ "skipped": skipped }),
+            Event::StateChanged { old, new } => json!({ "old": old, "new": new }),
+            Event::Error { source, message } | Event::Warning { source, message } => {
+                json!({ "source": source, "message": message })
+            }
+            Event::FormatChanged {
+                width,
+                height,
+                caps,
+            } => json!({ "width": width, "height": height, "caps": caps }),
+        };
+        value["event"] = self.name().into();
+        value
+    }
+}
+
+/// A channel of events, which any number of listeners can subscribe to.
+#[derive(Debug, Clone)]
+pub struct Events {
+    sender: Sender<Event>,
+}
+
+impl Events {
+    pub fn new() -> Self {
+        let (sender, _) = broadcast::channel(64);
+        Self { sender }
+    }
+
+    pub fn send(&self, event: Event) {
+        // It's fine if no one is listening.
+        let _ = self.sender.send(event);
+    }
+
+    pub fn subscribe(&self) -> Receiver<Event> {
+        self.sender.subscribe()
+    }
+}
+
+impl Default for Events {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,189 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{bail, Context};
+use bytes::{BufMut, Bytes, BytesMut};
+use gstreamer::glib::DateTime;
+
+/// Longest comment that fits in the EXIF segment, with room to spare for the other fields.
+pub const MAX_COMMENT_BYTES: usize = 60000;
+
+/// Longest text that fits in a JPEG COM segment.
+pub const MAX_COM_BYTES: usize = u16::MAX as usize - 2;
+
+const TYPE_ASCII: u16 = 2;
+const TYPE_LONG: u16 = 4;
+
+const TAG_IMAGE_DESCRIPTION: u16 = 0x010e;
+const TAG_EXIF_IFD: u16 = 0x8769;
+const TAG_DATE_TIME_ORIGINAL: u16 = 0x9003;
+const TAG_OFFSET_TIME_ORIGINAL: u16 = 0x9011;
+const TAG_SUB_SEC_TIME_ORIGINAL: u16 = 0x9291;
+
+/// One field of an IFD (a directory of tagged values in a TIFF structure).
+struct Entry {
+    tag: u16,
+    kind: u16,
+    count: u32,
+    data: Vec<u8>,
+}
+
+impl Entry {
+    fn ascii(tag: u16, s: &str) -> Self {
+        let mut data = s.as_bytes().to_vec();
+        data.push(0);
+        Self {
+            tag,
+            kind: TYPE_ASCII,
+            count: data.len() as u32,
+            data,
+        }
+    }
+
+    fn long(tag: u16, value: u32) -> Self {
+        Self {
+            tag,
+            kind: TYPE_LONG,
+            count: 1,
+            data: value.to_be_bytes().to_vec(),
+        }
+    }
+
+    /// Values of up to four bytes go in the entry itself; longer ones go after the IFD.
+    fn is_inline(&self) -> bool {
+        self.data.len() <= 4
+    }
+
+    /// Bytes needed after the IFD for this entry's value, padded to keep offsets even.
+    fn extra_len(&self) -> usize {
+        if self.is_inline() {
+            0
+        } else {
+            self.data.len() + self.data.len() % 2
+        }
+    }
+}
+
+fn ifd_len(entries: &[Entry]) -> usize {
+    2 + 12 * entries.len() + 4 + entries.iter().map(Entry::extra_len).sum::<usize>()
+}
+
+/// Write an IFD with no next IFD. `out` must start at the TIFF header, since offsets are from
+/// there, and entries must be sorted by tag.
+fn write_ifd(out: &mut BytesMut, entries: &[Entry]) {
+    let mut data_offset = out.len() + 2 + 12 * entries.len() + 4;
+    out.put_u16(entries.len() as u16);
+    for e in entries {
+        out.put_u16(e.tag);
+        out.put_u16(e.kind);
+        out.put_u32(e.count);
+        if e.is_inline() {
+            out.put_slice(&e.data);
+            out.put_bytes(0, 4 - e.data.len());
+        } else {
+            out.put_u32(data_offset as u32);
+            data_offset += e.extra_len();
+        }
+    }
+    out.put_u32(0);
+    for e in entries.iter().filter(|e| !e.is_inline()) {
+        out.put_slice(&e.data);
+        out.put_bytes(0, e.extra_len() - e.data.len());
+    }
+}
+
+/// Make the TIFF structure for an EXIF segment with the given capture time and description.
+fn tiff(captured: SystemTime, comment: Option<&str>) -> anyhow::Result<BytesMut> {
+    let since_epoch = captured
+        .duration_since(UNIX_EPOCH)
+        .context("capture time is before 1970")?;
+    let local = DateTime::from_unix_local(since_epoch.as_secs() as i64)
+        .context("failed to convert capture time to local time")?;
+    let date_time = local
+        .format("%Y:%m:%d %H:%M:%S")
+        .context("failed to format capture time")?;
+    let offset = local
+        .format("%:z")
+        .context("failed to format time zone offset")?;
+
+    let mut ifd0 = vec![];
+    if let Some(comment) = comment {
+        ifd0.push(Entry::ascii(TAG_IMAGE_DESCRIPTION, comment));
+    }
+    // Placeholder; the Exif IFD's offset isn't known until IFD0 is laid out.
+    ifd0.push(Entry::long(TAG_EXIF_IFD, 0));
+    let exif_ifd_offset = 8 + ifd_len(&ifd0);
+    ifd0.last_mut().unwrap().data = (exif_ifd_offset as u32).to_be_bytes().to_vec();
+
+    let exif_ifd = [
+        Entry::ascii(TAG_DATE_TIME_ORIGINAL, &date_time),
+        Entry::ascii(TAG_OFFSET_TIME_ORIGINAL, &offset),
+        Entry::ascii(
+            TAG_SUB_SEC_TIME_ORIGINAL,
+            &format!("{:03}", since_epoch.subsec_millis()),
+        ),
+    ];
+
+    let mut out = BytesMut::with_capacity(exif_ifd_offset + ifd_len(&exif_ifd));
+    // Big-endian ("Motorola") byte order, the magic number, and the offset of IFD0.
+    out.put_slice(b"MM");
+    out.put_u16(42);
+    out.put_u32(8);
+    write_ifd(&mut out, &ifd0);
+    write_ifd(&mut out, &exif_ifd);
+    Ok(out)
+}
+
+/// Add an EXIF segment to a JPEG, recording when it was captured and optionally a description.
+pub fn insert(jpeg: &[u8], captured: SystemTime, comment: Option<&str>) -> anyhow::Result<Bytes> {
+    if !jpeg.starts_with(&[0xff, 0xd8]) {
+        bail!("frame isn't a JPEG image");
+    }
+    if comment.map_or(0, str::len) > MAX_COMMENT_BYTES {
+        bail!("EXIF comment is longer than {MAX_COMMENT_BYTES} bytes");
+    }
+    let tiff = tiff(captured, comment)?;
+
+    // Readers expect a JFIF APP0 segment to come first, if there is one.
+    let mut pos = 2;
+    if jpeg.len() >= 6 && jpeg[2..4] == [0xff, 0xe0] {
+        pos += 2 + usize::from(u16::from_be_bytes([jpeg[4], jpeg[5]]));
+    }
+    if pos > jpeg.len() {
+        bail!("JPEG APP0 segment is truncated");
+    }
+
+    let mut out = BytesMut::with_capacity(jpeg.len() + 10 + tiff.len());
+    out.put_slice(&jpeg[..pos]);
+    out.put_slice(&[0xff, 0xe1]);
+    out.put_u16((2 + 6 + tiff.len()) as u16);
+    out.put_slice(b"Exif\0\0");
+    out.put_slice(&tiff);
+    out.put_slice(&jpeg[pos..]);
+    Ok(out.freeze())
+}
+
+/// Add a COM (comment) segment with the given text to a JPEG image, after its APPn segments
+/// (where JFIF and EXIF are), so readers that expect those first still find them.
+pub fn insert_comment(jpeg: &[u8], comment: &str) -> anyhow::Result<Bytes> {
+    if !jpeg.starts_with(&[0xff, 0xd8]) {
+        bail!("frame isn't a JPEG image");
+    }
+    if comment.len() > MAX_COM_BYTES {
+        bail!("JPEG comment is longer than {MAX_COM_BYTES} bytes");
+    }
+    let mut pos = 2;
+    while jpeg.len() >= pos + 4 && jpeg[pos] == 0xff && (0xe0..=0xef).contains(&jpeg[pos + 1]) {
+        pos += 2 + usize::from(u16::from_be_bytes([jpeg[pos + 2], jpeg[pos + 3]]));
+    }
+    if pos > jpeg.len() {
+        bail!("JPEG APPn segment is truncated");
+    }
+
+    let mut out = BytesMut::with_capacity(jpeg.len() + 4 + comment.len());
+    out.put_slice(&jpeg[..pos]);
+    out.put_slice(&[0xff, 0xfe]);
+    out.put_u16((2 + comment.len()) as u16);
+    out.put_slice(comment.as_bytes());
+    out.put_slice(&jpeg[pos..]);
+    Ok(out.freeze())
+}
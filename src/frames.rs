@@ -1,93 +1,926 @@
+use std::collections::VecDeque;
+use std::ffi::OsString;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
 use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
 use std::sync::Arc;
-use std::task::Poll;
-use std::time::Duration;
+use std::task::{ready, Poll};
+use std::time::{Duration, Instant, SystemTime};
 
+use anyhow::{bail, Context};
 use bytes::{Bytes, BytesMut};
-use futures::Stream;
+use futures::{FutureExt, Stream, StreamExt};
+use gstreamer::Caps;
+use tokio::io::AsyncWriteExt;
 use tokio::sync::broadcast::{self, Sender};
-use tokio::sync::{Mutex, MutexGuard};
+use tokio::sync::{mpsc, Mutex};
+use tokio_stream::wrappers::errors::BroadcastStreamRecvError;
 use tokio_stream::wrappers::BroadcastStream;
+use tokio_util::sync::{CancellationToken, WaitForCancellationFutureOwned};
 
-use crate::video::Video;
+use crate::events::{Event, Events};
+use crate::stats::{FrameStats, Stats};
+use crate::video::{Codec, Video};
 
-pub struct Frames {
-    video: Arc<Video>,
-    inner: Mutex<FramesInner>,
+/// One encoded frame of video.
+#[derive(Debug, Clone)]
+pub struct Frame {
+    pub data: Bytes,
+    /// Capture timestamp, relative to when the video was started. This is the buffer's DTS.
+    pub timestamp: Option<Duration>,
+    /// The buffer's presentation timestamp.
+    pub pts: Option<Duration>,
+    /// How long the buffer says the frame lasts.
+    pub duration: Option<Duration>,
+    /// Counts up from zero each time the video is started. Gaps mean frames were dropped.
+    pub number: u64,
+    /// How long it took from capture until the encoded frame came out of the pipeline.
+    pub latency: Option<Duration>,
+    /// Wall-clock time the frame was captured, as near as can be told.
+    pub captured: SystemTime,
 }
 
-struct FramesInner {
-    count: u64,
-    sender: Sender<(Bytes, Option<Duration>)>,
+/// How many frames' worth of memory to allocate at a time, for copying frames out of the
+/// pipeline. An allocation is freed once every frame in it is gone.
+const FRAMES_PER_ALLOCATION: usize = 4;
+
+/// A frame on its way to the streams. Its data can be dropped while it waits, to keep the total
+/// within the byte budget.
+type Slot = Arc<std::sync::Mutex<Option<Frame>>>;
+
+/// The frames that may still be waiting in the broadcast channel, newest last.
+struct Buffered {
+    /// Most frames the channel holds.
+    max_frames: usize,
+    /// Most bytes of frames to have waiting, or `None` for no limit.
+    budget: Option<usize>,
+    slots: VecDeque<Slot>,
+}
+
+impl Buffered {
+    fn push(&mut self, slot: Slot) {
+        self.slots.push_back(slot);
+        if self.slots.len() > self.max_frames {
+            self.slots.pop_front();
+        }
+        let Some(budget) = self.budget else {
+            return;
+        };
+        let mut bytes = self.bytes();
+        for slot in &self.slots {
+            if bytes <= budget {
+                break;
+            }
+            if Arc::strong_count(slot) > 1 {
+                if let Some(frame) = slot.lock().unwrap().take() {
+                    bytes -= frame.data.len();
+                }
+            }
+        }
+    }
+
+    /// Bytes of frames that haven't been received by every stream yet. The channel drops its
+    /// reference to a frame once they all have, leaving only ours.
+    fn bytes(&self) -> usize {
+        self.slots
+            .iter()
+            .filter(|slot| Arc::strong_count(slot) > 1)
+            .filter_map(|slot| slot.lock().unwrap().as_ref().map(|f| f.data.len()))
+            .sum()
+    }
+}
+
+/// Most memory the pre-record history may use, regardless of how long it's set to be.
+const MAX_HISTORY_BYTES: usize = 256 << 20;
+
+/// The most recent frames, going back a fixed amount of time.
+struct History {
+    window: Duration,
+    frames: VecDeque<(Instant, Frame)>,
+    bytes: usize,
+}
+
+impl History {
+    fn push(&mut self, frame: Frame) {
+        let now = Instant::now();
+        self.bytes += frame.data.len();
+        self.frames.push_back((now, frame));
+        while let Some((t, old)) = self.frames.front() {
+            if now.duration_since(*t) <= self.window && self.bytes <= MAX_HISTORY_BYTES {
+                break;
+            }
+            self.bytes -= old.data.len();
+            self.frames.pop_front();
+        }
+    }
+}
+
+/// Settings for how frames from the video are handled.
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// How much of the most recent video to keep in memory, to be included in clips saved with
+    /// [`Frames::save_clip`]. Zero disables clips.
+    pub pre_record: Duration,
+    /// How many frames can wait to be sent to a stream before the oldest ones are dropped. At
+    /// least 1.
+    pub buffer_frames: usize,
+    /// Most bytes of frames to have waiting for slow streams. Past this, the oldest are dropped.
+    pub buffer_bytes: Option<usize>,
+    /// Only one of every this many frames from the video is used at all; the rest are thrown
+    /// away as soon as they come out of the pipeline.
+    pub decimate: u64,
+    /// How long to throw away frames for after the video starts, while the camera settles.
+    pub warmup: Duration,
+    /// Keep frames under a size, by changing the encoder quality.
+    pub size_cap: Option<SizeCap>,
+    /// Send frames to streams at even intervals, rather than as soon as they're encoded.
+    pub smooth_output: bool,
+    /// Drop JPEG frames that are obviously broken, rather than sending them on.
+    pub check_frames: bool,
+    /// How long to keep the video running after the last stream goes away, in case another one
+    /// comes along.
+    pub idle_timeout: Duration,
+}
+
+/// Smallest a real JPEG frame could be; anything shorter is broken.
+const MIN_JPEG_BYTES: usize = 100;
+
+/// Whether a frame looks like a whole JPEG image: long enough, and starting and ending with the
+/// right markers. Some cameras pad frames with zeros after the end, which is fine.
+fn is_whole_jpeg(data: &[u8]) -> bool {
+    let end = data.iter().rposition(|&b| b != 0).map_or(0, |i| i + 1);
+    let data = &data[..end];
+    data.len() >= MIN_JPEG_BYTES && data.starts_with(&[0xff, 0xd8]) && data.ends_with(&[0xff, 0xd9])
+}
+
+/// Send a frame to every stream.
+fn broadcast(sender: &Sender<Slot>, buffered: &std::sync::Mutex<Buffered>, frame: Frame) {
+    // Sending can only fail if there are no receivers, which is normal just after starting and
+    // just before stopping.
+    let slot = Arc::new(std::sync::Mutex::new(Some(frame)));
+    if sender.send(slot.clone()).is_err() {
+        trace!("no streams to send frame to");
+    }
+    buffered.lock().unwrap().push(slot);
+}
+
+/// Send frames on to streams at even intervals, going by the recent average frame rate. Each
+/// frame is held for at most two intervals after it arrived, so if the camera gets faster, the
+/// delay doesn't keep growing.
+async fn pace(
+    mut frames: mpsc::UnboundedReceiver<(Instant, Frame)>,
+    stats: Arc<std::sync::Mutex<FrameStats>>,
+    sender: Sender<Slot>,
+    buffered: Arc<std::sync::Mutex<Buffered>>,
+) {
+    let mut next: Option<Instant> = None;
+    while let Some((arrived, frame)) = frames.recv().await {
+        let fps = stats.lock().unwrap().get().average.frames_per_sec;
+        if fps > 0. {
+            let interval = Duration::from_secs_f64(1. / fps);
+            let due = next
+                .unwrap_or(arrived)
+                .clamp(arrived, arrived + interval * 2);
+            tokio::time::sleep_until(due.into()).await;
+            next = Some(due + interval);
+        }
+        broadcast(&sender, &buffered, frame);
+    }
+}
+
+/// Keeps encoded frames under a size, by lowering the encoder quality whenever one goes over,
+/// and raising it again once frames have been well under for a while. Frames over the size are
+/// still sent; it's the following ones that get smaller.
+#[derive(Debug, Clone, Copy)]
+pub struct SizeCap {
+    pub max_bytes: usize,
+    pub min_quality: i32,
+    pub max_quality: i32,
+}
+
+impl SizeCap {
+    const STEP_DOWN: i32 = 5;
+    const STEP_UP: i32 = 1;
+    /// How many frames in a row have to be under 3/4 of the cap before raising the quality.
+    const CALM_FRAMES: u32 = 30;
+
+    /// Adjust the quality after a frame of `size` bytes. Returns whether it was over the cap.
+    fn check(&self, video: &Video, size: usize, calm: &AtomicU32) -> bool {
+        let Some(quality) = video.quality() else {
+            return size > self.max_bytes;
+        };
+        let new_quality = if size > self.max_bytes {
+            calm.store(0, Ordering::Relaxed);
+            (quality - Self::STEP_DOWN).max(self.min_quality)
+        } else if size <= self.max_bytes / 4 * 3
+            && calm.fetch_add(1, Ordering::Relaxed) + 1 >= Self::CALM_FRAMES
+        {
+            calm.store(0, Ordering::Relaxed);
+            (quality + Self::STEP_UP).min(self.max_quality)
+        } else {
+            quality
+        };
+        if new_quality != quality {
+            debug!(
+                "frame was {size} bytes; changing encoder quality from {quality} to {new_quality}"
+            );
+            if let Err(e) = video.set_quality(new_quality) {
+                error!("{e:#}");
+            }
+        }
+        size > self.max_bytes
+    }
+}
+
+pub struct Frames {
+    /// The current video. This can be replaced, while streams carry on.
+    video: std::sync::RwLock<Arc<Video>>,
+    /// Number of active streams. This is changed without locking, except when going between 0
+    /// and 1, which happens while holding `transition`.
+    count: AtomicU64,
+    /// Held while starting or stopping the video.
+    transition: Mutex<()>,
+    idle_timeout: Duration,
+    /// Whether the video is still running with no streams, waiting out `idle_timeout`. Only
+    /// changed while holding `transition`.
+    lingering: AtomicBool,
+    /// Counts up each time the video starts lingering, so a stop that was put off can tell if it's
+    /// still the latest one.
+    linger_generation: AtomicU64,
+    sender: Sender<Slot>,
+    /// How many frames the channels hold.
+    buffer_frames: usize,
+    /// Thumbnails, if the video makes them. These are small, so there's no byte budget.
+    thumb_sender: Sender<Slot>,
+    buffered: Arc<std::sync::Mutex<Buffered>>,
+    /// The most recent frame, while the video is running.
+    latest: Arc<std::sync::Mutex<Option<Frame>>>,
+    stats: Arc<std::sync::Mutex<FrameStats>>,
+    /// Recent frames, for saving clips that start before they were asked for. `None` if
+    /// pre-recording is disabled.
+    history: Option<Arc<std::sync::Mutex<History>>>,
+    /// Total frames skipped by streams that fell behind.
+    lagged: AtomicU64,
+    /// Only every this many frames from the video are used.
+    decimate: u64,
+    /// Frames from this long after the video starts are thrown away.
+    warmup: Duration,
+    size_cap: Option<SizeCap>,
+    smooth_output: bool,
+    /// Total frames bigger than the size cap.
+    oversize: Arc<AtomicU64>,
+    check_frames: bool,
+    /// Total frames dropped for being broken.
+    corrupt: Arc<AtomicU64>,
+    /// Cancelled (and replaced) when the video fails, to end the streams getting it.
+    ended: Arc<std::sync::Mutex<CancellationToken>>,
+    events: Events,
 }
 
 impl Frames {
-    pub fn new(video: Arc<Video>) -> Self {
-        let (sender, _) = broadcast::channel(16);
-        let inner = FramesInner { count: 0, sender };
+    pub fn new(video: Arc<Video>, events: Events, config: &Config) -> Self {
+        let buffer_frames = config.buffer_frames.max(1);
+        let (sender, _) = broadcast::channel(buffer_frames);
+        let (thumb_sender, _) = broadcast::channel(buffer_frames);
+        let history = (!config.pre_record.is_zero()).then(|| {
+            Arc::new(std::sync::Mutex::new(History {
+                window: config.pre_record,
+                frames: VecDeque::new(),
+                bytes: 0,
+            }))
+        });
         Self {
-            video,
-            inner: Mutex::new(inner),
+            video: std::sync::RwLock::new(video),
+            count: AtomicU64::new(0),
+            transition: Mutex::new(()),
+            idle_timeout: config.idle_timeout,
+            lingering: AtomicBool::new(false),
+            linger_generation: AtomicU64::new(0),
+            sender,
+            buffer_frames,
+            thumb_sender,
+            buffered: Arc::new(std::sync::Mutex::new(Buffered {
+                max_frames: buffer_frames,
+                budget: config.buffer_bytes,
+                slots: VecDeque::new(),
+            })),
+            latest: Arc::new(std::sync::Mutex::new(None)),
+            stats: Arc::new(std::sync::Mutex::new(FrameStats::default())),
+            history,
+            lagged: AtomicU64::new(0),
+            decimate: config.decimate.max(1),
+            warmup: config.warmup,
+            size_cap: config.size_cap,
+            smooth_output: config.smooth_output,
+            oversize: Arc::new(AtomicU64::new(0)),
+            check_frames: config.check_frames,
+            corrupt: Arc::new(AtomicU64::new(0)),
+            ended: Arc::new(std::sync::Mutex::new(CancellationToken::new())),
+            events,
+        }
+    }
+
+    pub fn video(&self) -> Arc<Video> {
+        self.video.read().unwrap().clone()
+    }
+
+    /// Switch to a new video, shutting down the old one. If the video is running, the new one is
+    /// started in its place, and existing streams get their frames from it from then on.
+    ///
+    /// With `hold_open`, the new video is held open (see [`Video::hold_open`]) once the old one
+    /// has let go of the device.
+    pub async fn replace_video(&self, video: Arc<Video>, hold_open: bool) -> anyhow::Result<()> {
+        let _guard = self.transition.lock().await;
+        let old = std::mem::replace(&mut *self.video.write().unwrap(), video.clone());
+        if let Err(e) = old.shutdown().await {
+            warn!("error shutting down old video: {e:#}");
         }
+        // The new one isn't running, so there's nothing to put off stopping.
+        self.lingering.store(false, Ordering::SeqCst);
+        self.latest.lock().unwrap().take();
+        if hold_open {
+            video.hold_open()?;
+        }
+        if self.count.load(Ordering::SeqCst) > 0 {
+            self.start();
+        }
+        Ok(())
+    }
+
+    /// The most recently captured frame, if the video is running and has produced one.
+    pub fn latest(&self) -> Option<Frame> {
+        self.latest.lock().unwrap().clone()
+    }
+
+    pub fn events(&self) -> &Events {
+        &self.events
+    }
+
+    pub fn stats(&self) -> Stats {
+        self.stats.lock().unwrap().get()
+    }
+
+    /// Total frames skipped by streams that fell behind.
+    pub fn lagged(&self) -> u64 {
+        self.lagged.load(Ordering::Relaxed)
+    }
+
+    /// Count frames a stream skipped because it fell behind.
+    fn count_lag(&self, skipped: u64) {
+        self.lagged.fetch_add(skipped, Ordering::Relaxed);
+        self.events.send(Event::Lagged { skipped });
+    }
+
+    /// Total frames dropped for being broken, since starting.
+    pub fn corrupt(&self) -> u64 {
+        self.corrupt.load(Ordering::Relaxed)
+    }
+
+    /// Total frames that were bigger than the size cap, if there is one.
+    pub fn oversize(&self) -> u64 {
+        self.oversize.load(Ordering::Relaxed)
+    }
+
+    /// Bytes of frames waiting to be sent to streams.
+    pub fn buffered_bytes(&self) -> usize {
+        self.buffered.lock().unwrap().bytes()
+    }
+
+    /// Number of streams currently receiving frames.
+    pub fn subscribers(&self) -> u64 {
+        self.count.load(Ordering::SeqCst)
+    }
+
+    /// Whether the video is running for streams: there are some, or the last one went away less
+    /// than `idle_timeout` ago.
+    pub fn is_running(&self) -> bool {
+        self.subscribers() > 0 || self.lingering.load(Ordering::SeqCst)
     }
 
     pub async fn stream(self: Arc<Self>) -> FrameStream {
+        let receiver = self.sender.subscribe();
+        self.open_stream(receiver).await
+    }
+
+    /// Like [`Frames::stream`], but of thumbnails. These only come if the video makes them
+    /// (see [`Video::has_thumbnails`]); the stream still keeps the video running either way.
+    pub async fn thumbnail_stream(self: Arc<Self>) -> FrameStream {
+        let receiver = self.thumb_sender.subscribe();
+        self.open_stream(receiver).await
+    }
+
+    async fn open_stream(self: Arc<Self>, receiver: broadcast::Receiver<Slot>) -> FrameStream {
         debug!("new streamer");
-        let mut inner = self.inner.lock().await;
-        let receiver = if inner.count == 0 {
-            info!("first streamer");
-            let (sender, receiver) = broadcast::channel(16);
-            inner.sender = sender;
-            inner.count = 1;
-            self.start(&inner);
-            receiver
-        } else {
-            debug!("{} previous streams; subscribing", inner.count);
-            inner.count += 1;
-            inner.sender.subscribe()
+        let count = loop {
+            let count = self.count.load(Ordering::SeqCst);
+            if count != 0 {
+                if self
+                    .count
+                    .compare_exchange(count, count + 1, Ordering::SeqCst, Ordering::SeqCst)
+                    .is_ok()
+                {
+                    debug!("{count} previous streams; subscribing");
+                    break count + 1;
+                }
+                continue;
+            }
+            let _guard = self.transition.lock().await;
+            if self
+                .count
+                .compare_exchange(0, 1, Ordering::SeqCst, Ordering::SeqCst)
+                .is_ok()
+            {
+                if self.lingering.swap(false, Ordering::SeqCst) {
+                    info!("first streamer; the video is still running");
+                } else {
+                    info!("first streamer");
+                    self.start();
+                }
+                break 1;
+            }
         };
+        self.events
+            .send(Event::ClientConnected { subscribers: count });
+        let ended = self.ended.lock().unwrap().clone().cancelled_owned();
         FrameStream {
             parent: self.clone(),
             stream: BroadcastStream::new(receiver),
+            ended: Box::pin(ended),
+            interval: None,
+            next_due: None,
+            started: Instant::now(),
+            lags: Lags::default(),
+            sent: 0,
+            client: None,
+        }
+    }
+
+    /// Get exactly one new frame, starting the video if it isn't running already, and letting it
+    /// stop again afterwards if nothing else is using it.
+    pub async fn capture_one(self: Arc<Self>, timeout: Duration) -> anyhow::Result<Frame> {
+        let mut stream = self.clone().stream().await;
+        match tokio::time::timeout(timeout, stream.next()).await {
+            Ok(Some(frame)) => Ok(frame),
+            Ok(None) => bail!("no frames from video source"),
+            Err(_) => match self.video().last_error() {
+                Some(e) => bail!("no frame from video source within {timeout:?}: {e}"),
+                None => bail!("timed out after {timeout:?} waiting for a frame from video source"),
+            },
+        }
+    }
+
+    /// Adjust the encoder quality between `min` and `max`: lower it when streams fall behind,
+    /// and raise it again once they've kept up for a while. This runs forever, so give it a task
+    /// of its own.
+    pub async fn adapt_quality(self: Arc<Self>, min: i32, max: i32) {
+        const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+        const STEP_DOWN: i32 = 10;
+        const STEP_UP: i32 = 5;
+        // How many checks in a row with no lag before raising the quality.
+        const CALM_CHECKS: u32 = 5;
+
+        let mut video = self.video();
+        let Some(mut quality) = video.quality() else {
+            warn!("the encoder has no quality setting; not adapting it");
+            return;
+        };
+        quality = quality.clamp(min, max);
+        if let Err(e) = video.set_quality(quality) {
+            error!("{e:#}");
+            return;
+        }
+        let mut last_lagged = self.lagged();
+        let mut calm = 0;
+        loop {
+            tokio::time::sleep(CHECK_INTERVAL).await;
+            let current = self.video();
+            if !Arc::ptr_eq(&current, &video) {
+                // The video was replaced; carry on from the same quality.
+                video = current;
+                if let Err(e) = video.set_quality(quality) {
+                    error!("{e:#}");
+                }
+            }
+            let lagged = self.lagged();
+            let new_quality = if lagged != last_lagged {
+                calm = 0;
+                (quality - STEP_DOWN).max(min)
+            } else {
+                calm += 1;
+                if calm < CALM_CHECKS {
+                    continue;
+                }
+                calm = 0;
+                (quality + STEP_UP).min(max)
+            };
+            last_lagged = lagged;
+            if new_quality != quality {
+                info!("adjusting encoder quality from {quality} to {new_quality}");
+                quality = new_quality;
+                if let Err(e) = video.set_quality(quality) {
+                    error!("{e:#}");
+                }
+            }
         }
     }
 
-    fn start(&self, inner: &MutexGuard<'_, FramesInner>) {
+    /// Rebuild the video at a lower resolution when streams keep falling behind, and go back up
+    /// once they've kept up for a while. `sizes` are the resolutions to step through, starting
+    /// with the current one and getting smaller, and `build` makes a video at one of them. This
+    /// runs forever, so give it a task of its own.
+    pub async fn adapt_resolution<F>(
+        self: Arc<Self>,
+        sizes: Vec<(u32, u32)>,
+        hold_open: bool,
+        build: F,
+    ) where
+        F: Fn((u32, u32)) -> anyhow::Result<Arc<Video>>,
+    {
+        const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+        // How many checks in a row with lag before lowering the resolution.
+        const LAG_CHECKS: u32 = 5;
+        // How many checks in a row with no lag before raising it again.
+        const CALM_CHECKS: u32 = 60;
+
+        let mut index = 0;
+        let mut video = self.video();
+        let mut last_lagged = self.lagged();
+        let mut lagging = 0;
+        let mut calm = 0;
+        loop {
+            tokio::time::sleep(CHECK_INTERVAL).await;
+            let lagged = self.lagged();
+            if !Arc::ptr_eq(&self.video(), &video) {
+                // Replaced by something else, like a restart, which goes back to the configured
+                // resolution.
+                video = self.video();
+                index = 0;
+                (lagging, calm, last_lagged) = (0, 0, lagged);
+                continue;
+            }
+            if lagged != last_lagged {
+                lagging += 1;
+                calm = 0;
+            } else {
+                calm += 1;
+                lagging = 0;
+            }
+            last_lagged = lagged;
+            let new_index = if lagging >= LAG_CHECKS && index + 1 < sizes.len() {
+                index + 1
+            } else if calm >= CALM_CHECKS && index > 0 {
+                index - 1
+            } else {
+                continue;
+            };
+            (lagging, calm) = (0, 0);
+
+            let (w, h) = sizes[index];
+            let (new_w, new_h) = sizes[new_index];
+            if new_index > index {
+                info!("streams keep falling behind; lowering resolution from {w}x{h} to {new_w}x{new_h}");
+            } else {
+                info!("streams have kept up; raising resolution from {w}x{h} to {new_w}x{new_h}");
+            }
+            let new = match build(sizes[new_index]) {
+                Ok(new) => new,
+                Err(e) => {
+                    error!("failed to make video at {new_w}x{new_h}: {e:#}");
+                    continue;
+                }
+            };
+            // The new video is in place even if this fails.
+            if let Err(e) = self.replace_video(new, hold_open).await {
+                error!("{e:#}");
+            }
+            video = self.video();
+            index = new_index;
+            last_lagged = self.lagged();
+        }
+    }
+
+    /// Whether clips can be saved, i.e. pre-recording is enabled.
+    pub fn can_save_clips(&self) -> bool {
+        self.history.is_some()
+    }
+
+    /// Write the pre-recorded frames, plus the next `after` worth of new ones, to a file. The
+    /// frames are written back to back, as an MJPEG or H.264 elementary stream.
+    ///
+    /// The video should already be running; pre-recording doesn't work otherwise anyway.
+    pub async fn save_clip(self: Arc<Self>, path: &Path, after: Duration) -> anyhow::Result<()> {
+        let history = self
+            .history
+            .as_ref()
+            .context("pre-recording isn't enabled")?;
+        // Subscribe before copying the history, so no frames are missed in between.
+        let mut stream = self.clone().stream().await;
+        let before: Vec<Frame> = history
+            .lock()
+            .unwrap()
+            .frames
+            .iter()
+            .map(|(_, frame)| frame.clone())
+            .collect();
+        let mut last_number = before.last().map(|frame| frame.number);
+
+        let mut file = tokio::fs::File::create(path)
+            .await
+            .with_context(|| format!("failed to create clip file {path:?}"))?;
+        for frame in &before {
+            file.write_all(&frame.data)
+                .await
+                .with_context(|| format!("failed to write to clip file {path:?}"))?;
+        }
+
+        let deadline = tokio::time::Instant::now() + after;
+        while let Ok(Some(frame)) = tokio::time::timeout_at(deadline, stream.next()).await {
+            // Frames that came in between subscribing and copying the history are in both.
+            if matches!(last_number, Some(last) if frame.number <= last) {
+                continue;
+            }
+            last_number = None;
+            file.write_all(&frame.data)
+                .await
+                .with_context(|| format!("failed to write to clip file {path:?}"))?;
+        }
+        file.flush()
+            .await
+            .with_context(|| format!("failed to write to clip file {path:?}"))?;
+        Ok(())
+    }
+
+    /// Keep `path` holding the latest frame, writing at most one every `interval`, for as long as
+    /// this runs. Each frame goes to a temporary file next to it first, and is renamed into place,
+    /// so readers never see half a frame. This doesn't keep the video running, and runs forever,
+    /// so give it a task of its own.
+    pub async fn write_latest(self: Arc<Self>, path: PathBuf, interval: Option<Duration>) {
+        let Some(name) = path.file_name() else {
+            error!("--latest-file {path:?} isn't a file name");
+            return;
+        };
+        let mut tmp_name = OsString::from(".");
+        tmp_name.push(name);
+        tmp_name.push(".tmp");
+        let tmp = path.with_file_name(tmp_name);
+        // Not counted as a stream, so frames only come while something else is running the video.
+        let mut receiver = self.sender.subscribe();
+        let mut last_written: Option<Instant> = None;
+        loop {
+            let slot = match receiver.recv().await {
+                Ok(slot) => slot,
+                // Only the newest frame matters anyway.
+                Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(broadcast::error::RecvError::Closed) => return,
+            };
+            if let (Some(last), Some(interval)) = (last_written, interval) {
+                if last.elapsed() < interval {
+                    continue;
+                }
+            }
+            let Some(frame) = slot.lock().unwrap().clone() else {
+                continue;
+            };
+            last_written = Some(Instant::now());
+            let result = async {
+                tokio::fs::write(&tmp, &frame.data)
+                    .await
+                    .with_context(|| format!("failed to write {tmp:?}"))?;
+                tokio::fs::rename(&tmp, &path)
+                    .await
+                    .with_context(|| format!("failed to rename {tmp:?} to {path:?}"))
+            }
+            .await;
+            if let Err(e) = result {
+                warn!("{e:#}");
+            }
+        }
+    }
+
+    fn start(&self) {
         info!("starting video");
-        if let Err(e) = self.video.start() {
+        let video = self.video();
+        if let Err(e) = video.start() {
             error!("error starting video: {e}");
             return;
         }
-        let sender = inner.sender.clone();
-        tokio::spawn(
-            self.video
-                .clone()
-                .foreach_frame(move |_video, _sample, buf| {
-                    debug!("frame {}", buf.offset());
-                    let mut bytes = BytesMut::new();
-                    for mem in buf.iter_memories() {
-                        bytes.extend_from_slice(mem.map_readable().unwrap().as_slice());
+        let ended = self.ended.clone();
+        let failed = video.clone();
+        video.spawn(async move {
+            failed.failed().await;
+            warn!("video failed; ending streams");
+            let token = std::mem::replace(&mut *ended.lock().unwrap(), CancellationToken::new());
+            token.cancel();
+        });
+
+        let thumb_sender = self.thumb_sender.clone();
+        let thumb_number = AtomicU64::new(0);
+        video.spawn(video.clone().foreach_thumbnail(move |buf| {
+            let data = match buf.map_readable() {
+                Ok(map) => Bytes::copy_from_slice(map.as_slice()),
+                Err(e) => {
+                    warn!("failed to map thumbnail memory; skipping it: {e}");
+                    return;
+                }
+            };
+            let frame = Frame {
+                data,
+                timestamp: buf.dts().map(Duration::from),
+                pts: buf.pts().map(Duration::from),
+                duration: buf.duration().map(Duration::from),
+                number: thumb_number.fetch_add(1, Ordering::Relaxed),
+                latency: None,
+                captured: SystemTime::now(),
+            };
+            let _ = thumb_sender.send(Arc::new(std::sync::Mutex::new(Some(frame))));
+        }));
+
+        let size_cap = self.size_cap;
+        if size_cap.is_some() && video.quality().is_none() {
+            warn!(
+                "the encoder has no quality setting; frames can't be kept under --max-frame-bytes"
+            );
+        }
+        let calm = AtomicU32::new(0);
+        let oversize = self.oversize.clone();
+        let check_jpeg = self.check_frames && video.codec() == Codec::Mjpeg;
+        let corrupt = self.corrupt.clone();
+        let sender = self.sender.clone();
+        let buffered = self.buffered.clone();
+        let latest = self.latest.clone();
+        let stats = self.stats.clone();
+        // It stops once the frame loop is done with the other end.
+        let pacer = self.smooth_output.then(|| {
+            let (tx, rx) = mpsc::unbounded_channel();
+            video.spawn(pace(rx, stats.clone(), sender.clone(), buffered.clone()));
+            tx
+        });
+        let history = self.history.clone();
+        let frame_number = AtomicU64::new(0);
+        let decimate = self.decimate;
+        let warmup = self.warmup;
+        // Not the running time, which starts over whenever a file loops.
+        let first_frame = std::sync::OnceLock::new();
+        let captured = AtomicU64::new(0);
+        let events = self.events.clone();
+        let last_caps = std::sync::Mutex::new(None::<Caps>);
+        // Frames are copied into this and split off, so several share one allocation.
+        let pool = std::sync::Mutex::new(BytesMut::new());
+        video.spawn(video.clone().foreach_frame(move |video, sample, buf| {
+            debug!("frame {}", buf.offset());
+            if let Some(caps) = sample.caps() {
+                let mut last = last_caps.lock().unwrap();
+                if last.as_deref() != Some(caps) {
+                    if last.is_some() {
+                        info!("video format changed to {caps}");
+                        let s = caps.structure(0);
+                        events.send(Event::FormatChanged {
+                            width: s.and_then(|s| s.get("width").ok()),
+                            height: s.and_then(|s| s.get("height").ok()),
+                            caps: caps.to_string(),
+                        });
+                    } else if let Some(format) = video.output_format() {
+                        info!("video negotiated {format}");
                     }
-                    let ts = match buf.dts().map(Duration::try_from) {
-                        Some(Ok(dur)) => Some(dur),
-                        _ => None,
-                    };
-                    if let Err(e) = sender.send((bytes.freeze(), ts)) {
-                        error!("failed to broadcast frame: {e}");
+                    *last = Some(caps.to_owned());
+                }
+            }
+            if first_frame.get_or_init(Instant::now).elapsed() < warmup {
+                trace!("skipping frame during warmup");
+                return;
+            }
+            if !captured
+                .fetch_add(1, Ordering::Relaxed)
+                .is_multiple_of(decimate)
+            {
+                return;
+            }
+            let mut bytes = pool.lock().unwrap();
+            if bytes.capacity() < buf.size() {
+                bytes.reserve(buf.size() * FRAMES_PER_ALLOCATION);
+            }
+            for mem in buf.iter_memories() {
+                match mem.map_readable() {
+                    Ok(map) => bytes.extend_from_slice(map.as_slice()),
+                    Err(e) => {
+                        warn!(
+                            "failed to map frame {} memory; skipping it: {e}",
+                            buf.offset()
+                        );
+                        bytes.clear();
+                        return;
                     }
-                }),
-        );
+                }
+            }
+            if check_jpeg && !is_whole_jpeg(&bytes) {
+                warn!(
+                    "frame {} isn't a whole JPEG ({} bytes); dropping it",
+                    buf.offset(),
+                    bytes.len()
+                );
+                corrupt.fetch_add(1, Ordering::Relaxed);
+                bytes.clear();
+                return;
+            }
+            let data = bytes.split().freeze();
+            drop(bytes);
+            let ts = match buf.dts().map(Duration::try_from) {
+                Some(Ok(dur)) => Some(dur),
+                _ => None,
+            };
+            let latency = match (buf.pts(), video.running_time()) {
+                (Some(pts), Some(now)) => Some(now.saturating_sub(pts.into())),
+                _ => None,
+            };
+            let frame = Frame {
+                data,
+                timestamp: ts,
+                pts: buf.pts().map(Duration::from),
+                duration: buf.duration().map(Duration::from),
+                number: frame_number.fetch_add(1, Ordering::Relaxed),
+                latency,
+                captured: SystemTime::now() - latency.unwrap_or_default(),
+            };
+            stats.lock().unwrap().record(frame.data.len(), latency);
+            if let Some(cap) = &size_cap {
+                if cap.check(video, frame.data.len(), &calm) {
+                    oversize.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+            *latest.lock().unwrap() = Some(frame.clone());
+            if let Some(history) = &history {
+                history.lock().unwrap().push(frame.clone());
+            }
+            match &pacer {
+                Some(pacer) => {
+                    let _ = pacer.send((Instant::now(), frame));
+                }
+                None => broadcast(&sender, &buffered, frame),
+            }
+        }));
     }
 
-    pub async fn stop(&self) {
-        let mut inner = self.inner.lock().await;
-        inner.count = inner.count.saturating_sub(1);
-        if inner.count != 0 {
-            debug!("have {} streamers still", inner.count);
-            return;
+    pub async fn stop(self: Arc<Self>) {
+        loop {
+            let count = self.count.load(Ordering::SeqCst);
+            if count == 0 {
+                warn!("stop called with no streamers");
+                return;
+            }
+            if count > 1 {
+                if self
+                    .count
+                    .compare_exchange(count, count - 1, Ordering::SeqCst, Ordering::SeqCst)
+                    .is_ok()
+                {
+                    debug!("have {} streamers still", count - 1);
+                    self.events.send(Event::ClientDisconnected {
+                        subscribers: count - 1,
+                    });
+                    return;
+                }
+                continue;
+            }
+            let _guard = self.transition.lock().await;
+            if self
+                .count
+                .compare_exchange(1, 0, Ordering::SeqCst, Ordering::SeqCst)
+                .is_ok()
+            {
+                self.events
+                    .send(Event::ClientDisconnected { subscribers: 0 });
+                if self.idle_timeout.is_zero() {
+                    info!("last streamer went away; stopping video");
+                    self.stop_video().await;
+                    return;
+                }
+                info!(
+                    "last streamer went away; stopping video in {:?} unless another comes",
+                    self.idle_timeout
+                );
+                self.lingering.store(true, Ordering::SeqCst);
+                let generation = self.linger_generation.fetch_add(1, Ordering::SeqCst) + 1;
+                let frames = self.clone();
+                tokio::spawn(async move {
+                    tokio::time::sleep(frames.idle_timeout).await;
+                    let _guard = frames.transition.lock().await;
+                    if frames.linger_generation.load(Ordering::SeqCst) == generation
+                        && frames.lingering.swap(false, Ordering::SeqCst)
+                    {
+                        info!("no streamers for {:?}; stopping video", frames.idle_timeout);
+                        frames.stop_video().await;
+                    }
+                });
+                return;
+            }
+            // Someone else subscribed meanwhile; try again.
         }
-        info!("last streamer went away; stopping video");
-        if let Err(e) = self.video.stop() {
+    }
+
+    async fn stop_video(&self) {
+        self.latest.lock().unwrap().take();
+        if let Err(e) = self.video().stop().await {
             error!("error stopping video: {e}");
         }
     }
@@ -95,35 +928,326 @@ impl Frames {
 
 pub struct FrameStream {
     parent: Arc<Frames>,
-    stream: BroadcastStream<(Bytes, Option<Duration>)>,
+    stream: BroadcastStream<Slot>,
+    /// Finishes if the video fails, ending the stream.
+    ended: Pin<Box<WaitForCancellationFutureOwned>>,
+    /// Minimum time between frames, if the stream is rate-limited.
+    interval: Option<Duration>,
+    /// When the next frame should be sent, on the same clock as `frame_time`.
+    next_due: Option<Duration>,
+    /// For timing frames that have no timestamp.
+    started: Instant,
+    lags: Lags,
+    /// Frames passed on so far.
+    sent: u64,
+    /// Who the stream is for, for logging.
+    client: Option<IpAddr>,
+}
+
+impl FrameStream {
+    /// Limit the stream to at most `fps` frames per second, dropping the rest.
+    ///
+    /// Frames are picked to be as evenly spaced as possible, so they don't come in bursts.
+    pub fn max_fps(mut self, fps: f64) -> Self {
+        self.interval = Some(Duration::from_secs_f64(1. / fps));
+        self
+    }
+
+    /// End the stream once it falls behind and skips frames `lags` times in a row (without
+    /// keeping up for a while in between), instead of carrying on every time.
+    pub fn disconnect_after_lags(mut self, lags: u32) -> Self {
+        self.lags.max = Some(lags);
+        self
+    }
+
+    /// Say who the stream is for, so the number of frames sent to them is logged when it ends.
+    pub fn for_client(mut self, client: IpAddr) -> Self {
+        self.client = Some(client);
+        self
+    }
+
+    fn frame_time(&self, frame: &Frame) -> Duration {
+        frame.timestamp.unwrap_or_else(|| self.started.elapsed())
+    }
+
+    /// Decide whether to send a frame, and update the schedule if so.
+    fn keep(&mut self, frame: &Frame) -> bool {
+        let Some(interval) = self.interval else {
+            return true;
+        };
+        let now = self.frame_time(frame);
+        match self.next_due {
+            Some(due) if now < due => {
+                // Timestamps start over when the video is restarted or a file loops.
+                if due - now <= interval {
+                    return false;
+                }
+                self.next_due = Some(now + interval);
+            }
+            // Step the schedule forward by exactly one interval, so the error from frames not
+            // landing exactly on it doesn't accumulate. If we've fallen far behind (e.g. the
+            // source stalled), start over from now instead of sending a burst to catch up.
+            Some(due) if now < due + interval => self.next_due = Some(due + interval),
+            _ => self.next_due = Some(now + interval),
+        }
+        true
+    }
 }
 
 impl Drop for FrameStream {
     fn drop(&mut self) {
         debug!("FrameStream dropped");
+        if let Some(client) = self.client {
+            info!(
+                "stream to {client} ended after {:.1?}: sent {} frames",
+                self.started.elapsed(),
+                self.sent
+            );
+        }
         let video = self.parent.clone();
         tokio::spawn(async move { video.stop().await });
     }
 }
 
 impl Stream for FrameStream {
-    type Item = (Bytes, Option<Duration>);
+    type Item = Frame;
     fn poll_next(
         mut self: Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
     ) -> Poll<Option<Self::Item>> {
-        let stream = Pin::new(&mut self.stream);
-        match stream.poll_next(cx) {
-            Poll::Ready(Some(Ok(stuff))) => Poll::Ready(Some(stuff)),
-            Poll::Ready(Some(Err(lag))) => {
+        if self.ended.poll_unpin(cx).is_ready() {
+            return Poll::Ready(None);
+        }
+        loop {
+            let this = &mut *self;
+            let lagged = |skipped| {
+                this.parent.count_lag(skipped);
+                this.lags.lagged()
+            };
+            let Some(slot) = ready!(poll_broadcast(&mut this.stream, cx, lagged)) else {
+                return Poll::Ready(None);
+            };
+            let Some(frame) = slot.lock().unwrap().clone() else {
+                // Dropped to stay within the buffer budget.
+                self.parent.count_lag(1);
+                if self.lags.lagged() {
+                    return Poll::Ready(None);
+                }
+                continue;
+            };
+            let buffer_frames = self.parent.buffer_frames;
+            self.lags.received(buffer_frames);
+            if self.keep(&frame) {
+                self.sent += 1;
+                return Poll::Ready(Some(frame));
+            }
+        }
+    }
+}
+
+/// How a stream is doing at keeping up, for [`FrameStream::disconnect_after_lags`].
+#[derive(Debug, Default)]
+struct Lags {
+    /// End the stream after falling behind this many times in a row.
+    max: Option<u32>,
+    /// How many times in a row the stream has fallen behind.
+    in_a_row: u32,
+    /// Frames received since the stream last fell behind.
+    since_lag: usize,
+}
+
+impl Lags {
+    /// Count a time the stream fell behind, and say whether it should be ended for it.
+    fn lagged(&mut self) -> bool {
+        self.in_a_row += 1;
+        self.since_lag = 0;
+        match self.max {
+            Some(max) if self.in_a_row >= max => {
+                warn!(
+                    "stream fell behind {} times in a row; ending it",
+                    self.in_a_row
+                );
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Count a frame received. Right after lagging, the next frame is the oldest one still
+    /// buffered, so it only counts as having caught up once `buffered` have come through.
+    fn received(&mut self, buffered: usize) {
+        self.since_lag += 1;
+        if self.since_lag >= buffered {
+            self.in_a_row = 0;
+        }
+    }
+}
+
+/// Get the next item from a broadcast channel. If the receiver fell behind, this skips ahead to
+/// the oldest item still buffered, after calling `lagged` with how many were missed; if that
+/// returns true, the stream ends instead.
+fn poll_broadcast<T: Clone + Send + 'static>(
+    stream: &mut BroadcastStream<T>,
+    cx: &mut std::task::Context<'_>,
+    mut lagged: impl FnMut(u64) -> bool,
+) -> Poll<Option<T>> {
+    loop {
+        match ready!(Pin::new(&mut *stream).poll_next(cx)) {
+            Some(Ok(item)) => return Poll::Ready(Some(item)),
+            Some(Err(lag)) => {
                 warn!("lag: {lag}");
-                self.poll_next(cx)
+                let BroadcastStreamRecvError::Lagged(skipped) = lag;
+                if lagged(skipped) {
+                    return Poll::Ready(None);
+                }
             }
-            Poll::Ready(None) => {
+            None => {
                 warn!("FrameStream returned none");
-                Poll::Ready(None)
+                return Poll::Ready(None);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::video;
+
+    /// Frames from a test pattern, unless GStreamer or its plugins aren't installed.
+    fn test_frames(buffer_frames: usize, idle_timeout: Duration) -> Option<Arc<Frames>> {
+        let config = video::tests::test_config();
+        if !video::tests::can_build(&config) {
+            return None;
+        }
+        let video = Arc::new(Video::new(&config).unwrap());
+        let config = Config {
+            pre_record: Duration::ZERO,
+            buffer_frames,
+            buffer_bytes: None,
+            decimate: 1,
+            warmup: Duration::ZERO,
+            size_cap: None,
+            smooth_output: false,
+            check_frames: false,
+            idle_timeout,
+        };
+        Some(Arc::new(Frames::new(video, Events::new(), &config)))
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn video_is_running_while_lingering() {
+        let Some(frames) = test_frames(16, Duration::from_secs(10)) else {
+            return;
+        };
+        assert!(!frames.is_running());
+        let mut stream = frames.clone().stream().await;
+        assert!(stream.next().await.is_some());
+        assert!(frames.is_running());
+
+        drop(stream);
+        // Dropping the stream lets go of it in a task of its own.
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(frames.subscribers(), 0);
+        assert!(
+            frames.is_running(),
+            "not running while waiting out the idle timeout"
+        );
+        frames.video().shutdown().await.unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn streamed_frames_come_in_order_and_gaps_are_counted() {
+        let Some(frames) = test_frames(16, Duration::ZERO) else {
+            return;
+        };
+        let mut stream = frames.clone().stream().await;
+        let mut last = stream.next().await.unwrap().number;
+        let mut skipped = 0;
+        for _ in 0..30 {
+            let number = stream.next().await.unwrap().number;
+            assert!(number > last, "frame {number} came after {last}");
+            skipped += number - last - 1;
+            last = number;
+        }
+        assert_eq!(frames.lagged(), skipped);
+        drop(stream);
+        frames.video().shutdown().await.unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn slow_stream_skips_to_the_oldest_buffered_frame() {
+        let Some(frames) = test_frames(2, Duration::ZERO) else {
+            return;
+        };
+        let mut stream = frames.clone().stream().await;
+        let first = stream.next().await.unwrap().number;
+        // Plenty of time for more than two frames to come in.
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        let next = stream.next().await.unwrap().number;
+        assert!(next > first + 1, "didn't skip any frames after {first}");
+        assert_eq!(frames.lagged(), next - first - 1);
+        drop(stream);
+        frames.video().shutdown().await.unwrap();
+    }
+
+    /// Poll a receiver for its next item, counting lags in `lags`.
+    async fn next(stream: &mut BroadcastStream<u32>, lags: &mut Lags) -> Option<u32> {
+        futures::future::poll_fn(|cx| poll_broadcast(stream, cx, |_| lags.lagged()))
+            .await
+            .inspect(|_| lags.received(4))
+    }
+
+    #[tokio::test]
+    async fn lagging_stream_skips_ahead() {
+        let (tx, rx) = broadcast::channel(4);
+        let mut stream = BroadcastStream::new(rx);
+        let mut lags = Lags::default();
+        for round in 0..3 {
+            for i in 0..10 {
+                tx.send(round * 10 + i).unwrap();
+            }
+            // It missed the oldest six, and carries on from the oldest still buffered.
+            for i in 6..10 {
+                assert_eq!(next(&mut stream, &mut lags).await, Some(round * 10 + i));
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn lagging_stream_ends_after_max_lags() {
+        let (tx, rx) = broadcast::channel(4);
+        let mut stream = BroadcastStream::new(rx);
+        let mut lags = Lags {
+            max: Some(2),
+            ..Lags::default()
+        };
+        for i in 0..10 {
+            tx.send(i).unwrap();
+        }
+        assert_eq!(next(&mut stream, &mut lags).await, Some(6));
+        for i in 10..20 {
+            tx.send(i).unwrap();
+        }
+        // That's twice in a row, without getting a buffer's worth in between.
+        assert_eq!(next(&mut stream, &mut lags).await, None);
+    }
+
+    #[tokio::test]
+    async fn keeping_up_resets_the_lag_count() {
+        let (tx, rx) = broadcast::channel(4);
+        let mut stream = BroadcastStream::new(rx);
+        let mut lags = Lags {
+            max: Some(2),
+            ..Lags::default()
+        };
+        for round in 0..3 {
+            for i in 0..10 {
+                tx.send(round * 10 + i).unwrap();
+            }
+            for i in 6..10 {
+                assert_eq!(next(&mut stream, &mut lags).await, Some(round * 10 + i));
             }
-            Poll::Pending => Poll::Pending,
         }
     }
 }
@@ -1,100 +1,2040 @@
+use std::collections::HashMap;
 use std::convert::Infallible;
-use std::net::{Ipv4Addr, SocketAddr};
+use std::future::Future;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::Arc;
+use std::task::{ready, Context as TaskContext, Poll};
+use std::time::{Duration, SystemTime};
 
-use anyhow::Context;
+use anyhow::{bail, Context};
+use bytes::{Bytes, BytesMut};
 use futures::StreamExt;
-use gstreamer::glib::uuid_string_random;
+use gstreamer::prelude::GstValueExt;
+use gstreamer::{Fraction, FractionRange, IntRange, State};
+use hyper::body::HttpBody;
 use hyper::http::HeaderValue;
-use hyper::server::conn::AddrStream;
+use hyper::server::accept::{self, Accept};
+use hyper::server::conn::{AddrIncoming, AddrStream};
 use hyper::service::{make_service_fn, service_fn};
-use hyper::{Body, HeaderMap, Request, Response, Server};
+use hyper::{Body, HeaderMap, Method, Request, Response, Server, Uri};
 use multipart_stream::Part;
+use serde_json::json;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::sync::{mpsc, oneshot, watch, OnceCell};
+use tokio::time::{Instant, Sleep};
+use tokio_stream::wrappers::{BroadcastStream, IntervalStream};
 
-use crate::frames::Frames;
+use crate::exif;
+use crate::frames::{Frame, Frames};
+use crate::raw::RawFormat;
+use crate::stats::Throughput;
+use crate::video::{self, Balance, Codec, Reencode, Rotation};
 
-#[derive(Debug, Clone)]
+/// Largest width or height snapshots can be scaled to.
+const MAX_SNAPSHOT_SIZE: u32 = 4096;
+
+/// Largest snapshot that can be sent as a data URI, before base64 makes it a third bigger.
+const MAX_DATA_URI_BYTES: usize = 4 * 1024 * 1024;
+
+/// How long to wait before retrying a failed snapshot.
+const SNAPSHOT_RETRY_DELAY: Duration = Duration::from_millis(250);
+
+/// How often to send something on an otherwise quiet event stream, so it isn't closed as idle.
+const EVENTS_KEEPALIVE_INTERVAL: Duration = Duration::from_secs(15);
+
+/// How long a new stream waits for the video to start before giving up with a 503.
+const STREAM_START_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// How long to tell clients to wait before trying again when there are too many streams.
+const MAX_CLIENTS_RETRY_AFTER: Duration = Duration::from_secs(10);
+
+/// Largest request body accepted. The only bodies used are short control commands.
+const MAX_BODY_BYTES: usize = 4096;
+
+/// Path for querying and changing the pipeline state.
+const CONTROL_STATE_PATH: &str = "/control/state";
+
+/// Path for querying and changing the color adjustments.
+const CONTROL_BALANCE_PATH: &str = "/control/balance";
+
+/// Path for saving a clip of recent video to disk.
+const CONTROL_SAVE_CLIP_PATH: &str = "/control/save-clip";
+
+/// Path for rebuilding the video pipeline.
+const CONTROL_RESTART_PATH: &str = "/control/restart-pipeline";
+
+/// Path for the Server-Sent Events stream of notable events.
+const EVENTS_PATH: &str = "/events";
+
+/// How long connections in progress get to finish when the server shuts down.
+pub const SHUTDOWN_GRACE: Duration = Duration::from_secs(5);
+
+/// Path for the thumbnail stream.
+const THUMB_PATH: &str = "/thumb";
+
+/// Path for the camera's supported formats.
+const CAPS_PATH: &str = "/caps";
+
+/// Path for the motion score.
+const MOTION_PATH: &str = "/motion";
+
+/// Path for stats as JSON.
+const INFO_PATH: &str = "/info";
+
+/// Path for stats in Prometheus's text format.
+const METRICS_PATH: &str = "/metrics";
+
+/// Path for stats as `key=value` lines, for shell scripts.
+const STATS_PATH: &str = "/stats";
+
+/// Every fixed path the server answers at the top level, which camera names mustn't hide.
+const FIXED_PATHS: &[&str] = &[
+    CONTROL_STATE_PATH,
+    EVENTS_PATH,
+    THUMB_PATH,
+    CAPS_PATH,
+    MOTION_PATH,
+    INFO_PATH,
+    METRICS_PATH,
+    STATS_PATH,
+    "/hls/",
+];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Paths {
-    pub stream: String,
-    pub snapshot: String,
+    /// Paths for the stream, main one first. Empty if it's disabled.
+    pub stream: Vec<String>,
+    /// Paths for snapshots, main one first. Empty if they're disabled.
+    pub snapshot: Vec<String>,
+    /// Paths for the JSON status summary.
+    pub status: Vec<String>,
+    /// Paths for the JSON list of cameras attached to the system.
+    pub devices: Vec<String>,
+}
+
+/// The `Content-Type` used for the multipart stream.
+///
+/// Browsers want `multipart/x-mixed-replace`, which tells them each part replaces the previous
+/// one. Some other clients (notably ones built on generic MIME parsers) only understand
+/// `multipart/mixed`. The body is framed the same way either way.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MultipartType {
+    XMixedReplace,
+    Mixed,
+}
+
+impl MultipartType {
+    fn mime_type(self) -> &'static str {
+        match self {
+            MultipartType::XMixedReplace => "multipart/x-mixed-replace",
+            MultipartType::Mixed => "multipart/mixed",
+        }
+    }
+}
+
+impl FromStr for MultipartType {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "x-mixed-replace" => MultipartType::XMixedReplace,
+            "mixed" => MultipartType::Mixed,
+            _ => bail!("multipart type must be either \"x-mixed-replace\" or \"mixed\""),
+        })
+    }
+}
+
+/// What to do with a stream whose client can't keep up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SlowClient {
+    /// Skip the frames it missed, and carry on from the newest one.
+    Drop,
+    /// End the stream after it falls behind a number of times in a row.
+    Disconnect { lags: u32 },
+}
+
+/// How many times in a row a stream can fall behind before [`SlowClient::Disconnect`] ends it,
+/// unless told otherwise.
+pub const DEFAULT_SLOW_CLIENT_LAGS: u32 = 3;
+
+impl SlowClient {
+    /// The policy for one stream, from its `?slow-client=` (`drop` or `disconnect`) and
+    /// `?slow-client-lags=` parameters, with `self` as the default.
+    fn with_query(self, query: &str) -> Result<Self, &'static str> {
+        let lags = match query_param(query, "slow-client-lags").map(str::parse::<u32>) {
+            None => None,
+            Some(Ok(n)) if n > 0 => Some(n),
+            Some(_) => return Err("slow-client-lags must be a positive whole number"),
+        };
+        let default_lags = match self {
+            SlowClient::Disconnect { lags } => lags,
+            SlowClient::Drop => DEFAULT_SLOW_CLIENT_LAGS,
+        };
+        Ok(match (query_param(query, "slow-client"), self) {
+            (Some("drop"), _) | (None, SlowClient::Drop) => SlowClient::Drop,
+            (Some("disconnect"), _) | (None, SlowClient::Disconnect { .. }) => {
+                SlowClient::Disconnect {
+                    lags: lags.unwrap_or(default_lags),
+                }
+            }
+            (Some(_), _) => return Err("slow-client must be \"drop\" or \"disconnect\""),
+        })
+    }
+}
+
+/// A fixed multipart boundary string, for clients that can't handle a different one each time.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Boundary(String);
+
+impl FromStr for Boundary {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // RFC 2046 allows a few more characters than these, but they'd need the Content-Type
+        // parameter to be quoted, which some of the clients that want a fixed boundary don't
+        // understand.
+        if s.is_empty() || s.len() > 70 {
+            bail!("boundary must be 1 to 70 characters long");
+        }
+        if let Some(c) = s
+            .chars()
+            .find(|c| !c.is_ascii_alphanumeric() && !"'+-._".contains(*c))
+        {
+            bail!(
+                "boundary can't contain {c:?}; only letters, digits, and any of '+-._ are allowed"
+            );
+        }
+        Ok(Self(s.to_owned()))
+    }
+}
+
+/// A range of IP addresses, written like `10.0.0.0/8` or `fd00::/8`. A bare address means just
+/// that one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        // Clients on an IPv6 socket can show up as IPv4-mapped addresses.
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+            v4 => v4,
+        };
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - u32::from(self.prefix))
+                    .unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - u32::from(self.prefix))
+                    .unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr
+            .parse()
+            .with_context(|| format!("invalid IP address {addr:?}"))?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(p) => p
+                .parse::<u8>()
+                .ok()
+                .filter(|&p| p <= max)
+                .with_context(|| format!("prefix length must be from 0 to {max}"))?,
+            None => max,
+        };
+        Ok(Self { addr, prefix })
+    }
+}
+
+/// A change to how the stream is framed, for clients that mishandle the usual output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Quirk {
+    /// Send only `Content-Type` and `Content-Length` with each part.
+    MinimalHeaders,
+    /// Leave out `X-Timestamp`.
+    NoTimestamp,
+    /// End each part's data with a CRLF, before the next boundary. Some clients find boundaries
+    /// by looking for a CRLF before them.
+    TrailingCrlf,
+}
+
+impl FromStr for Quirk {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "minimal-headers" => Quirk::MinimalHeaders,
+            "no-timestamp" => Quirk::NoTimestamp,
+            "trailing-crlf" => Quirk::TrailingCrlf,
+            _ => bail!(
+                "unknown quirk {s:?}; expected minimal-headers, no-timestamp, or trailing-crlf"
+            ),
+        })
+    }
+}
+
+/// Quirks to apply to clients whose `User-Agent` contains some text (ignoring case), written
+/// like `ESP32=trailing-crlf,minimal-headers`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientQuirks {
+    agent: String,
+    quirks: Vec<Quirk>,
+}
+
+impl FromStr for ClientQuirks {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some((agent, quirks)) = s.rsplit_once('=') else {
+            bail!("expected USER-AGENT=QUIRK[,QUIRK...]");
+        };
+        if agent.is_empty() {
+            bail!("the User-Agent text can't be empty");
+        }
+        Ok(Self {
+            agent: agent.to_lowercase(),
+            quirks: quirks
+                .split(',')
+                .map(str::parse)
+                .collect::<anyhow::Result<_>>()?,
+        })
+    }
+}
+
+/// A user name and password for HTTP Basic authentication, written like `USER:PASS`.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Credentials {
+    user: String,
+    /// What the `Authorization` header has to say after `Basic `.
+    token: String,
+}
+
+impl Credentials {
+    /// Whether an `Authorization` header value gives these credentials.
+    fn matches(&self, header: &[u8]) -> bool {
+        let Some((scheme, token)) = std::str::from_utf8(header)
+            .ok()
+            .and_then(|h| h.trim().split_once(' '))
+        else {
+            return false;
+        };
+        // Compare every byte, so how long this takes doesn't say how much of it was right.
+        let token = token.trim_start().as_bytes();
+        scheme.eq_ignore_ascii_case("basic")
+            && token.len() == self.token.len()
+            && token
+                .iter()
+                .zip(self.token.as_bytes())
+                .fold(0, |diff, (a, b)| diff | (a ^ b))
+                == 0
+    }
+}
+
+// Keep the password out of logs.
+impl std::fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Credentials")
+            .field("user", &self.user)
+            .finish_non_exhaustive()
+    }
+}
+
+impl FromStr for Credentials {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some((user, _)) = s.split_once(':') else {
+            bail!("expected USER:PASS");
+        };
+        if user.is_empty() {
+            bail!("the user name can't be empty");
+        }
+        Ok(Self {
+            user: user.to_owned(),
+            token: base64(s.as_bytes()),
+        })
+    }
+}
+
+/// One piece of an access log line.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum LogToken {
+    Text(String),
+    Remote,
+    Method,
+    Path,
+    Status,
+    UserAgent,
+    Bytes,
+    Duration,
+}
+
+/// How to write access log lines, like `{remote} "{method} {path}" {status} {bytes}`. Each
+/// `{token}` is replaced with something about the request; `{{` and `}}` are literal braces.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AccessLogFormat(Vec<LogToken>);
+
+impl AccessLogFormat {
+    fn format(&self, entry: &AccessLogEntry, status: u16, bytes: u64) -> String {
+        let mut out = String::new();
+        for token in &self.0 {
+            match token {
+                LogToken::Text(s) => out += s,
+                LogToken::Remote => out += &entry.remote.to_string(),
+                LogToken::Method => out += entry.method.as_str(),
+                LogToken::Path => out += &entry.path,
+                LogToken::Status => out += &status.to_string(),
+                LogToken::UserAgent => out += entry.user_agent.as_deref().unwrap_or("-"),
+                LogToken::Bytes => out += &bytes.to_string(),
+                LogToken::Duration => out += &entry.start.elapsed().as_millis().to_string(),
+            }
+        }
+        out
+    }
+}
+
+impl FromStr for AccessLogFormat {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut tokens = vec![];
+        let mut text = String::new();
+        let mut rest = s;
+        while let Some(i) = rest.find(['{', '}']) {
+            text += &rest[..i];
+            let tail = &rest[i..];
+            if tail.starts_with("{{") || tail.starts_with("}}") {
+                text.push(tail.as_bytes()[0] as char);
+                rest = &tail[2..];
+                continue;
+            }
+            if tail.starts_with('}') {
+                bail!("unmatched '}}'; write '}}}}' for a literal one");
+            }
+            let Some(end) = tail.find('}') else {
+                bail!("unmatched '{{'; write '{{{{' for a literal one");
+            };
+            let token = match &tail[1..end] {
+                "remote" => LogToken::Remote,
+                "method" => LogToken::Method,
+                "path" => LogToken::Path,
+                "status" => LogToken::Status,
+                "user-agent" => LogToken::UserAgent,
+                "bytes" => LogToken::Bytes,
+                "duration" => LogToken::Duration,
+                other => bail!(
+                    "unknown token {{{other}}}; expected remote, method, path, status, \
+                    user-agent, bytes, or duration"
+                ),
+            };
+            if !text.is_empty() {
+                tokens.push(LogToken::Text(std::mem::take(&mut text)));
+            }
+            tokens.push(token);
+            rest = &tail[end + 1..];
+        }
+        text += rest;
+        if !text.is_empty() {
+            tokens.push(LogToken::Text(text));
+        }
+        Ok(Self(tokens))
+    }
+}
+
+/// Where to send requests to rebuild the video pipeline. Each one carries a channel to send the
+/// result back on.
+pub type RestartSender = mpsc::Sender<oneshot::Sender<anyhow::Result<()>>>;
+
+/// Limits on what clients can send, to guard against abuse. These can't be changed while
+/// running.
+#[derive(Debug, Clone)]
+pub struct Limits {
+    /// Most bytes of request headers to buffer; larger requests get a 431 error.
+    pub max_header_bytes: usize,
+    /// How long a client has to send the request headers before it's disconnected.
+    pub header_read_timeout: Duration,
+    /// How long a connection can go without reading or writing anything before it's closed.
+    pub idle_timeout: Duration,
+    /// Most new connections to accept a second, on average, on each port.
+    pub max_accepts_per_sec: Option<u32>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Config {
+    pub paths: Paths,
+    pub multipart_type: MultipartType,
+    /// Most frames per second to send on any stream.
+    pub stream_max_fps: Option<f64>,
+    /// Most frames per second to send on any thumbnail stream.
+    pub thumbnail_max_fps: Option<f64>,
+    /// Most streams to send at once; more are refused with a 503.
+    pub max_clients: Option<u64>,
+    /// Boundary to use for every stream, instead of a random one.
+    pub boundary: Option<Boundary>,
+    /// What to do with streams that can't keep up.
+    pub slow_client: SlowClient,
+    /// Whether to send an `X-Frame-Number` header with each part of the stream.
+    pub frame_numbers: bool,
+    /// `Content-Type` for each part of the stream, instead of the codec's usual one.
+    pub part_content_type: Option<HeaderValue>,
+    /// Whether to leave out everything but `Content-Type` and `Content-Length` from each part.
+    pub minimal_headers: bool,
+    /// Whether to send each part's raw buffer timing, in `X-PTS`, `X-DTS`, and `X-Duration`.
+    pub debug_timing: bool,
+    /// Whether to follow each frame of the stream with a JSON part describing it.
+    pub stream_metadata: bool,
+    /// Changes to the stream for particular clients. Every entry that matches applies.
+    pub client_quirks: Vec<ClientQuirks>,
+    /// How to log each request once its response is done, instead of the usual line when it
+    /// arrives.
+    pub access_log_format: Option<AccessLogFormat>,
+    /// Credentials needed for everything but the index page, or what's under `auth_paths`.
+    pub auth: Option<Credentials>,
+    /// Path prefixes that need `auth`. Empty means everything does.
+    pub auth_paths: Vec<String>,
+    /// Let connections from this machine in without `auth`.
+    pub auth_allow_localhost: bool,
+    /// Origin to let other sites' scripts fetch the stream, snapshots, and status from, with
+    /// CORS headers (`*` for any). None sends no CORS headers.
+    pub cors_origin: Option<HeaderValue>,
+    /// How long to wait for a frame when taking a snapshot.
+    pub snapshot_timeout: Duration,
+    /// How long clients may cache snapshots for. Zero means not at all.
+    pub snapshot_cache: Duration,
+    /// How many more times to try getting a snapshot frame if the first attempt fails.
+    pub snapshot_retries: u32,
+    /// Most snapshots per second to capture. Requests in between get the last one again.
+    pub snapshot_max_fps: Option<f64>,
+    /// Whether to add EXIF metadata with the capture time to JPEG snapshots.
+    pub snapshot_exif: bool,
+    /// Text for the EXIF image description.
+    pub snapshot_exif_comment: Option<String>,
+    /// Text to put in a JPEG comment segment of every snapshot, unless the request gives its own.
+    pub snapshot_comment: Option<String>,
+    /// Rotate JPEG snapshots by this much from how the video comes out of the pipeline, before
+    /// any rotation the request asks for.
+    pub snapshot_rotation: Rotation,
+    /// Image to serve for snapshots while the video isn't running, instead of starting it.
+    pub placeholder: Option<PathBuf>,
+    /// Directory to serve HLS files from, under `/hls/`.
+    pub hls_dir: Option<PathBuf>,
+    /// Directory to save clips into, or `None` if clips are disabled.
+    pub clip_dir: Option<PathBuf>,
+    /// How much video to add to clips after they're asked for.
+    pub post_record: Duration,
+    /// Proxies whose `X-Forwarded-For` headers are believed.
+    pub trusted_proxies: Vec<Cidr>,
+}
+
+impl Config {
+    /// Quirks to apply to the stream for a client with this `User-Agent`.
+    fn quirks(&self, user_agent: &str) -> Vec<Quirk> {
+        let user_agent = user_agent.to_lowercase();
+        self.client_quirks
+            .iter()
+            .filter(|c| user_agent.contains(&c.agent))
+            .flat_map(|c| c.quirks.iter().copied())
+            .collect()
+    }
+
+    /// Whether a request for this path needs the credentials. For one of the --camera ones,
+    /// `prefix` is its `/NAME`, and a match with or without it counts.
+    fn needs_auth(&self, prefix: &str, path: &str) -> bool {
+        self.auth.is_some()
+            && (self.auth_paths.is_empty()
+                || self
+                    .auth_paths
+                    .iter()
+                    .any(|p| under_prefix(path, p) || under_prefix(&format!("{prefix}{path}"), p)))
+    }
+
+    /// The address of the client a request is really from: the peer's address, unless the peer
+    /// is a trusted proxy, in which case it's the last address the proxy added to
+    /// `X-Forwarded-For`. Headers from anyone else are ignored, since they're trivial to forge.
+    fn client_ip(&self, peer: IpAddr, headers: &HeaderMap) -> IpAddr {
+        if !self.trusted_proxies.iter().any(|c| c.contains(peer)) {
+            return peer;
+        }
+        headers
+            .get_all("x-forwarded-for")
+            .iter()
+            .next_back()
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.rsplit(',').next())
+            .and_then(|ip| ip.trim().parse().ok())
+            .unwrap_or(peer)
+    }
+}
+
+/// State shared by requests on every port.
+#[derive(Debug, Default)]
+pub struct Shared {
+    streams: Arc<StreamCount>,
+    conversions: std::sync::Mutex<Conversions>,
+    /// The last frame captured for a snapshot, and when, for `snapshot_max_fps`.
+    last_snapshot: std::sync::Mutex<Option<(Instant, Frame)>>,
+    started: Started,
+    /// Connections accepted since starting, on every port.
+    connections: AtomicU64,
+    /// Set once shutting down, when new streams are refused but requests in progress can finish.
+    draining: AtomicBool,
+}
+
+/// When the server started, for reporting uptime.
+#[derive(Debug)]
+struct Started {
+    at: SystemTime,
+    instant: Instant,
+}
+
+impl Default for Started {
+    fn default() -> Self {
+        Self {
+            at: SystemTime::now(),
+            instant: Instant::now(),
+        }
+    }
+}
+
+impl Shared {
+    /// Where to put the result of re-encoding a frame, or find it if another request for the same
+    /// frame and changes got there first.
+    fn conversion(&self, frame: &Frame, reencode: Reencode) -> Arc<OnceCell<Bytes>> {
+        let mut conversions = self.conversions.lock().unwrap();
+        let id = (frame.number, frame.captured);
+        if conversions.frame != Some(id) {
+            // Only the latest frame is worth keeping conversions of.
+            conversions.frame = Some(id);
+            conversions.results.clear();
+        }
+        conversions.results.entry(reencode).or_default().clone()
+    }
+}
+
+/// Re-encoded versions of one frame, so identical snapshot requests that come in together share
+/// the work instead of each re-encoding it.
+#[derive(Debug, Default)]
+struct Conversions {
+    /// Which frame these are of. Frame numbers start over when the video does, so the capture
+    /// time is needed too.
+    frame: Option<(u64, SystemTime)>,
+    results: HashMap<Reencode, Arc<OnceCell<Bytes>>>,
+}
+
+/// Counts the streams being sent, for limiting how many there can be. Each one holds a
+/// [`StreamSlot`].
+#[derive(Debug, Default)]
+struct StreamCount(AtomicU64);
+
+impl StreamCount {
+    /// Take a slot, unless `max` of them are already taken.
+    fn acquire(self: &Arc<Self>, max: Option<u64>) -> Option<StreamSlot> {
+        self.0
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| {
+                (n < max.unwrap_or(u64::MAX)).then_some(n + 1)
+            })
+            .ok()?;
+        Some(StreamSlot(self.clone()))
+    }
+}
+
+struct StreamSlot(Arc<StreamCount>);
+
+impl Drop for StreamSlot {
+    fn drop(&mut self) {
+        self.0 .0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Where a request came from.
+#[derive(Debug, Clone, Copy)]
+struct Client {
+    /// The address it's really from, as far as can be told; see [`Config::client_ip`].
+    ip: IpAddr,
+    /// The other end of the connection. This might be a proxy, but unlike `ip`, it can't be
+    /// forged with headers.
+    peer: IpAddr,
+}
+
+/// Whether an address is this machine's, including IPv4 loopback addresses on an IPv6 socket.
+fn is_loopback(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(v4) => v4.is_loopback(),
+        IpAddr::V6(v6) => {
+            v6.is_loopback() || v6.to_ipv4_mapped().is_some_and(|v4| v4.is_loopback())
+        }
+    }
 }
 
+/// Handle a request for the camera with the given frames. `prefix` is what was taken off the
+/// start of the path to pick the camera, if it's not the main one.
 async fn handle_request(
     req: Request<Body>,
-    _remote: SocketAddr,
-    paths: Arc<Paths>,
+    client: Client,
+    config: Arc<Config>,
     frames: Arc<Frames>,
+    shared: Arc<Shared>,
+    restart: Option<RestartSender>,
+    prefix: &str,
 ) -> anyhow::Result<Response<Body>> {
+    let paths = &config.paths;
     let path = req
         .uri()
         .path_and_query()
         .map(|pq| pq.as_str())
         .unwrap_or("");
+    let query = req.uri().query().unwrap_or("");
+    let user_agent = req
+        .headers()
+        .get("user-agent")
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("");
     if path == "/" {
-        index(&paths)
-    } else if path == paths.stream {
-        handle_stream(frames).await
-    } else if path == paths.snapshot {
-        handle_snapshot(frames).await
+        return index(&config, &frames, prefix);
+    }
+    if let (Some(origin), &Method::OPTIONS) = (&config.cors_origin, req.method()) {
+        // Preflights never carry credentials, so this has to come before checking them.
+        return cors_preflight(origin);
+    }
+    if let Some(auth) = config
+        .auth
+        .as_ref()
+        .filter(|_| config.needs_auth(prefix, req.uri().path()))
+        .filter(|_| !(config.auth_allow_localhost && is_loopback(client.peer)))
+    {
+        let given = req.headers().get("Authorization");
+        if !given.is_some_and(|h| auth.matches(h.as_bytes())) {
+            if given.is_some() {
+                warn!("wrong credentials from {}", client.ip);
+            }
+            return unauthorized();
+        }
+    }
+    let cors = config
+        .cors_origin
+        .clone()
+        .filter(|_| gets_cors(paths, req.uri()));
+    let resp = if path_matches(&paths.stream, req.uri()) {
+        handle_stream(
+            &config, frames, &shared, client.ip, user_agent, query, false,
+        )
+        .await
+    } else if path_matches(&paths.status, req.uri()) {
+        handle_status(frames)
+    } else if path_matches(&paths.devices, req.uri()) {
+        handle_devices().await
+    } else if path_matches(&paths.snapshot, req.uri()) {
+        let accept = req
+            .headers()
+            .get("Accept")
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or("");
+        handle_snapshot(&config, frames, &shared, query, accept).await
+    } else if let (Some(dir), Some(name)) =
+        (&config.hls_dir, req.uri().path().strip_prefix("/hls/"))
+    {
+        handle_hls(dir, name).await
+    } else if req.uri().path() == THUMB_PATH && frames.video().has_thumbnails() {
+        handle_stream(&config, frames, &shared, client.ip, user_agent, query, true).await
+    } else if req.uri().path() == EVENTS_PATH {
+        handle_events(frames)
+    } else if req.uri().path() == CAPS_PATH {
+        handle_caps(frames).await
+    } else if req.uri().path() == MOTION_PATH && frames.video().has_motion() {
+        handle_motion(config.snapshot_timeout, frames).await
+    } else if req.uri().path() == INFO_PATH {
+        handle_info(frames, &shared).await
+    } else if req.uri().path() == METRICS_PATH {
+        handle_metrics(frames, &shared).await
+    } else if req.uri().path() == STATS_PATH {
+        handle_stats(frames, &shared)
+    } else if let Some(allowed) = control_methods(req.uri().path()) {
+        if !allowed.contains(req.method()) {
+            return method_not_allowed(&allowed);
+        }
+        match req.uri().path() {
+            CONTROL_STATE_PATH => handle_control_state(req, frames).await,
+            CONTROL_BALANCE_PATH => handle_control_balance(req.method(), query, frames),
+            CONTROL_SAVE_CLIP_PATH => handle_save_clip(&config, frames),
+            CONTROL_RESTART_PATH => handle_restart(restart).await,
+            _ => unreachable!(),
+        }
     } else {
-        Ok(Response::builder()
-            .status(404)
-            .body(format!("nothing configured for the path {path:?}").into())?)
+        not_found(path)
+    };
+    resp.map(|resp| with_cors(cors.as_ref(), resp))
+}
+
+/// The methods each control endpoint supports, or `None` if the path isn't one.
+fn control_methods(path: &str) -> Option<Vec<Method>> {
+    Some(match path {
+        CONTROL_STATE_PATH | CONTROL_BALANCE_PATH => vec![Method::GET, Method::POST],
+        CONTROL_SAVE_CLIP_PATH | CONTROL_RESTART_PATH => vec![Method::POST],
+        _ => return None,
+    })
+}
+
+fn method_not_allowed(allowed: &[Method]) -> anyhow::Result<Response<Body>> {
+    let allow = allowed
+        .iter()
+        .map(Method::as_str)
+        .collect::<Vec<_>>()
+        .join(", ");
+    Response::builder()
+        .status(405)
+        .header("Allow", &allow)
+        .header("Content-Type", "text/plain")
+        .body(format!("method not allowed; use {allow}").into())
+        .context("failed to build method not allowed response")
+}
+
+/// Whether a camera called `name`, whose paths all start with `/name/`, would hide any of the
+/// server's own paths.
+pub fn shadows_path(name: &str, paths: &Paths) -> bool {
+    let configured = [
+        &paths.stream,
+        &paths.snapshot,
+        &paths.status,
+        &paths.devices,
+    ];
+    FIXED_PATHS
+        .iter()
+        .copied()
+        .chain(configured.into_iter().flatten().map(String::as_str))
+        .any(|path| path.trim_start_matches('/').split(['/', '?']).next() == Some(name))
+}
+
+/// A camera to serve: the main one, or another one under `/NAME/`.
+pub struct Camera {
+    pub frames: Arc<Frames>,
+    /// Where to ask for its pipeline to be rebuilt, if that's allowed.
+    pub restart: Option<RestartSender>,
+}
+
+/// If a request is for one of the extra cameras (its path starts with `/NAME/`), take the name
+/// off the path, leaving the path the main camera would have, and return the camera's name and
+/// the camera.
+fn route_camera<'a>(
+    cameras: &'a HashMap<String, Camera>,
+    req: &mut Request<Body>,
+) -> Option<(&'a str, &'a Camera)> {
+    let path = req.uri().path().strip_prefix('/')?;
+    let (name, rest) = path.split_once('/').unwrap_or((path, ""));
+    let (name, camera) = cameras.get_key_value(name)?;
+    let mut new = format!("/{rest}");
+    if let Some(query) = req.uri().query() {
+        new += &format!("?{query}");
     }
+    *req.uri_mut() = new.parse().ok()?;
+    Some((name, camera))
+}
+
+/// Check whether a request URI is for any of the given configured paths.
+///
+/// A configured path with a query string in it (like mjpg-streamer's `/?action=stream`) has to
+/// match the full path and query; otherwise the query string is ignored.
+fn path_matches(configured: &[String], uri: &Uri) -> bool {
+    configured.iter().any(|configured| {
+        if configured.contains('?') {
+            uri.path_and_query().map(|pq| pq.as_str()) == Some(configured.as_str())
+        } else {
+            uri.path() == configured
+        }
+    })
+}
+
+/// Whether a path is `prefix`, or something under it. `/control` covers `/control/restart`,
+/// but not `/controls`.
+fn under_prefix(path: &str, prefix: &str) -> bool {
+    let prefix = prefix.trim_end_matches('/');
+    path.strip_prefix(prefix)
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
 }
 
-async fn handle_stream(frames: Arc<Frames>) -> anyhow::Result<Response<Body>> {
-    let bdry = uuid_string_random();
-    let stream = frames.stream().await;
-    let parts = stream.map(|(buf, ts)| {
+/// Look up the value of a parameter in a URL query string.
+fn query_param<'a>(query: &'a str, name: &str) -> Option<&'a str> {
+    query
+        .split('&')
+        .filter_map(|pair| pair.split_once('=').or(Some((pair, ""))))
+        .find(|(k, _)| *k == name)
+        .map(|(_, v)| v)
+}
+
+/// Undo URL encoding of a query string value (`%XX` escapes, and `+` for spaces). `None` if it
+/// isn't valid UTF-8 afterwards.
+fn percent_decode(s: &str) -> Option<String> {
+    let mut out = Vec::with_capacity(s.len());
+    let mut bytes = s.bytes();
+    while let Some(b) = bytes.next() {
+        match b {
+            b'+' => out.push(b' '),
+            b'%' => {
+                let hex = [bytes.next()?, bytes.next()?];
+                let hex = std::str::from_utf8(&hex).ok()?;
+                out.push(u8::from_str_radix(hex, 16).ok()?);
+            }
+            b => out.push(b),
+        }
+    }
+    String::from_utf8(out).ok()
+}
+
+/// Read a whole request body, or return `None` if it's longer than `MAX_BODY_BYTES`.
+async fn read_body(mut body: Body) -> anyhow::Result<Option<BytesMut>> {
+    let mut buf = BytesMut::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.context("failed to read request body")?;
+        if buf.len() + chunk.len() > MAX_BODY_BYTES {
+            return Ok(None);
+        }
+        buf.extend_from_slice(&chunk);
+    }
+    Ok(Some(buf))
+}
+
+fn payload_too_large() -> anyhow::Result<Response<Body>> {
+    Response::builder()
+        .status(413)
+        .header("Content-Type", "text/plain")
+        .body(format!("request body is limited to {MAX_BODY_BYTES} bytes").into())
+        .context("failed to build payload too large response")
+}
+
+fn bad_request(msg: impl std::fmt::Display) -> anyhow::Result<Response<Body>> {
+    Response::builder()
+        .status(400)
+        .header("Content-Type", "text/plain")
+        .body(format!("bad request: {msg}").into())
+        .context("failed to build bad request response")
+}
+
+async fn handle_stream(
+    config: &Config,
+    frames: Arc<Frames>,
+    shared: &Shared,
+    client: IpAddr,
+    user_agent: &str,
+    query: &str,
+    thumbnails: bool,
+) -> anyhow::Result<Response<Body>> {
+    if shared.draining.load(Ordering::SeqCst) {
+        // It would only be cut off in a moment anyway.
+        return Response::builder()
+            .status(503)
+            .header("Content-Type", "text/plain")
+            .header("Connection", "close")
+            .body("the server is shutting down".into())
+            .context("failed to build service unavailable response");
+    }
+    let max_fps = match query_param(query, "fps").map(str::parse::<f64>) {
+        None => None,
+        Some(Ok(fps)) if fps > 0. && fps.is_finite() => Some(fps),
+        Some(_) => return bad_request("fps must be a positive number"),
+    };
+    let slow_client = match config.slow_client.with_query(query) {
+        Ok(policy) => policy,
+        Err(e) => return bad_request(e),
+    };
+    // The global cap always applies; clients can only ask for less.
+    let cap = if thumbnails {
+        config.thumbnail_max_fps
+    } else {
+        config.stream_max_fps
+    };
+    let max_fps = match (max_fps, cap) {
+        (Some(a), Some(b)) => Some(a.min(b)),
+        (a, b) => a.or(b),
+    };
+    let bdry = stream_boundary(&config.boundary);
+    let Some(slot) = shared.streams.acquire(config.max_clients) else {
+        warn!("already sending the most streams allowed; refusing another");
+        return at_capacity();
+    };
+    let frame_numbers = config.frame_numbers;
+    let debug_timing = config.debug_timing;
+    let stream_metadata = config.stream_metadata;
+    let quirks = config.quirks(user_agent);
+    if !quirks.is_empty() {
+        debug!("using quirks {quirks:?} for {user_agent:?}");
+    }
+    let minimal_headers = config.minimal_headers || quirks.contains(&Quirk::MinimalHeaders);
+    let timestamps = !quirks.contains(&Quirk::NoTimestamp);
+    let trailing_crlf = quirks.contains(&Quirk::TrailingCrlf);
+    let codec = if thumbnails {
+        Codec::Mjpeg
+    } else {
+        frames.video().codec()
+    };
+    let part_type = config
+        .part_content_type
+        .clone()
+        .unwrap_or_else(|| HeaderValue::from_static(codec.mime_type()));
+    let mut stream = if thumbnails {
+        frames.clone().thumbnail_stream().await
+    } else {
+        frames.clone().stream().await
+    };
+    if !frames
+        .video()
+        .wait_for_state(State::Playing, STREAM_START_TIMEOUT)
+        .await
+    {
+        warn!("video didn't start within {STREAM_START_TIMEOUT:?}; not starting stream");
+        let body = match frames.video().last_error() {
+            Some(e) => format!("video failed to start: {e}"),
+            None => "video isn't running yet; try again shortly".to_owned(),
+        };
+        return Response::builder()
+            .status(503)
+            .header("Content-Type", "text/plain")
+            .header("Retry-After", STREAM_START_TIMEOUT.as_secs().to_string())
+            .body(body.into())
+            .context("failed to build service unavailable response");
+    }
+    if let Some(fps) = max_fps {
+        stream = stream.max_fps(fps);
+    }
+    stream = stream.for_client(client);
+    if let SlowClient::Disconnect { lags } = slow_client {
+        stream = stream.disconnect_after_lags(lags);
+    }
+    let with_crlf = move |data: Bytes| {
+        if trailing_crlf {
+            let mut body = BytesMut::with_capacity(data.len() + 2);
+            body.extend_from_slice(&data);
+            body.extend_from_slice(b"\r\n");
+            body.freeze()
+        } else {
+            data
+        }
+    };
+    let metadata_frames = frames.clone();
+    let parts = stream.flat_map(move |frame| {
+        // Held until the stream ends.
+        let _slot = &slot;
+        let metadata = stream_metadata.then(|| {
+            // Thumbnails are a different size from the main video, and it isn't tracked.
+            let resolution = (!thumbnails)
+                .then(|| metadata_frames.video().resolution())
+                .flatten();
+            let json = json!({
+                "timestamp": frame.timestamp.map(|ts| ts.as_secs_f64()),
+                "captured": frame
+                    .captured
+                    .duration_since(SystemTime::UNIX_EPOCH)
+                    .ok()
+                    .map(|t| t.as_secs_f64()),
+                "frame_number": frame.number,
+                "width": resolution.map(|(w, _)| w),
+                "height": resolution.map(|(_, h)| h),
+            });
+            let mut headers = HeaderMap::new();
+            headers.append("Content-Type", HeaderValue::from_static("application/json"));
+            Part {
+                headers,
+                body: with_crlf(json.to_string().into()),
+            }
+        });
         let mut headers = HeaderMap::new();
-        headers.append("Content-Type", HeaderValue::from_static("image/jpeg"));
-        if let Some(ts) = ts {
-            headers.append(
-                "X-Timestamp",
-                HeaderValue::from_str(&format!("{}.{:.06}", ts.as_secs(), ts.subsec_micros()))
-                    .unwrap(),
-            );
+        headers.append("Content-Type", part_type.clone());
+        if !minimal_headers {
+            if let (Some(ts), true) = (frame.timestamp, timestamps) {
+                headers.append(
+                    "X-Timestamp",
+                    HeaderValue::from_str(&format_timestamp(ts)).unwrap(),
+                );
+            }
+            if frame_numbers {
+                headers.append("X-Frame-Number", HeaderValue::from(frame.number));
+            }
+            if debug_timing {
+                let timing = [
+                    ("X-PTS", frame.pts),
+                    ("X-DTS", frame.timestamp),
+                    ("X-Duration", frame.duration),
+                ];
+                for (name, value) in timing {
+                    if let Some(value) = value {
+                        headers.append(name, HeaderValue::from(value.as_nanos() as u64));
+                    }
+                }
+            }
+            if let Some(latency) = frame.latency {
+                headers.append(
+                    "X-Encode-Latency-Ms",
+                    HeaderValue::from_str(&format!("{:.1}", latency.as_secs_f64() * 1000.))
+                        .unwrap(),
+                );
+            }
         }
-        Ok::<_, Infallible>(Part { headers, body: buf })
+        let part = Part {
+            headers,
+            body: with_crlf(frame.data),
+        };
+        futures::stream::iter(
+            std::iter::once(part)
+                .chain(metadata)
+                .map(Ok::<_, Infallible>),
+        )
     });
     let body = Body::wrap_stream(multipart_stream::serialize(parts, bdry.as_str()));
     let mut resp = Response::new(body);
     resp.headers_mut().insert(
         "Content-Type",
         HeaderValue::from_str(&format!(
-            "multipart/x-mixed-replace;boundary={}",
+            "{};boundary={}",
+            config.multipart_type.mime_type(),
             bdry.as_str()
         ))
         .unwrap(),
     );
+    if !thumbnails {
+        if let Some(res) = resolution_header(&frames) {
+            resp.headers_mut().insert("X-Resolution", res);
+        }
+    }
     Ok(resp)
 }
 
-async fn handle_snapshot(frames: Arc<Frames>) -> anyhow::Result<Response<Body>> {
-    let (frame, _ts) = match frames.stream().await.next().await {
-        Some(frame) => frame,
-        None => {
-            return server_error(anyhow::anyhow!("no frames from video source")).map_err(Into::into)
+/// The `X-Resolution` header value, if the resolution is known yet.
+fn resolution_header(frames: &Frames) -> Option<HeaderValue> {
+    let (w, h) = frames.video().resolution()?;
+    Some(HeaderValue::from_str(&format!("{w}x{h}")).unwrap())
+}
+
+async fn handle_snapshot(
+    config: &Config,
+    frames: Arc<Frames>,
+    shared: &Shared,
+    query: &str,
+    accept: &str,
+) -> anyhow::Result<Response<Body>> {
+    let rotation = match query_param(query, "rotate").map(str::parse::<Rotation>) {
+        None => Rotation::None,
+        Some(Ok(r)) => r,
+        Some(Err(e)) => return bad_request(e),
+    };
+    let width = match query_param(query, "width").map(str::parse::<u32>) {
+        None => None,
+        Some(Ok(w)) if w > 0 => Some(w.min(MAX_SNAPSHOT_SIZE)),
+        Some(_) => return bad_request("width must be a positive whole number"),
+    };
+    let height = match query_param(query, "height").map(str::parse::<u32>) {
+        None => None,
+        Some(Ok(h)) if h > 0 => Some(h.min(MAX_SNAPSHOT_SIZE)),
+        Some(_) => return bad_request("height must be a positive whole number"),
+    };
+    let quality = match query_param(query, "quality").map(str::parse::<i32>) {
+        None => None,
+        Some(Ok(q)) if (0..=100).contains(&q) => Some(q),
+        Some(_) => return bad_request("quality must be a whole number from 0 to 100"),
+    };
+    let mut reencode = Reencode {
+        rotation,
+        width,
+        height,
+        quality,
+    };
+    let format = query_param(query, "format")
+        .or_else(|| negotiate_format(accept, frames.video().has_raw_frames()));
+    let data_uri = format == Some("datauri");
+    let raw_format = match format {
+        None | Some("jpeg") | Some("datauri") => None,
+        Some(format) => match format.parse::<RawFormat>() {
+            Ok(format) => Some(format),
+            Err(e) => return bad_request(e),
+        },
+    };
+    let max_age = match query_param(query, "max_age_ms").map(str::parse::<u64>) {
+        None => None,
+        Some(Ok(ms)) => Some(Duration::from_millis(ms)),
+        Some(Err(_)) => return bad_request("max_age_ms must be a whole number of milliseconds"),
+    };
+    let fresh = match query_param(query, "fresh") {
+        None | Some("0") => false,
+        Some("1") => true,
+        Some(_) => return bad_request("fresh must be 0 or 1"),
+    };
+    if fresh && max_age.is_some() {
+        return bad_request("fresh and max_age_ms can't be used together");
+    }
+    let codec = frames.video().codec();
+    // The configured orientation only applies where it can be: re-encoded JPEGs.
+    if codec == Codec::Mjpeg && raw_format.is_none() {
+        reencode.rotation = config.snapshot_rotation + reencode.rotation;
+    }
+    if !reencode.is_noop() && (codec != Codec::Mjpeg || raw_format.is_some()) {
+        return bad_request(
+            "rotate, width, height, and quality are only supported for JPEG snapshots",
+        );
+    }
+    if data_uri && codec != Codec::Mjpeg {
+        return bad_request("format=datauri is only supported for JPEG snapshots");
+    }
+    let comment = match query_param(query, "comment").map(percent_decode) {
+        None => config.snapshot_comment.clone(),
+        Some(Some(c)) if c.len() <= exif::MAX_COM_BYTES => Some(c),
+        Some(_) => return bad_request("comment must be valid UTF-8 and not too long"),
+    };
+    if comment.is_some() && (codec != Codec::Mjpeg || raw_format.is_some()) {
+        return bad_request("comments are only supported for JPEG snapshots");
+    }
+    if let Some(path) = &config.placeholder {
+        // Snapshotting would have to start the video.
+        if !frames.is_running() {
+            return placeholder(path).await;
+        }
+    }
+    let (frame, content_type, timestamp) = if let Some(format) = raw_format {
+        let video = frames.video().clone();
+        if !video.has_raw_frames() {
+            return bad_request("uncompressed snapshots need --debug-endpoints");
+        }
+        // Holding a stream keeps the video running while the frame is pulled.
+        let _stream = frames.clone().stream().await;
+        let timeout = config.snapshot_timeout;
+        let raw = tokio::task::spawn_blocking(move || video.pull_raw_frame(timeout))
+            .await
+            .context("raw frame task failed")??;
+        (format.encode(&raw), format.mime_type(), None)
+    } else {
+        let frame = match max_age {
+            _ if fresh => capture_next_frame(config, frames.clone())
+                .await
+                .ok_or_else(|| format!("no new frame within {:?}", config.snapshot_timeout)),
+            None => Ok(rate_limited_snapshot(config, shared, frames.clone()).await?),
+            Some(max_age) => capture_fresh_snapshot(config, frames.clone(), max_age)
+                .await
+                .ok_or_else(|| {
+                    format!(
+                        "no frame newer than {max_age:?} within {:?}",
+                        config.snapshot_timeout
+                    )
+                }),
+        };
+        let frame = match frame {
+            Ok(frame) => frame,
+            Err(message) => {
+                return Response::builder()
+                    .status(503)
+                    .header("Content-Type", "text/plain")
+                    .body(message.into())
+                    .context("failed to build service unavailable response")
+            }
+        };
+        let timestamp = frame.timestamp;
+        let captured = frame.captured;
+        let mut data = frame.data.clone();
+        if !reencode.is_noop() {
+            data = shared
+                .conversion(&frame, reencode)
+                .get_or_try_init(|| async move {
+                    tokio::task::spawn_blocking(move || video::reencode_jpeg(data, &reencode))
+                        .await
+                        .context("re-encode task failed")?
+                })
+                .await?
+                .clone();
+        }
+        let mut frame = data;
+        if config.snapshot_exif && codec == Codec::Mjpeg {
+            frame = exif::insert(&frame, captured, config.snapshot_exif_comment.as_deref())?;
+        }
+        if let Some(comment) = &comment {
+            frame = exif::insert_comment(&frame, comment)?;
+        }
+        if data_uri {
+            if frame.len() > MAX_DATA_URI_BYTES {
+                return bad_request(format!(
+                    "snapshot is {} bytes, more than the {MAX_DATA_URI_BYTES} allowed for \
+                    format=datauri; use width or quality to make it smaller",
+                    frame.len()
+                ));
+            }
+            let body = json!({
+                "timestamp": timestamp.map(|t| t.as_secs_f64()),
+                "image": format!("data:{};base64,{}", codec.mime_type(), base64(&frame)),
+            });
+            (body.to_string().into(), "application/json", timestamp)
+        } else {
+            (frame, codec.mime_type(), timestamp)
+        }
+    };
+    let mut resp = Response::builder()
+        .header("Content-Type", content_type)
+        .header("Vary", "Accept");
+    // Cache-Control only has a resolution of seconds.
+    let cache_secs = config.snapshot_cache.as_secs();
+    if cache_secs > 0 {
+        resp = resp.header("Cache-Control", format!("max-age={cache_secs}"));
+    }
+    if let Some(ts) = timestamp {
+        resp = resp.header("X-Timestamp", format_timestamp(ts));
+    }
+    // After re-encoding, the resolution from the pipeline may be wrong.
+    if reencode.is_noop() {
+        if let Some(res) = resolution_header(&frames) {
+            resp = resp.header("X-Resolution", res);
+        }
+    }
+    resp.body(frame.into())
+        .context("failed to make snapshot response")
+}
+
+/// The multipart boundary for a stream: the configured one, or else a random one.
+fn stream_boundary(configured: &Option<Boundary>) -> String {
+    match configured {
+        Some(Boundary(b)) => b.clone(),
+        None => random_boundary(),
+    }
+}
+
+/// Make up a multipart boundary that won't turn up in a frame by chance: 128 random bits, in hex.
+fn random_boundary() -> String {
+    format!("{:032x}", rand::random::<u128>())
+}
+
+/// Pick a snapshot `format` from an `Accept` header, by its `q` values (the first listed wins a
+/// tie). `None` means the usual encoded frame, which is also what anything unsupported gets.
+/// Uncompressed formats are only considered if `raw` frames are available.
+fn negotiate_format(accept: &str, raw: bool) -> Option<&'static str> {
+    let mut best: Option<(f32, Option<&'static str>)> = None;
+    for item in accept.split(',') {
+        let mut params = item.split(';');
+        let format = match params.next().unwrap_or("").trim() {
+            "image/jpeg" | "image/*" | "*/*" => None,
+            "image/x-portable-pixmap" if raw => Some("ppm"),
+            "image/bmp" if raw => Some("bmp"),
+            _ => continue,
+        };
+        let q = params
+            .find_map(|p| p.trim().strip_prefix("q="))
+            .and_then(|q| q.parse::<f32>().ok())
+            .unwrap_or(1.);
+        if q > 0. && !matches!(best, Some((best_q, _)) if best_q >= q) {
+            best = Some((q, format));
         }
+    }
+    best.and_then(|(_, format)| format)
+}
+
+/// Respond with the placeholder image, read fresh so it can be changed while running.
+async fn placeholder(path: &Path) -> anyhow::Result<Response<Body>> {
+    let content_type = match path.extension().and_then(|e| e.to_str()) {
+        Some("jpg" | "jpeg") => "image/jpeg",
+        Some("png") => "image/png",
+        Some("gif") => "image/gif",
+        Some("webp") => "image/webp",
+        _ => "application/octet-stream",
     };
+    let data = tokio::fs::read(path)
+        .await
+        .with_context(|| format!("failed to read placeholder {path:?}"))?;
     Response::builder()
-        .header("Content-Type", "image/jpeg")
-        .body(frame.into())
-        .context("failed to make snapshot response")
+        .header("Content-Type", content_type)
+        .header("X-Placeholder", "1")
+        .body(data.into())
+        .context("failed to build placeholder response")
+}
+
+/// Standard base64 with padding, as used in data URIs.
+fn base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, &b)| n | u32::from(b) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[(n >> (18 - 6 * i) & 63) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+/// Get a frame for a snapshot, but no more often than `snapshot_max_fps`; until it's time for a
+/// new one, the last one is reused.
+async fn rate_limited_snapshot(
+    config: &Config,
+    shared: &Shared,
+    frames: Arc<Frames>,
+) -> anyhow::Result<Frame> {
+    let Some(fps) = config.snapshot_max_fps else {
+        return capture_snapshot(config, frames).await;
+    };
+    if let Some((at, frame)) = &*shared.last_snapshot.lock().unwrap() {
+        if at.elapsed() < Duration::from_secs_f64(1. / fps) {
+            return Ok(frame.clone());
+        }
+    }
+    let frame = capture_snapshot(config, frames).await?;
+    *shared.last_snapshot.lock().unwrap() = Some((Instant::now(), frame.clone()));
+    Ok(frame)
+}
+
+/// Get a frame for a snapshot: the most recent one if the video is running, or else the first one
+/// after starting it. Each attempt waits up to the snapshot timeout, and failed ones are retried.
+async fn capture_snapshot(config: &Config, frames: Arc<Frames>) -> anyhow::Result<Frame> {
+    let mut attempt = 0;
+    loop {
+        if let Some(frame) = frames.latest() {
+            return Ok(frame);
+        }
+        let error = match frames.clone().capture_one(config.snapshot_timeout).await {
+            Ok(frame) => return Ok(frame),
+            Err(e) => e,
+        };
+        attempt += 1;
+        if attempt > config.snapshot_retries {
+            return Err(error);
+        }
+        warn!("snapshot attempt {attempt} failed: {error}; retrying");
+        tokio::time::sleep(SNAPSHOT_RETRY_DELAY).await;
+    }
+}
+
+/// Wait for the next frame from the pipeline, ignoring any that arrived before the request.
+async fn capture_next_frame(config: &Config, frames: Arc<Frames>) -> Option<Frame> {
+    let mut stream = frames.stream().await;
+    tokio::time::timeout(config.snapshot_timeout, stream.next())
+        .await
+        .ok()
+        .flatten()
+}
+
+/// Get a frame for a snapshot that was captured no more than `max_age` ago, waiting up to the
+/// snapshot timeout for one. Returns `None` if there wasn't one in time.
+async fn capture_fresh_snapshot(
+    config: &Config,
+    frames: Arc<Frames>,
+    max_age: Duration,
+) -> Option<Frame> {
+    let video = frames.video().clone();
+    let is_fresh = |frame: &Frame| match (frame.timestamp, video.running_time()) {
+        (Some(ts), Some(now)) => now.saturating_sub(ts) <= max_age,
+        _ => false,
+    };
+    if let Some(frame) = frames.latest().filter(is_fresh) {
+        return Some(frame);
+    }
+    let mut stream = frames.clone().stream().await;
+    let deadline = tokio::time::Instant::now() + config.snapshot_timeout;
+    while let Ok(Some(frame)) = tokio::time::timeout_at(deadline, stream.next()).await {
+        // A frame without a timestamp is still newer than the request.
+        if frame.timestamp.is_none() || is_fresh(&frame) {
+            return Some(frame);
+        }
+    }
+    None
+}
+
+async fn handle_hls(dir: &Path, name: &str) -> anyhow::Result<Response<Body>> {
+    let content_type = if name.ends_with(".m3u8") {
+        "application/vnd.apple.mpegurl"
+    } else if name.ends_with(".ts") {
+        "video/mp2t"
+    } else {
+        ""
+    };
+    // Only serve plain files that hlssink2 writes, with no path components.
+    if content_type.is_empty() || name.contains('/') || name.starts_with('.') {
+        return not_found(name);
+    }
+    match tokio::fs::read(dir.join(name)).await {
+        // Segments never change once written, but the playlist does.
+        Ok(data) if content_type == "video/mp2t" => Response::builder()
+            .header("Content-Type", content_type)
+            .header("Cache-Control", "max-age=60")
+            .body(data.into())
+            .context("failed to build HLS response"),
+        Ok(data) => Response::builder()
+            .header("Content-Type", content_type)
+            .body(data.into())
+            .context("failed to build HLS response"),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => not_found(name),
+        Err(e) => Err(e).with_context(|| format!("failed to read HLS file {name:?}")),
+    }
+}
+
+/// Methods scripts from other origins may use, for CORS.
+const CORS_METHODS: &str = "GET, OPTIONS";
+
+/// Whether a request is for something other sites' scripts may fetch, when CORS is enabled:
+/// the stream, thumbnails, snapshots, or status.
+fn gets_cors(paths: &Paths, uri: &Uri) -> bool {
+    path_matches(&paths.stream, uri)
+        || path_matches(&paths.snapshot, uri)
+        || path_matches(&paths.status, uri)
+        || uri.path() == THUMB_PATH
+}
+
+/// Add CORS headers to a response, if `origin` is given.
+fn with_cors(origin: Option<&HeaderValue>, mut resp: Response<Body>) -> Response<Body> {
+    if let Some(origin) = origin {
+        let headers = resp.headers_mut();
+        headers.insert("Access-Control-Allow-Origin", origin.clone());
+        headers.insert(
+            "Access-Control-Allow-Methods",
+            HeaderValue::from_static(CORS_METHODS),
+        );
+    }
+    resp
+}
+
+/// Answer a CORS preflight request, allowing `origin` to make the real one.
+fn cors_preflight(origin: &HeaderValue) -> anyhow::Result<Response<Body>> {
+    Response::builder()
+        .status(204)
+        .header("Access-Control-Allow-Origin", origin)
+        .header("Access-Control-Allow-Methods", CORS_METHODS)
+        // For --auth.
+        .header("Access-Control-Allow-Headers", "Authorization")
+        .body(Body::empty())
+        .context("failed to build preflight response")
+}
+
+fn unauthorized() -> anyhow::Result<Response<Body>> {
+    Response::builder()
+        .status(401)
+        .header(
+            "WWW-Authenticate",
+            "Basic realm=\"gst-mjpg\", charset=\"UTF-8\"",
+        )
+        .header("Content-Type", "text/plain")
+        .body("a user name and password are needed".into())
+        .context("failed to build unauthorized response")
+}
+
+fn not_found(path: &str) -> anyhow::Result<Response<Body>> {
+    Ok(Response::builder()
+        .status(404)
+        .body(format!("nothing configured for the path {path:?}").into())?)
+}
+
+fn handle_events(frames: Arc<Frames>) -> anyhow::Result<Response<Body>> {
+    let events = BroadcastStream::new(frames.events().subscribe()).filter_map(|event| async move {
+        // If we fell behind, just skip the missed events.
+        let event = event.ok()?;
+        Some(format!(
+            "event: {}\ndata: {}\n\n",
+            event.name(),
+            event.to_json()
+        ))
+    });
+    // Comment lines are ignored by clients.
+    let keepalive = IntervalStream::new(tokio::time::interval(EVENTS_KEEPALIVE_INTERVAL))
+        .map(|_| ":\n\n".to_owned());
+    let events = futures::stream::select(events, keepalive).map(Ok::<_, Infallible>);
+    Response::builder()
+        .header("Content-Type", "text/event-stream")
+        .body(Body::wrap_stream(events))
+        .context("failed to build events response")
+}
+
+async fn handle_caps(frames: Arc<Frames>) -> anyhow::Result<Response<Body>> {
+    // Holding a stream keeps the camera open while it's asked.
+    let _stream = frames.clone().stream().await;
+    let caps = frames.video().running_camera_caps()?;
+    // Some sources (like decoders) can produce anything at all, and don't list modes.
+    let body = json!({ "any": caps.is_any(), "modes": caps_modes(&caps) });
+    Response::builder()
+        .header("Content-Type", "application/json")
+        .body(body.to_string().into())
+        .context("failed to build caps response")
+}
+
+/// Each structure in some caps as a JSON object, with its `media_type` and fields.
+fn caps_modes(caps: &gstreamer::Caps) -> Vec<serde_json::Value> {
+    caps.iter()
+        .map(|s| {
+            let mut mode = serde_json::Map::new();
+            mode.insert("media_type".into(), s.name().as_str().into());
+            for (name, value) in s.iter() {
+                mode.insert(name.as_str().into(), value_json(value));
+            }
+            mode.into()
+        })
+        .collect()
+}
+
+async fn handle_devices() -> anyhow::Result<Response<Body>> {
+    let cameras = tokio::task::spawn_blocking(video::list_cameras)
+        .await
+        .context("camera listing task failed")?;
+    let devices: Vec<serde_json::Value> = cameras
+        .iter()
+        .map(|camera| {
+            json!({
+                "name": camera.name,
+                "path": camera.path,
+                "modes": camera.caps.as_ref().map(caps_modes).unwrap_or_default(),
+            })
+        })
+        .collect();
+    Response::builder()
+        .header("Content-Type", "application/json")
+        .body(json!({ "devices": devices }).to_string().into())
+        .context("failed to build devices response")
+}
+
+/// A caps field's value as JSON. Ranges (which sources that can scale, like the test source,
+/// use for sizes and frame rates) become `{"min", "max"}` objects, lists become arrays, and
+/// fractions become strings like "30/1".
+fn value_json(value: &gstreamer::glib::Value) -> serde_json::Value {
+    if let Ok(n) = value.get::<i32>() {
+        n.into()
+    } else if let Ok(r) = value.get::<IntRange<i32>>() {
+        json!({ "min": r.min(), "max": r.max(), "step": r.step() })
+    } else if let Ok(f) = value.get::<Fraction>() {
+        f.to_string().into()
+    } else if let Ok(r) = value.get::<FractionRange>() {
+        json!({ "min": r.min().to_string(), "max": r.max().to_string() })
+    } else if let Ok(list) = value.get::<gstreamer::List>() {
+        list.iter().map(|v| value_json(v)).collect()
+    } else if let Ok(s) = value.get::<String>() {
+        s.into()
+    } else {
+        value
+            .serialize()
+            .map(|s| s.as_str().into())
+            .unwrap_or_default()
+    }
+}
+
+async fn handle_motion(timeout: Duration, frames: Arc<Frames>) -> anyhow::Result<Response<Body>> {
+    let video = frames.video();
+    // Holding a stream keeps the video running while the frames are pulled.
+    let _stream = frames.clone().stream().await;
+    let score = tokio::task::spawn_blocking(move || video.motion_score(timeout))
+        .await
+        .context("motion task failed")??;
+    Response::builder()
+        .header("Content-Type", "application/json")
+        .body(json!({ "score": score }).to_string().into())
+        .context("failed to build motion response")
+}
+
+/// The response refusing a stream when `max_clients` are already being sent.
+fn at_capacity() -> anyhow::Result<Response<Body>> {
+    Response::builder()
+        .status(503)
+        .header("Content-Type", "text/plain")
+        .header("Retry-After", MAX_CLIENTS_RETRY_AFTER.as_secs().to_string())
+        .body("the server is at capacity; try again later".into())
+        .context("failed to build service unavailable response")
+}
+
+/// A frame timestamp as seconds, to the microsecond, for `X-Timestamp` headers.
+fn format_timestamp(ts: Duration) -> String {
+    format!("{}.{:06}", ts.as_secs(), ts.subsec_micros())
+}
+
+fn unix_secs(t: SystemTime) -> f64 {
+    t.duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs_f64()
+}
+
+fn throughput_json(t: &Throughput) -> serde_json::Value {
+    json!({
+        "frames_per_sec": t.frames_per_sec,
+        "bytes_per_frame": t.bytes_per_frame,
+        "bits_per_sec": t.bits_per_sec,
+        "latency_ms": t.latency.map(|l| l.as_secs_f64() * 1000.),
+    })
+}
+
+async fn handle_info(frames: Arc<Frames>, shared: &Shared) -> anyhow::Result<Response<Body>> {
+    let stats = frames.stats();
+    let info = json!({
+        "version": env!("CARGO_PKG_VERSION"),
+        "start_time": unix_secs(shared.started.at),
+        "uptime_secs": shared.started.instant.elapsed().as_secs_f64(),
+        "total_connections": shared.connections.load(Ordering::Relaxed),
+        "subscribers": frames.subscribers(),
+        "encoder": frames.video().encoder_name(),
+        "quality": frames.video().quality(),
+        "pipeline_latency_ms": frames.video().latency().map(|l| l.as_secs_f64() * 1000.),
+        "lagged_frames": frames.lagged(),
+        "oversize_frames": frames.oversize(),
+        "corrupt_frames": frames.corrupt(),
+        "total_frames": stats.total_frames,
+        "total_bytes": stats.total_bytes,
+        "last_frame_bytes": stats.last_frame_bytes,
+        "current": throughput_json(&stats.current),
+        "average": throughput_json(&stats.average),
+    });
+    Response::builder()
+        .header("Content-Type", "application/json")
+        .body(info.to_string().into())
+        .context("failed to build info response")
+}
+
+/// A short summary of whether things are working, for monitoring.
+fn handle_status(frames: Arc<Frames>) -> anyhow::Result<Response<Body>> {
+    let video = frames.video();
+    let format = video.output_format();
+    let status = json!({
+        "version": env!("CARGO_PKG_VERSION"),
+        "subscribers": frames.subscribers(),
+        "playing": video.pipeline_state() == State::Playing,
+        "width": format.map(|f| f.width),
+        "height": format.map(|f| f.height),
+        "framerate": format.and_then(|f| f.framerate),
+    });
+    Response::builder()
+        .header("Content-Type", "application/json")
+        .body(status.to_string().into())
+        .context("failed to build status response")
+}
+
+async fn handle_metrics(frames: Arc<Frames>, shared: &Shared) -> anyhow::Result<Response<Body>> {
+    let stats = frames.stats();
+    let mut out = String::new();
+    let mut metric = |name: &str, kind: &str, help: &str, values: &[(&str, f64)]| {
+        out += &format!("# HELP gst_mjpg_{name} {help}\n# TYPE gst_mjpg_{name} {kind}\n");
+        for (labels, value) in values {
+            out += &format!("gst_mjpg_{name}{labels} {value}\n");
+        }
+    };
+    metric(
+        "start_time_seconds",
+        "gauge",
+        "When the server started, in seconds since the Unix epoch.",
+        &[("", unix_secs(shared.started.at))],
+    );
+    metric(
+        "connections_total",
+        "counter",
+        "HTTP connections accepted, on every port.",
+        &[("", shared.connections.load(Ordering::Relaxed) as f64)],
+    );
+    metric(
+        "subscribers",
+        "gauge",
+        "Number of streams currently receiving frames.",
+        &[("", frames.subscribers() as f64)],
+    );
+    metric(
+        "buffered_bytes",
+        "gauge",
+        "Bytes of frames waiting to be sent to streams.",
+        &[("", frames.buffered_bytes() as f64)],
+    );
+    metric(
+        "oversize_frames_total",
+        "counter",
+        "Frames bigger than --max-frame-bytes.",
+        &[("", frames.oversize() as f64)],
+    );
+    metric(
+        "corrupt_frames_total",
+        "counter",
+        "JPEG frames dropped for looking broken.",
+        &[("", frames.corrupt() as f64)],
+    );
+    if let Some(drops) = frames.video().record_drops() {
+        metric(
+            "record_dropped_frames_total",
+            "counter",
+            "Frames left out of the --record file because the disk couldn't keep up.",
+            &[("", drops as f64)],
+        );
+    }
+    metric(
+        "frames_total",
+        "counter",
+        "Frames captured.",
+        &[("", stats.total_frames as f64)],
+    );
+    metric(
+        "bytes_total",
+        "counter",
+        "Bytes of encoded frames captured.",
+        &[("", stats.total_bytes as f64)],
+    );
+    metric(
+        "frames_per_second",
+        "gauge",
+        "Recent frame rate.",
+        &[
+            ("{window=\"1s\"}", stats.current.frames_per_sec),
+            ("{window=\"10s\"}", stats.average.frames_per_sec),
+        ],
+    );
+    metric(
+        "frame_bytes",
+        "gauge",
+        "Recent average encoded frame size.",
+        &[
+            ("{window=\"1s\"}", stats.current.bytes_per_frame),
+            ("{window=\"10s\"}", stats.average.bytes_per_frame),
+        ],
+    );
+    metric(
+        "bits_per_second",
+        "gauge",
+        "Recent encoded bit rate.",
+        &[
+            ("{window=\"1s\"}", stats.current.bits_per_sec),
+            ("{window=\"10s\"}", stats.average.bits_per_sec),
+        ],
+    );
+    let latencies = [
+        ("{window=\"1s\"}", stats.current.latency),
+        ("{window=\"10s\"}", stats.average.latency),
+    ]
+    .into_iter()
+    .filter_map(|(labels, latency)| Some((labels, latency?.as_secs_f64())))
+    .collect::<Vec<_>>();
+    metric(
+        "encode_latency_seconds",
+        "gauge",
+        "Recent average time from capture to encoded frame.",
+        &latencies,
+    );
+    Response::builder()
+        .header("Content-Type", "text/plain; version=0.0.4")
+        .body(out.into())
+        .context("failed to build metrics response")
+}
+
+fn handle_stats(frames: Arc<Frames>, shared: &Shared) -> anyhow::Result<Response<Body>> {
+    let stats = frames.stats();
+    let values: [(&str, &dyn std::fmt::Display); 10] = [
+        ("uptime_secs", &shared.started.instant.elapsed().as_secs()),
+        ("subscribers", &frames.subscribers()),
+        ("frames", &stats.total_frames),
+        ("bytes", &stats.total_bytes),
+        ("fps", &format!("{:.2}", stats.current.frames_per_sec)),
+        ("fps_10s", &format!("{:.2}", stats.average.frames_per_sec)),
+        (
+            "bits_per_sec",
+            &format!("{:.0}", stats.current.bits_per_sec),
+        ),
+        ("dropped", &frames.lagged()),
+        ("oversize", &frames.oversize()),
+        ("corrupt", &frames.corrupt()),
+    ];
+    let mut out = String::new();
+    for (key, value) in values {
+        out += &format!("{key}={value}\n");
+    }
+    Response::builder()
+        .header("Content-Type", "text/plain")
+        .body(out.into())
+        .context("failed to build stats response")
+}
+
+async fn handle_control_state(
+    req: Request<Body>,
+    frames: Arc<Frames>,
+) -> anyhow::Result<Response<Body>> {
+    let video = frames.video();
+    if *req.method() == Method::POST {
+        let body = match read_body(req.into_body()).await? {
+            Some(body) => body,
+            None => return payload_too_large(),
+        };
+        let requested = std::str::from_utf8(&body).unwrap_or("").trim();
+        let state = match parse_state(requested) {
+            Some(state) => state,
+            None => {
+                return bad_request(format_args!(
+                    "unknown state {requested:?}; expected one of Null, Ready, Paused, Playing"
+                ))
+            }
+        };
+        info!("setting pipeline state to {state:?} by request");
+        video.set_pipeline_state(state)?;
+    }
+    Response::builder()
+        .header("Content-Type", "text/plain")
+        .body(format!("{:?}\n", video.pipeline_state()).into())
+        .context("failed to build pipeline state response")
+}
+
+/// Show the color adjustments, after changing any given in the query string on a POST.
+fn handle_control_balance(
+    method: &Method,
+    query: &str,
+    frames: Arc<Frames>,
+) -> anyhow::Result<Response<Body>> {
+    let video = frames.video();
+    // Passed-through camera frames can't be adjusted.
+    let Some(before) = video.balance() else {
+        return not_found(CONTROL_BALANCE_PATH);
+    };
+    if *method == Method::POST {
+        // Check everything first, so a bad request doesn't change anything.
+        let mut changes = vec![];
+        for (name, _, _) in Balance::RANGES {
+            let Some(value) = query_param(query, name) else {
+                continue;
+            };
+            match value.parse::<f64>() {
+                Ok(value) => changes.push((name, value)),
+                Err(_) => return bad_request(format_args!("{name} must be a number")),
+            }
+        }
+        for &(name, value) in &changes {
+            if let Err(e) = Balance::check(name, value) {
+                return bad_request(e);
+            }
+        }
+        for (name, value) in changes {
+            info!("setting {name} to {value} by request");
+            video.set_balance(name, value)?;
+        }
+    }
+    let balance = video.balance().unwrap_or(before);
+    let body = json!({
+        "brightness": balance.brightness,
+        "contrast": balance.contrast,
+        "saturation": balance.saturation,
+        "hue": balance.hue,
+    });
+    Response::builder()
+        .header("Content-Type", "application/json")
+        .body(body.to_string().into())
+        .context("failed to build balance response")
+}
+
+/// Start saving a clip, and respond right away with its file name.
+fn handle_save_clip(config: &Config, frames: Arc<Frames>) -> anyhow::Result<Response<Body>> {
+    let (Some(dir), true) = (&config.clip_dir, frames.can_save_clips()) else {
+        return not_found(CONTROL_SAVE_CLIP_PATH);
+    };
+    let ext = match frames.video().codec() {
+        Codec::Mjpeg => "mjpeg",
+        Codec::H264 => "h264",
+    };
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default();
+    let name = format!("clip-{}.{ext}", now.as_millis());
+    let path = dir.join(&name);
+    let after = config.post_record;
+    info!("saving clip to {path:?}");
+    tokio::spawn(async move {
+        match frames.save_clip(&path, after).await {
+            Ok(()) => info!("saved clip {path:?}"),
+            Err(e) => error!("failed to save clip: {e:#}"),
+        }
+    });
+    Response::builder()
+        .status(202)
+        .header("Content-Type", "application/json")
+        .body(json!({ "file": name }).to_string().into())
+        .context("failed to build save clip response")
+}
+
+/// Rebuild the video pipeline, and respond once it's done.
+async fn handle_restart(restart: Option<RestartSender>) -> anyhow::Result<Response<Body>> {
+    let Some(restart) = restart else {
+        return not_found(CONTROL_RESTART_PATH);
+    };
+    let (tx, rx) = oneshot::channel();
+    restart
+        .send(tx)
+        .await
+        .map_err(|_| anyhow::anyhow!("restart handler is gone"))?;
+    rx.await.context("restart handler is gone")??;
+    Response::builder()
+        .header("Content-Type", "text/plain")
+        .body("pipeline restarted\n".into())
+        .context("failed to build restart response")
 }
 
-fn index(paths: &Paths) -> anyhow::Result<Response<Body>> {
+fn parse_state(s: &str) -> Option<State> {
+    Some(match s.to_ascii_lowercase().as_str() {
+        "null" => State::Null,
+        "ready" => State::Ready,
+        "paused" => State::Paused,
+        "playing" => State::Playing,
+        _ => return None,
+    })
+}
+
+fn index(config: &Config, frames: &Frames, prefix: &str) -> anyhow::Result<Response<Body>> {
+    let codec = frames.video().codec();
+    // As of when the page was loaded; it doesn't update by itself.
+    let streams = match frames.subscribers() {
+        1 => "1 stream".to_owned(),
+        n => format!("{n} streams"),
+    };
+    let status = format!(
+        "<p>Video is {:?}, with {streams} open.\n            ",
+        frames.video().pipeline_state()
+    );
+    let mut links = String::new();
+    let mut link = |href: &str, text: &str| {
+        links += &format!(
+            "<p><a href=\"{}\">{text}</a>\n            ",
+            html_escape(href)
+        );
+    };
+    if let Some(path) = config.paths.stream.first() {
+        link(&format!("{prefix}{path}"), "start stream");
+    }
+    if let Some(path) = config.paths.snapshot.first() {
+        link(&format!("{prefix}{path}"), "get snapshot");
+    }
+    if config.hls_dir.is_some() {
+        link("/hls/playlist.m3u8", "HLS playlist");
+    }
+
+    // Browsers can only show MJPEG in an <img>.
+    let mut viewer = String::new();
+    if codec == Codec::Mjpeg {
+        if let Some(path) = config.paths.stream.first() {
+            viewer += &format!(
+                "<p><button id=\"stream\" data-src=\"{}\">Start stream</button>
+            <p><img id=\"stream-view\" alt=\"\">
+            ",
+                html_escape(&format!("{prefix}{path}"))
+            );
+        }
+        if let Some(path) = config.paths.snapshot.first() {
+            viewer += &format!(
+                "<p><button id=\"snapshot\" data-src=\"{}\">Take snapshot</button>
+            <p><img id=\"snapshot-view\" alt=\"\">
+            ",
+                html_escape(&format!("{prefix}{path}"))
+            );
+        }
+        if !viewer.is_empty() {
+            viewer += INDEX_SCRIPT;
+        }
+    }
+
     Response::builder()
         .header("Content-Type", "text/html")
         .body(
             format!(
                 "<html><body><h1><code>gst-mjpg</code></h1>
-            <p><a href=\"{}\">start stream</a>
-            <p><a href=\"{}\">get snapshot</a>
-            <address>gst-mjpg/v{}",
-                paths.stream,
-                paths.snapshot,
+            {status}{links}{viewer}<address>gst-mjpg/v{}",
                 env!("CARGO_PKG_VERSION")
             )
             .into(),
@@ -102,6 +2042,61 @@ fn index(paths: &Paths) -> anyhow::Result<Response<Body>> {
         .context("failed to build index response")
 }
 
+/// Makes the index page's buttons show the stream and snapshots inline.
+const INDEX_SCRIPT: &str = r#"<script>
+            function button(id, onclick) {
+                const b = document.getElementById(id);
+                if (b) b.onclick = () => onclick(b, document.getElementById(id + "-view"));
+            }
+            button("stream", (b, img) => {
+                if (img.hasAttribute("src")) {
+                    // Removing the source closes the connection.
+                    img.removeAttribute("src");
+                    b.textContent = "Start stream";
+                } else {
+                    img.src = b.dataset.src;
+                    b.textContent = "Stop stream";
+                }
+            });
+            button("snapshot", (b, img) => {
+                // A configured path with a query has to be used exactly as is.
+                const src = b.dataset.src;
+                img.src = src.includes("?") ? src : src + "?t=" + Date.now();
+            });
+            </script>
+            "#;
+
+/// Escape text for use in HTML, including in attribute values.
+fn html_escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => out += "&amp;",
+            '<' => out += "&lt;",
+            '>' => out += "&gt;",
+            '"' => out += "&quot;",
+            '\'' => out += "&#39;",
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// Set headers telling clients and proxies not to cache the response at all.
+fn set_no_cache(hdrs: &mut HeaderMap) {
+    hdrs.insert(
+        "Cache-Control",
+        HeaderValue::from_static(
+            "no-store, no-cache, must-revalidate, pre-check=0, post-check=0, max-age=0",
+        ),
+    );
+    hdrs.insert("Pragma", HeaderValue::from_static("no-cache"));
+    hdrs.insert(
+        "Expires",
+        HeaderValue::from_static("Mon, 3 Jan 2000 12:34:56 GMT"),
+    );
+}
+
 fn server_error(e: anyhow::Error) -> Result<Response<Body>, Infallible> {
     Ok(Response::builder()
         .status(500)
@@ -110,49 +2105,466 @@ fn server_error(e: anyhow::Error) -> Result<Response<Body>, Infallible> {
         .unwrap())
 }
 
-pub async fn serve(port: u16, paths: Arc<Paths>, frames: Arc<Frames>) -> Result<(), hyper::Error> {
-    let make_svc = make_service_fn(move |conn: &AddrStream| {
-        let remote = conn.remote_addr();
-        let paths = paths.clone();
+/// Open the listening socket for the HTTP server.
+pub fn bind(ip: IpAddr, port: u16) -> anyhow::Result<AddrIncoming> {
+    let addr = SocketAddr::from((ip, port));
+    AddrIncoming::bind(&addr).with_context(|| format!("failed to bind to {addr}"))
+}
+
+/// Serve HTTP on the given socket, for the `main` camera and any others in `cameras` (by name,
+/// which their paths start with). New settings sent on `config` take effect for subsequent
+/// requests. This goes on until `shutdown` completes, then stops accepting connections and gives
+/// the ones in progress up to [`SHUTDOWN_GRACE`] to finish before returning.
+pub async fn serve(
+    mut incoming: AddrIncoming,
+    limits: Limits,
+    config: watch::Receiver<Arc<Config>>,
+    main: Camera,
+    cameras: Arc<HashMap<String, Camera>>,
+    shared: Arc<Shared>,
+    shutdown: impl Future<Output = ()>,
+) -> Result<(), hyper::Error> {
+    let Camera { frames, restart } = main;
+    let idle_timeout = limits.idle_timeout;
+    let counter = shared.clone();
+    let draining = shared.clone();
+    let mut limiter = limits.max_accepts_per_sec.map(AcceptLimiter::new);
+    let conns = futures::stream::poll_fn(move |cx| {
+        if let Some(limiter) = &mut limiter {
+            ready!(limiter.poll_ready(cx));
+        }
+        let conn = ready!(Pin::new(&mut incoming).poll_accept(cx));
+        if let Some(limiter) = &mut limiter {
+            limiter.take();
+        }
+        Poll::Ready(conn)
+    })
+    .map(move |conn| {
+        conn.map(|conn| {
+            counter.connections.fetch_add(1, Ordering::Relaxed);
+            let remote = conn.remote_addr();
+            IdleTimeout::new(conn, remote, idle_timeout)
+        })
+    });
+
+    let make_svc = make_service_fn(move |conn: &IdleTimeout<AddrStream>| {
+        let remote = conn.remote;
+        let config = config.clone();
         let frames = frames.clone();
+        let cameras = cameras.clone();
+        let shared = shared.clone();
+        let restart = restart.clone();
         async move {
-            Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
-                info!(
-                    "HTTP request from {} ({:?}): {} {}",
-                    remote,
-                    req.headers()
-                        .get("user-agent")
-                        .unwrap_or(&HeaderValue::from_static("<no useragent>")),
-                    req.method(),
-                    req.uri()
-                );
-                let frames = frames.clone();
-                let paths = paths.clone();
+            Ok::<_, Infallible>(service_fn(move |mut req: Request<Body>| {
+                let config = config.borrow().clone();
+                let client = Client {
+                    ip: config.client_ip(remote.ip(), req.headers()),
+                    peer: remote.ip(),
+                };
+                let entry = if config.access_log_format.is_some() {
+                    Some(AccessLogEntry {
+                        config: config.clone(),
+                        remote: client.ip,
+                        method: req.method().clone(),
+                        path: req.uri().to_string(),
+                        user_agent: req
+                            .headers()
+                            .get("user-agent")
+                            .map(|ua| String::from_utf8_lossy(ua.as_bytes()).into_owned()),
+                        start: Instant::now(),
+                    })
+                } else {
+                    info!(
+                        "HTTP request from {} ({:?}): {} {}",
+                        client.ip,
+                        req.headers()
+                            .get("user-agent")
+                            .unwrap_or(&HeaderValue::from_static("<no useragent>")),
+                        req.method(),
+                        req.uri()
+                    );
+                    None
+                };
+                // After logging, so the log shows the path as it was asked for.
+                let (config, frames, restart, prefix) = match route_camera(&cameras, &mut req) {
+                    // HLS is only for the main camera.
+                    Some((name, camera)) => (
+                        Arc::new(Config {
+                            hls_dir: None,
+                            ..(*config).clone()
+                        }),
+                        camera.frames.clone(),
+                        camera.restart.clone(),
+                        format!("/{name}"),
+                    ),
+                    None => (config, frames.clone(), restart.clone(), String::new()),
+                };
+                let shared = shared.clone();
                 async move {
-                    let mut resp = handle_request(req, remote, paths, frames)
-                        .await
-                        .or_else(server_error)
-                        .unwrap();
+                    let mut resp =
+                        handle_request(req, client, config, frames, shared, restart, &prefix)
+                            .await
+                            .or_else(server_error)
+                            .unwrap();
                     let hdrs = resp.headers_mut();
                     hdrs.insert(
                         "Server",
                         HeaderValue::from_str(&format!("gst-mjpg/v{}", env!("CARGO_PKG_VERSION")))
                             .unwrap(),
                     );
-                    hdrs.insert("Cache-Control", HeaderValue::from_static("no-store, no-cache, must-revalidate, pre-check=0, post-check=0, max-age=0"));
-                    hdrs.insert("Pragma", HeaderValue::from_static("no-cache"));
-                    hdrs.insert(
-                        "Expires",
-                        HeaderValue::from_static("Mon, 3 Jan 2000 12:34:56 GMT"),
-                    );
-                    Ok::<_, Infallible>(resp)
+                    // Anything that can be cached sets its own policy.
+                    if !hdrs.contains_key("Cache-Control") {
+                        set_no_cache(hdrs);
+                    }
+                    let status = resp.status().as_u16();
+                    Ok::<_, Infallible>(resp.map(|inner| LoggedBody {
+                        inner,
+                        status,
+                        bytes: 0,
+                        entry,
+                    }))
                 }
             }))
         }
     });
 
-    let addr = SocketAddr::from((Ipv4Addr::UNSPECIFIED, port));
-    Server::bind(&addr).serve(make_svc).await?;
+    let (stopping_tx, stopping_rx) = oneshot::channel();
+    let server = Server::builder(accept::from_stream(conns))
+        .http1_max_buf_size(limits.max_header_bytes)
+        .http1_header_read_timeout(limits.header_read_timeout)
+        .serve(make_svc)
+        .with_graceful_shutdown(async move {
+            shutdown.await;
+            draining.draining.store(true, Ordering::SeqCst);
+            let _ = stopping_tx.send(());
+        });
+    tokio::pin!(server);
+
+    tokio::select! {
+        result = &mut server => result?,
+        Ok(()) = stopping_rx => {
+            // Streams never finish on their own, so don't wait for them forever.
+            match tokio::time::timeout(SHUTDOWN_GRACE, server).await {
+                Ok(result) => result?,
+                Err(_) => {
+                    warn!("connections still open {SHUTDOWN_GRACE:?} after shutting down; closing them")
+                }
+            }
+        }
+    }
 
     Ok(())
 }
+
+/// A token bucket for accepting connections: on average `rate` a second, in bursts of up to
+/// `rate`. When it's empty, accepting pauses and new connections wait in the listen backlog.
+struct AcceptLimiter {
+    rate: f64,
+    tokens: f64,
+    last: Instant,
+    wait: Pin<Box<Sleep>>,
+    throttled: bool,
+}
+
+impl AcceptLimiter {
+    fn new(rate: u32) -> Self {
+        let rate = f64::from(rate);
+        Self {
+            rate,
+            tokens: rate,
+            last: Instant::now(),
+            wait: Box::pin(tokio::time::sleep(Duration::ZERO)),
+            throttled: false,
+        }
+    }
+
+    /// Ready once there's a token for another connection.
+    fn poll_ready(&mut self, cx: &mut TaskContext<'_>) -> Poll<()> {
+        let now = Instant::now();
+        let refill = now.duration_since(self.last).as_secs_f64() * self.rate;
+        self.tokens = (self.tokens + refill).min(self.rate);
+        self.last = now;
+        if self.tokens >= 1. {
+            if self.throttled {
+                self.throttled = false;
+                info!("accepting connections again");
+            }
+            return Poll::Ready(());
+        }
+        if !self.throttled {
+            self.throttled = true;
+            warn!(
+                "more than {} new connections a second; pausing accepting them",
+                self.rate
+            );
+        }
+        let wait = Duration::from_secs_f64((1. - self.tokens) / self.rate);
+        self.wait.as_mut().reset(now + wait);
+        if self.wait.as_mut().poll(cx).is_ready() {
+            cx.waker().wake_by_ref();
+        }
+        Poll::Pending
+    }
+
+    fn take(&mut self) {
+        self.tokens -= 1.;
+    }
+}
+
+/// What's known about a request when it arrives, for its access log line.
+struct AccessLogEntry {
+    config: Arc<Config>,
+    remote: IpAddr,
+    method: Method,
+    path: String,
+    user_agent: Option<String>,
+    start: Instant,
+}
+
+/// A response body that writes the access log line once it's done, successfully or not, so the
+/// line can say how much was sent and how long it took. For streams, that's when the client goes
+/// away.
+struct LoggedBody {
+    inner: Body,
+    status: u16,
+    bytes: u64,
+    entry: Option<AccessLogEntry>,
+}
+
+impl HttpBody for LoggedBody {
+    type Data = Bytes;
+    type Error = hyper::Error;
+
+    fn poll_data(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+    ) -> Poll<Option<Result<Bytes, hyper::Error>>> {
+        let result = ready!(Pin::new(&mut self.inner).poll_data(cx));
+        if let Some(Ok(data)) = &result {
+            self.bytes += data.len() as u64;
+        }
+        Poll::Ready(result)
+    }
+
+    fn poll_trailers(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+    ) -> Poll<Result<Option<HeaderMap>, hyper::Error>> {
+        Pin::new(&mut self.inner).poll_trailers(cx)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> hyper::body::SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+impl Drop for LoggedBody {
+    fn drop(&mut self) {
+        if let Some(entry) = &self.entry {
+            if let Some(format) = &entry.config.access_log_format {
+                info!("{}", format.format(entry, self.status, self.bytes));
+            }
+        }
+    }
+}
+
+/// A connection that's closed if nothing is read from or written to it for a while.
+///
+/// This also logs failed writes, which hyper otherwise keeps to itself. A failed write ends the
+/// connection, dropping whatever stream was being sent on it, so the video can stop right away
+/// if that was the last one.
+struct IdleTimeout<S> {
+    inner: S,
+    remote: SocketAddr,
+    timeout: Duration,
+    deadline: Pin<Box<Sleep>>,
+}
+
+impl<S> IdleTimeout<S> {
+    fn new(inner: S, remote: SocketAddr, timeout: Duration) -> Self {
+        Self {
+            inner,
+            remote,
+            timeout,
+            deadline: Box::pin(tokio::time::sleep(timeout)),
+        }
+    }
+
+    fn log_write_error<T>(&self, poll: &Poll<std::io::Result<T>>) {
+        if let Poll::Ready(Err(e)) = poll {
+            debug!("failed to write to client {}: {e}", self.remote);
+        }
+    }
+
+    /// Handle the result of polling the inner connection: push the deadline back if anything
+    /// happened, or else fail if the deadline has passed.
+    fn check<T>(
+        &mut self,
+        cx: &mut TaskContext<'_>,
+        poll: Poll<std::io::Result<T>>,
+    ) -> Poll<std::io::Result<T>> {
+        if poll.is_ready() {
+            self.deadline.as_mut().reset(Instant::now() + self.timeout);
+            return poll;
+        }
+        if self.deadline.as_mut().poll(cx).is_ready() {
+            return Poll::Ready(Err(std::io::Error::new(
+                std::io::ErrorKind::TimedOut,
+                "connection idle for too long",
+            )));
+        }
+        Poll::Pending
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for IdleTimeout<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let poll = Pin::new(&mut self.inner).poll_read(cx, buf);
+        self.check(cx, poll)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for IdleTimeout<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let poll = Pin::new(&mut self.inner).poll_write(cx, buf);
+        self.log_write_error(&poll);
+        self.check(cx, poll)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<std::io::Result<()>> {
+        let poll = Pin::new(&mut self.inner).poll_flush(cx);
+        self.log_write_error(&poll);
+        poll
+    }
+
+    fn poll_shutdown(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn timestamps_are_zero_padded() {
+        assert_eq!(format_timestamp(Duration::new(1, 5_000)), "1.000005");
+        assert_eq!(format_timestamp(Duration::new(1, 42_000)), "1.000042");
+        assert_eq!(
+            format_timestamp(Duration::new(12, 999_999_999)),
+            "12.999999"
+        );
+        assert_eq!(format_timestamp(Duration::ZERO), "0.000000");
+    }
+
+    #[test]
+    fn streams_past_max_clients_are_refused() {
+        let shared = Shared::default();
+        let mut slots = (0..3)
+            .map(|_| shared.streams.acquire(Some(3)))
+            .collect::<Option<Vec<_>>>()
+            .expect("refused a stream under the limit");
+        assert!(shared.streams.acquire(Some(3)).is_none());
+
+        let resp = at_capacity().unwrap();
+        assert_eq!(resp.status(), 503);
+        assert_eq!(resp.headers()["Retry-After"], "10");
+
+        // Once one ends, there's room again.
+        slots.pop();
+        assert!(shared.streams.acquire(Some(3)).is_some());
+        assert!(shared.streams.acquire(None).is_some());
+    }
+
+    #[test]
+    fn slow_client_policy_from_query() {
+        let drop = SlowClient::Drop;
+        let disconnect = SlowClient::Disconnect { lags: 5 };
+        assert_eq!(drop.with_query(""), Ok(SlowClient::Drop));
+        assert_eq!(disconnect.with_query("fps=5"), Ok(disconnect));
+        assert_eq!(
+            drop.with_query("slow-client=disconnect"),
+            Ok(SlowClient::Disconnect {
+                lags: DEFAULT_SLOW_CLIENT_LAGS
+            })
+        );
+        assert_eq!(
+            drop.with_query("slow-client=disconnect&slow-client-lags=2"),
+            Ok(SlowClient::Disconnect { lags: 2 })
+        );
+        assert_eq!(
+            disconnect.with_query("slow-client-lags=1"),
+            Ok(SlowClient::Disconnect { lags: 1 })
+        );
+        assert_eq!(
+            disconnect.with_query("slow-client=drop"),
+            Ok(SlowClient::Drop)
+        );
+        assert!(drop.with_query("slow-client=wait").is_err());
+        assert!(drop
+            .with_query("slow-client=disconnect&slow-client-lags=0")
+            .is_err());
+    }
+
+    #[test]
+    fn fixed_boundary_is_used_as_given() {
+        let fixed = "myboundary".parse().unwrap();
+        assert_eq!(stream_boundary(&Some(fixed)), "myboundary");
+    }
+
+    #[test]
+    fn random_boundaries_are_128_bits_of_hex() {
+        let a = stream_boundary(&None);
+        let b = stream_boundary(&None);
+        for bdry in [&a, &b] {
+            assert_eq!(bdry.len(), 32, "{bdry:?}");
+            assert!(bdry.chars().all(|c| c.is_ascii_hexdigit()), "{bdry:?}");
+        }
+        assert_ne!(a, b);
+    }
+
+    #[test]
+    fn cors_covers_stream_thumb_snapshot_and_status() {
+        let paths = Paths {
+            stream: vec!["/stream".to_owned(), "/?action=stream".to_owned()],
+            snapshot: vec!["/snapshot".to_owned()],
+            status: vec!["/status".to_owned()],
+            devices: vec!["/devices".to_owned()],
+        };
+        let gets = |uri: &str| gets_cors(&paths, &uri.parse().unwrap());
+        for uri in [
+            "/stream",
+            "/?action=stream",
+            "/thumb",
+            "/snapshot",
+            "/status",
+        ] {
+            assert!(gets(uri), "{uri}");
+        }
+        for uri in ["/devices", "/metrics", "/control/restart", "/nope"] {
+            assert!(!gets(uri), "{uri}");
+        }
+
+        let origin = HeaderValue::from_static("https://example.com");
+        let resp = with_cors(Some(&origin), Response::new(Body::empty()));
+        assert_eq!(resp.headers()["Access-Control-Allow-Origin"], origin);
+        assert_eq!(resp.headers()["Access-Control-Allow-Methods"], CORS_METHODS);
+        let resp = with_cors(None, Response::new(Body::empty()));
+        assert!(!resp.headers().contains_key("Access-Control-Allow-Origin"));
+    }
+}
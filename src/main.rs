@@ -1,24 +1,44 @@
 #[macro_use]
 extern crate log;
 
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::io::Write;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 use anyhow::{bail, Context};
 use clap::Parser;
+use futures::StreamExt;
 use gstreamer::prelude::GstObjectExt;
 use gstreamer::MessageView;
-use video::VideoSource;
+use hyper::http::HeaderValue;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::{mpsc, oneshot, watch, Notify};
+use video::{ChromaSubsampling, Codec, Corner, Flip, RecordOverflow, Rotation, VideoSource};
 
+pub mod events;
+pub mod exif;
 pub mod frames;
 pub mod http;
+pub mod privs;
+pub mod raw;
+pub mod stats;
+pub mod tcp;
 pub mod video;
 
+use crate::events::{Event, Events};
 use crate::frames::Frames;
-use crate::http::Paths;
+use crate::http::{
+    AccessLogFormat, Boundary, Camera, Cidr, ClientQuirks, Config, Credentials, Limits,
+    MultipartType, Paths, RestartSender, Shared, SlowClient,
+};
 use crate::video::Video;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 struct Size {
     width: u32,
     height: u32,
@@ -37,25 +57,195 @@ impl FromStr for Size {
     }
 }
 
-#[derive(Debug, Parser)]
+/// A shape for the video, written like `16:9`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Aspect {
+    width: u32,
+    height: u32,
+}
+
+impl FromStr for Aspect {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some((ws, hs)) = s.split_once(':') else {
+            bail!("aspect ratio must be WIDTH:HEIGHT, like 16:9");
+        };
+        let width = ws.parse::<u32>().context("invalid width")?;
+        let height = hs.parse::<u32>().context("invalid height")?;
+        if width == 0 || height == 0 {
+            bail!("aspect ratio can't be zero");
+        }
+        Ok(Self { width, height })
+    }
+}
+
+/// What to do when the video comes to an end.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum OnEos {
+    /// Rebuild the pipeline, as with `POST /control/restart-pipeline`.
+    Restart,
+    /// Seek back to the beginning.
+    Loop,
+    /// Stop serving and exit.
+    Exit,
+}
+
+impl FromStr for OnEos {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "restart" => OnEos::Restart,
+            "loop" => OnEos::Loop,
+            "exit" => OnEos::Exit,
+            _ => bail!("must be one of restart, loop, or exit"),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Parser)]
+#[command(args_override_self = true)]
 struct Args {
-    /// WIDTHxHEIGHT. If unspecified, use whatever the camera's native resolution is.
+    /// Read options from this TOML file. Keys are the long option names (e.g.
+    /// `stream-path = "/video"`), and options given on the command line take precedence.
+    ///
+    /// On SIGHUP the file is read again. Changes to the HTTP settings, --quality, and
+    /// --caption-file are applied immediately, and other video changes rebuild the pipeline.
+    /// Changes to the listening ports and connection limits need a restart.
+    #[arg(long)]
+    config: Option<PathBuf>,
+
+    /// WIDTHxHEIGHT. If unspecified, use whatever the camera's native resolution is. If the camera
+    /// can't produce this size, the video is scaled to it.
     #[arg(long)]
     size: Option<Size>,
 
-    /// Video device to open.
+    /// Don't scale the video to match --size; require the camera to produce that size natively.
+    #[arg(long)]
+    no_scale: bool,
+
+    /// Use the largest size the camera supports, instead of whatever it defaults to. Cameras
+    /// often have lower frame rates at their largest sizes; run with -v to see what it supports.
+    #[arg(long, conflicts_with = "size")]
+    max_resolution: bool,
+
+    /// Add borders to make the video this shape, like `16:9`, instead of stretching it, for
+    /// displays that expect a fixed shape. The video keeps its --size (or the camera's largest,
+    /// with --max-resolution) and grows to fit the borders, so one of those is needed.
+    #[arg(long)]
+    aspect: Option<Aspect>,
+
+    /// Color of the borders added by --aspect.
+    #[arg(
+        long,
+        default_value = "black",
+        value_parser = ["black", "green", "blue", "red", "yellow", "white"],
+    )]
+    pad_color: String,
+
+    /// When streams keep falling behind for several seconds, rebuild the pipeline at the
+    /// camera's next smaller size, and go back up after a minute without any falling behind.
+    /// Needs --size or --max-resolution to start from. Each change briefly interrupts the video.
+    #[arg(long)]
+    adaptive_resolution: bool,
+
+    /// Video device to open: a path, the name of a link in /dev/v4l/by-id or /dev/v4l/by-path
+    /// (which, unlike /dev/videoN, don't change between reboots), or a camera's name or serial
+    /// number. Names are looked up again whenever the pipeline is rebuilt.
     #[arg(long, default_value = "/dev/video0")]
     device: String,
 
-    /// TCP port to listen on for HTTP server.
+    /// Also serve another V4L2 camera, as NAME=DEVICE (with DEVICE as for --device). Its paths
+    /// are the main camera's under /NAME, like /NAME/stream and /NAME/snapshot, and it starts
+    /// and stops on its own. It uses the same settings as the main camera, except HLS,
+    /// --record, and --preview, which are only for the main one. Can be given more than once.
+    #[arg(long, value_parser = parse_camera)]
+    camera: Vec<(String, String)>,
+
+    /// Don't take an exclusive lock on the --device. Normally it's locked at startup, and
+    /// starting fails if another instance already has it; use this for setups that share a
+    /// camera on purpose.
+    #[arg(long)]
+    share_device: bool,
+
+    /// I/O mode for v4l2src to use with the device (e.g. `mmap`, `userptr`, `dmabuf`), for
+    /// cameras that don't work with the default.
+    #[arg(long, conflicts_with_all = ["test_video", "test_clock", "file", "libcamera"])]
+    v4l2_io_mode: Option<String>,
+
+    /// Set any other v4l2src property, as `NAME=VALUE` (see `gst-inspect-1.0 v4l2src`). Can be
+    /// given more than once.
+    #[arg(
+        long,
+        value_parser = parse_property,
+        conflicts_with_all = ["test_video", "test_clock", "file", "libcamera"],
+    )]
+    v4l2_prop: Vec<(String, String)>,
+
+    /// TCP port to listen on for HTTP server. Can be given more than once, to serve the same
+    /// video on several ports.
     #[arg(long, default_value = "5001")]
-    port: u16,
+    port: Vec<u16>,
+
+    /// Address to listen on, for the HTTP server and --raw-frame-port: an IPv4 or IPv6 address,
+    /// like 127.0.0.1 to only be reachable from this machine. Listens on all IPv4 interfaces by
+    /// default; use `::` for all IPv6 (and, on most systems, IPv4) ones.
+    #[arg(long, default_value = "0.0.0.0")]
+    bind: IpAddr,
+
+    /// Also send frames on this TCP port without HTTP: each one as its length (4 bytes,
+    /// big-endian) followed by its data, one after another, for as long as the client stays
+    /// connected. Each client counts as a stream, for starting and stopping the video.
+    #[arg(long)]
+    raw_frame_port: Option<u16>,
+
+    /// If some of the ports can't be bound, carry on with the rest instead of exiting.
+    #[arg(long)]
+    ignore_bind_errors: bool,
+
+    /// Advertise the server on the local network via mDNS (Bonjour/Zeroconf) under this name.
+    #[arg(long)]
+    mdns_name: Option<String>,
+
+    /// User to run as, after opening the video device and binding the port. This allows binding
+    /// to a privileged port (like 80) without running as root the whole time.
+    #[arg(long)]
+    user: Option<String>,
+
+    /// Group to run as, after opening the video device and binding the port. Defaults to the
+    /// primary group of --user.
+    #[arg(long)]
+    group: Option<String>,
+
+    /// Largest HTTP request header size to accept, in bytes. Must be at least 8192.
+    #[arg(long, default_value = "16384", value_parser = clap::value_parser!(u32).range(8192..))]
+    max_header_bytes: u32,
+
+    /// Seconds a client has to send its HTTP request headers before being disconnected.
+    #[arg(long, default_value = "30")]
+    header_read_timeout: u64,
+
+    /// Seconds an HTTP connection can go without sending or receiving anything before it's
+    /// closed. This cleans up keep-alive connections left open by idle clients.
+    #[arg(long, default_value = "60", value_parser = clap::value_parser!(u64).range(1..))]
+    http_idle_timeout: u64,
+
+    /// Accept at most this many new connections a second on each port (on average, in bursts of
+    /// up to this many). Past that, accepting pauses and new connections wait in the kernel's
+    /// backlog, so a flood of them can't use up file descriptors.
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    max_accepts_per_sec: Option<u32>,
 
     /// Verbose output. Specify multiple times to increase level.
     /// 0x = Error/Warning, 1x = Info, 2x = Debug, 3x = Trace.
     #[arg(short, long, action = clap::ArgAction::Count)]
     verbose: u8,
 
+    /// Also show log messages from this module of a dependency, like `hyper` (or a part of it,
+    /// like `hyper::proto`), at the same level as this program's. Can be given more than once.
+    /// Most dependencies log nothing useful, so by default only this program's messages show.
+    #[arg(long)]
+    log_module: Vec<String>,
+
     /// Stream from a fake video source instead of opening a real video device.
     ///
     /// Optional argument is the pattern to show. See `gst-inspect-1.0 testvideosrc` (property
@@ -63,68 +253,1671 @@ struct Args {
     #[arg(long, default_missing_value = "smpte", num_args(0..=1))]
     test_video: Option<String>,
 
-    /// URL path to use for the stream.
+    /// Stream a fake video source showing the time in milliseconds since it started, for
+    /// measuring latency: photograph the stream next to a local view of it (like `--preview`)
+    /// and compare the times.
+    #[arg(long, conflicts_with = "test_video")]
+    test_clock: bool,
+
+    /// Stream from a video file instead of opening a real video device.
+    #[arg(long, conflicts_with_all = ["test_video", "test_clock"])]
+    file: Option<String>,
+
+    /// Stream from a camera handled by libcamera (such as a Raspberry Pi CSI camera) instead of
+    /// a V4L2 device.
+    ///
+    /// Optional argument is the name of the camera to use, as listed by `libcamera-hello
+    /// --list-cameras`; by default the first one is used.
+    #[arg(long, num_args(0..=1), conflicts_with_all = ["test_video", "test_clock", "file"])]
+    libcamera: Option<Option<String>>,
+
+    /// Deliver frames as fast as they're produced, instead of in step with the pipeline clock.
+    /// With --file, this processes the file as fast as possible instead of in real time.
+    #[arg(long)]
+    no_sync: bool,
+
+    /// With --file, start over from the beginning when the end of the file is reached. This is
+    /// the same as `--on-eos loop`.
+    #[arg(long = "loop", requires = "file", conflicts_with = "on_eos")]
+    loop_file: bool,
+
+    /// What to do when the video ends, e.g. at the end of a file or when a device goes away:
+    /// `restart` rebuilds the pipeline (re-reading the config file), `loop` seeks back to the
+    /// start (for files), and `exit` stops the server. By default it's only logged, and
+    /// streaming stops.
+    #[arg(long)]
+    on_eos: Option<OnEos>,
+
+    /// After a pipeline error (like the camera dropping off the USB bus), or the video ending
+    /// without --on-eos, rebuild the pipeline automatically, up to this many times in a row. The
+    /// tries are --reconnect-initial-ms apart at first, twice as long each time after, up to
+    /// --reconnect-max-ms. Once the pipeline has gone a minute without trouble, the count starts
+    /// over; after the last try fails, the server exits with an error.
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    auto_restart: Option<u32>,
+
+    /// Like --auto-restart, but 0 keeps trying forever.
+    #[arg(long, conflicts_with = "auto_restart")]
+    reconnect_max_attempts: Option<u32>,
+
+    /// How long automatic restarts wait before the first try, in milliseconds.
+    #[arg(long, default_value_t = 1000)]
+    reconnect_initial_ms: u64,
+
+    /// The longest automatic restarts wait between tries, in milliseconds.
+    #[arg(long, default_value_t = 30_000)]
+    reconnect_max_ms: u64,
+
+    /// URL path to use for the stream. Can be given more than once, for clients that expect
+    /// different URLs (like `/stream.mjpg` or `/?action=stream`); the first is the one linked
+    /// from the index page.
     #[arg(long, default_value = "/stream")]
-    stream_path: String,
+    stream_path: Vec<String>,
 
-    /// URL path to use for taking snapshots (single frames sent as JPEG).
+    /// URL path to use for taking snapshots (single frames sent as JPEG). Can be given more than
+    /// once, like --stream-path.
     #[arg(long, default_value = "/snapshot")]
-    snapshot_path: String,
+    snapshot_path: Vec<String>,
+
+    /// URL path for a JSON summary of the server's status (streams open, whether the video is
+    /// playing, its size and frame rate, and the version), for monitoring. Can be given more than
+    /// once, like --stream-path.
+    #[arg(long, default_value = "/status")]
+    status_path: Vec<String>,
+
+    /// URL path for a JSON list of the cameras attached to the system and the formats each
+    /// supports, for finding which to use with --device. Can be given more than once, like
+    /// --stream-path.
+    #[arg(long, default_value = "/devices")]
+    devices_path: Vec<String>,
+
+    /// Disable the stream, so only snapshots can be taken.
+    #[arg(long)]
+    no_stream: bool,
+
+    /// Disable snapshots.
+    #[arg(long)]
+    no_snapshot: bool,
+
+    /// How many seconds to wait for a frame when taking a snapshot. This has to allow for the
+    /// camera starting up if nothing is streaming.
+    #[arg(long, default_value = "5")]
+    snapshot_timeout: u64,
+
+    /// Image file to serve for snapshots when the video isn't already running, instead of
+    /// starting the camera for each one. Once something is streaming, snapshots are live again.
+    /// The Content-Type comes from the extension (.jpg, .png, .gif, or .webp).
+    #[arg(long)]
+    placeholder: Option<PathBuf>,
+
+    /// How long, in milliseconds, clients may cache snapshots for. This is sent in whole seconds,
+    /// so less than 1000 means snapshots aren't cached.
+    #[arg(long, default_value = "0")]
+    snapshot_cache_ms: u64,
+
+    /// How many times to retry getting a snapshot frame if the first attempt fails or times out.
+    #[arg(long, default_value = "2")]
+    snapshot_retries: u32,
+
+    /// Most snapshots per second to actually capture. Requests that come faster than this get the
+    /// last one again, which saves work when something polls for snapshots very often.
+    #[arg(long, value_parser = parse_fps)]
+    snapshot_max_fps: Option<f64>,
+
+    /// Add EXIF metadata to JPEG snapshots, recording when the frame was captured (in local
+    /// time), so the time is kept when the image is saved.
+    #[arg(long)]
+    snapshot_exif: bool,
+
+    /// With --snapshot-exif, also put this text in the EXIF image description.
+    #[arg(long, requires = "snapshot_exif", value_parser = parse_exif_comment)]
+    snapshot_exif_comment: Option<String>,
+
+    /// Put this text (e.g. the camera's name or location) in a comment segment of every JPEG
+    /// snapshot. Requests can give their own with `?comment=`. Unlike EXIF, this needs no other
+    /// metadata, and it's kept when the image is saved. Streams are left alone.
+    #[arg(long, value_parser = parse_snapshot_comment)]
+    snapshot_comment: Option<String>,
 
     /// Description (in gst-launch syntax) of additional filter(s) to insert between the camera and
     /// jpeg encoding.
     ///
-    /// Hint: try something like `videoflip method=rotate-180`
+    /// Hint: try something like `videocrop top=20 bottom=20`
     #[arg(long)]
     filter: Option<String>,
+
+    /// Rotate the video clockwise by this many degrees (0, 90, 180, or 270) in the pipeline, for
+    /// a camera mounted sideways or upside down. This applies to everything: streams, snapshots,
+    /// HLS, and thumbnails. --size is the size after rotating.
+    #[arg(long, visible_alias = "rotate", default_value = "0")]
+    rotate_stream: Rotation,
+
+    /// Mirror the video ("horizontal", left to right, or "vertical", top to bottom) in the
+    /// pipeline, for a camera that sees through a mirror or is mounted facing the wrong way.
+    /// This happens before --filter, and before --rotate-stream.
+    #[arg(long, default_value = "none")]
+    flip: Flip,
+
+    /// Orientation for JPEG snapshots, as degrees clockwise (0, 90, 180, or 270) from how the
+    /// camera sees things, if it should differ from --rotate-stream. Snapshots are re-encoded to
+    /// get there, which costs CPU time for each one. Defaults to the same as --rotate-stream.
+    #[arg(long)]
+    rotate_snapshot: Option<Rotation>,
+
+    /// Encode at most this many frames per second. Extra frames from the camera are dropped
+    /// right after --filter and rotation, before scaling to --size and encoding, which saves the
+    /// CPU time of encoding them (unlike --decimate and --stream-max-fps). Applies to everything,
+    /// HLS included.
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    framerate: Option<u32>,
+
+    /// Output grayscale video.
+    #[arg(long)]
+    grayscale: bool,
+
+    /// Automatically lower the encoder quality when streams fall behind and drop frames, and
+    /// raise it again when they keep up. Only works with encoders that have a "quality" property,
+    /// like jpegenc.
+    #[arg(long)]
+    adaptive_quality: bool,
+
+    /// Keep encoded frames under this many bytes, by lowering the encoder quality whenever one
+    /// is bigger (down to --min-quality), and slowly raising it again (up to --max-quality) while
+    /// frames are well under. Frames aren't re-encoded, so one that's too big is still sent, and
+    /// the ones after it are smaller. Only works with encoders that have a "quality" property.
+    #[arg(long, conflicts_with = "adaptive_quality")]
+    max_frame_bytes: Option<usize>,
+
+    /// Send frames to streams at evenly spaced times, at the recent average frame rate, instead
+    /// of as soon as they're encoded. For cameras that deliver frames unevenly, this makes motion
+    /// look smoother, at the cost of up to two frame intervals of extra latency.
+    #[arg(long)]
+    smooth_output: bool,
+
+    /// Send on JPEG frames even if they look broken (too short, or missing the start or end
+    /// markers). Normally those are dropped and counted. Useful with an --encode-pipeline that
+    /// produces unusual but valid JPEGs.
+    #[arg(long)]
+    no_frame_check: bool,
+
+    /// Keep the video running for this many seconds after the last stream ends, so a client that
+    /// reconnects right away (like a browser reloading the page) doesn't have to wait for the
+    /// camera to start up again. 0 stops it right away.
+    #[arg(long, default_value = "0")]
+    idle_timeout: u64,
+
+    /// Lowest quality --adaptive-quality and --max-frame-bytes go to.
+    #[arg(long, default_value = "30", value_parser = clap::value_parser!(i32).range(0..=100))]
+    min_quality: i32,
+
+    /// Highest quality --adaptive-quality and --max-frame-bytes go to.
+    #[arg(long, default_value = "85", value_parser = clap::value_parser!(i32).range(0..=100))]
+    max_quality: i32,
+
+    /// Overlay the text in this file on the video. The file is watched, and the text updated
+    /// shortly after it changes.
+    #[arg(long)]
+    caption_file: Option<PathBuf>,
+
+    /// Draw the local date and time on the video, encoded into every frame.
+    ///
+    /// Optional argument is the corner to put it in: "top-left", "top-right", "bottom-left", or
+    /// "bottom-right" (the default).
+    #[arg(long, default_missing_value = "bottom-right", num_args(0..=1))]
+    timestamp_overlay: Option<Corner>,
+
+    /// Format of the --timestamp-overlay text, with strftime-style % codes.
+    #[arg(long, default_value = "%Y-%m-%d %H:%M:%S")]
+    timestamp_format: String,
+
+    /// Video encoding to serve: "mjpeg" or "h264". Only MJPEG is viewable in a browser.
+    #[arg(long, default_value = "mjpeg")]
+    codec: Codec,
+
+    /// GStreamer encoder element to use, if not the default for the codec (jpegenc for mjpeg,
+    /// x264enc for h264). For example, `v4l2jpegenc` or `vaapijpegenc` for hardware JPEG
+    /// encoding, or `v4l2h264enc` for hardware H.264. If it isn't available, the default is used
+    /// instead, with a warning; the encoder actually in use is shown in /info.
+    #[arg(long)]
+    encoder: Option<String>,
+
+    /// Encode with these elements, in gst-launch syntax, instead of a single encoder element:
+    /// e.g. `"videoconvert ! v4l2jpegenc extra-controls=c,compression_quality=80"`. The first
+    /// element gets raw video, and the last has to output the codec's format (`image/jpeg` for
+    /// mjpeg), which is checked before starting.
+    #[arg(long, conflicts_with = "encoder")]
+    encode_pipeline: Option<String>,
+
+    /// DCT method for jpegenc: `ifast` is quickest but slightly less accurate, `islow` (the
+    /// default) is accurate, and `float` is about as accurate and any faster only on CPUs with
+    /// fast floating point. On a slow CPU, `ifast` can noticeably lower the encoding load.
+    #[arg(long, value_parser = ["ifast", "islow", "float"])]
+    jpeg_idct: Option<String>,
+
+    /// Encoder quality, from 0 to 100 (jpegenc's default is 85). Lower quality makes smaller
+    /// frames, for slow links. Only works with encoders that have a "quality" property, like
+    /// jpegenc. With --adaptive-quality or --max-frame-bytes, this is where it starts.
+    #[arg(long, value_parser = clap::value_parser!(u8).range(0..=100))]
+    quality: Option<u8>,
+
+    /// When stopping the video, first send an end-of-stream through the pipeline and wait up to
+    /// this many milliseconds for it to get all the way through, so sinks can finish writing
+    /// (like the last HLS segment). 0 stops right away, cutting off whatever was in progress.
+    #[arg(long, default_value = "0")]
+    eos_timeout_ms: u64,
+
+    /// Give the pipeline this fixed latency, in milliseconds, instead of what its elements
+    /// report. For lining up several instances, give them all the same latency (at least as much
+    /// as the slowest one needs).
+    #[arg(long)]
+    latency_ms: Option<u64>,
+
+    /// Run the pipeline on the system's wall clock rather than GStreamer's default monotonic one,
+    /// so instances on different machines synced with NTP share a time base.
+    #[arg(long)]
+    system_clock: bool,
+
+    /// Put a queue of this many frames between capturing and encoding, so the encoder runs in
+    /// its own thread while the camera is read in another. On a multicore board this keeps a
+    /// slow encode from holding up capture. When it fills up, capture waits.
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    encode_queue: Option<u32>,
+
+    /// Put a queue of this many frames between the encoder and handing frames to streams, so
+    /// the encoder can go on to the next frame right away.
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    sink_queue: Option<u32>,
+
+    /// Chroma subsampling to encode with: 420 (color at half the resolution both ways, the
+    /// smallest), 422 (half horizontally), or 444 (full color resolution, the biggest). By default
+    /// the encoder gets whatever format the camera produces.
+    #[arg(long, conflicts_with = "grayscale")]
+    chroma_subsampling: Option<ChromaSubsampling>,
+
+    /// Also serve the video as HLS, under /hls/playlist.m3u8, for playback in a browser's native
+    /// <video> element. This needs x264enc (from gst-plugins-ugly) and hlssink2 (from
+    /// gst-plugins-good), and keeps the video running even when no one is watching.
+    #[arg(long)]
+    hls: bool,
+
+    /// Also save the encoded video to this file, as AVI, while serving it. This keeps the video
+    /// running even when no one is watching, and the file is started over whenever the video
+    /// is.
+    #[arg(long)]
+    record: Option<PathBuf>,
+
+    /// What --record does when the disk can't keep up: "drop" leaves frames out of the recording,
+    /// so streams aren't held up (they're counted in /metrics), and "block" waits for the disk,
+    /// so the recording has every frame but every stream stalls along with it.
+    #[arg(long, default_value = "drop")]
+    record_overflow: RecordOverflow,
+
+    /// Multipart type to use for the stream: "x-mixed-replace" (what browsers expect) or "mixed"
+    /// (for clients that only understand standard MIME multipart).
+    #[arg(long, default_value = "x-mixed-replace")]
+    multipart_type: MultipartType,
+
+    /// What to do about clients that can't keep up with the stream: "drop" the frames they miss
+    /// and carry on, or "disconnect" them after they fall behind --slow-client-lags times in a
+    /// row, to save the server's resources. Streams can pick for themselves with
+    /// `?slow-client=` and `?slow-client-lags=`.
+    #[arg(long, default_value = "drop", value_parser = ["drop", "disconnect"])]
+    slow_client: String,
+
+    /// With `--slow-client disconnect`, how many times in a row a stream can fall behind before
+    /// it's ended.
+    #[arg(
+        long,
+        default_value_t = http::DEFAULT_SLOW_CLIENT_LAGS,
+        value_parser = clap::value_parser!(u32).range(1..),
+    )]
+    slow_client_lags: u32,
+
+    /// Also show the video in a window on the local display, for debugging. This keeps the video
+    /// running all the time.
+    #[arg(long)]
+    preview: bool,
+
+    /// Enable features meant for debugging and analysis: uncompressed snapshots, with
+    /// `?format=ppm` or `?format=bmp`. This converts every frame to RGB while the video is
+    /// running, whether or not anyone asks for one.
+    #[arg(long)]
+    debug_endpoints: bool,
+
+    /// Also serve a small MJPEG stream at /thumb, scaled down to this many pixels wide, for
+    /// thumbnails. This is encoded separately from the main video, from the same camera.
+    #[arg(long, value_parser = clap::value_parser!(u32).range(16..=4096))]
+    thumbnail_width: Option<u32>,
+
+    /// If the camera can send JPEG itself (at --size, if given), serve its frames as they are,
+    /// without decoding and re-encoding them. That saves CPU and keeps the camera's quality. Any
+    /// option that needs raw video or has to do with encoding (like --filter, --quality, or
+    /// --thumbnail-width) rules it out, as does a camera without JPEG; then the video is
+    /// re-encoded as usual, with a warning saying why. Color adjustments aren't available.
+    #[arg(long)]
+    passthrough: bool,
+
+    /// Most frames per second to send on /thumb streams, like --stream-max-fps is for the main
+    /// stream.
+    #[arg(long, value_parser = parse_fps, requires = "thumbnail_width")]
+    thumbnail_max_fps: Option<f64>,
+
+    /// Serve a rough measure of motion at /motion: how different the two latest frames are, from
+    /// 0 to 1. This keeps small grayscale copies of frames while the video is running.
+    #[arg(long)]
+    motion: bool,
+
+    /// Keep this many seconds of the most recent video in memory, so clips saved with
+    /// `POST /control/save-clip` include what happened before they were asked for. This keeps
+    /// the video running all the time. Zero disables it.
+    #[arg(long, default_value = "0")]
+    pre_record_seconds: u64,
+
+    /// How many seconds of video to add to saved clips after the request to save one.
+    #[arg(long, default_value = "5")]
+    post_record_seconds: u64,
+
+    /// Directory to save clips into.
+    #[arg(long, default_value = ".")]
+    clip_dir: PathBuf,
+
+    /// While the video is running, keep this file holding the latest frame, for tools that watch
+    /// a file. Each one is written to a temporary file in the same directory and renamed over it,
+    /// so it's never seen half-written. This doesn't start the video by itself.
+    #[arg(long)]
+    latest_file: Option<PathBuf>,
+
+    /// Most frames per second to write to --latest-file, to go easy on the disk.
+    #[arg(long, value_parser = parse_fps, requires = "latest_file")]
+    latest_file_max_fps: Option<f64>,
+
+    /// Most frames per second to send on a stream, no matter how fast the camera is or what the
+    /// client asks for with `?fps=`. Extra frames are dropped, evenly spaced.
+    #[arg(long, value_parser = parse_fps)]
+    stream_max_fps: Option<f64>,
+
+    /// After the video starts, throw away frames for this many milliseconds, so clients don't see
+    /// the dark or washed-out ones from before the camera's auto-exposure settles. This happens
+    /// every time the video starts, so --snapshot-timeout needs to allow for it too.
+    #[arg(long, default_value = "0")]
+    warmup_ms: u64,
+
+    /// Use only one of every N encoded frames, for streams, snapshots, and clips (HLS isn't
+    /// affected). This is cheaper than --stream-max-fps, since the rest are dropped as soon as
+    /// they're encoded, but it can only divide the camera's frame rate.
+    #[arg(long, default_value = "1", value_parser = clap::value_parser!(u64).range(1..))]
+    decimate: u64,
+
+    /// Most streams to send at once. Clients past this get a 503 error, with a Retry-After header,
+    /// instead of a stream. No limit by default.
+    #[arg(long)]
+    max_clients: Option<u64>,
+
+    /// Most bytes of frames to keep waiting for streams that are falling behind. Past this, the
+    /// oldest waiting frames are dropped, as they would be if there were too many of them. This
+    /// matters at high resolutions, where a few frames can be many megabytes. No limit by
+    /// default.
+    #[arg(long)]
+    buffer_bytes: Option<usize>,
+
+    /// Most frames to keep waiting for streams that are falling behind, before the oldest are
+    /// skipped. More rides out longer hiccups in a client's connection, at the cost of memory
+    /// and of how far behind a stream can get.
+    #[arg(
+        long,
+        default_value = "16",
+        value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..),
+    )]
+    buffer_frames: usize,
+
+    /// Use this fixed boundary string between the frames of every stream, instead of a random
+    /// one, for clients that expect a particular boundary. It can contain letters, digits, and
+    /// any of '+-._ (up to 70 characters).
+    #[arg(long)]
+    boundary: Option<Boundary>,
+
+    /// Allow rebuilding the video pipeline with `POST /control/restart-pipeline`. This re-reads
+    /// the config file first, so it applies changed video settings. Streams carry on with frames
+    /// from the new pipeline.
+    #[arg(long)]
+    allow_restart: bool,
+
+    /// Believe the `X-Forwarded-For` header on requests from a reverse proxy in this address
+    /// range (like `127.0.0.1` or `10.0.0.0/8`), and log the client address it gives instead of
+    /// the proxy's. Can be given more than once. The header is ignored from anyone else.
+    #[arg(long)]
+    trusted_proxy: Vec<Cidr>,
+
+    /// Instead of serving anything, capture one frame, write it to this file, and exit. This
+    /// waits up to --snapshot-timeout for the frame.
+    #[arg(long, conflicts_with = "hls")]
+    capture_one: Option<PathBuf>,
+
+    /// Check that the pipeline can be built and its formats negotiated, print the format of
+    /// the encoded video, and exit, without serving anything. Exits with an error status if the
+    /// check fails, for catching a bad --filter or unsupported --size before deploying. This
+    /// waits up to --snapshot-timeout.
+    #[arg(long, conflicts_with_all = ["hls", "capture_one", "once"])]
+    check: bool,
+
+    /// Don't start the video briefly at startup to make sure its formats can be negotiated.
+    /// Normally, if the camera can't produce what's asked for (like an unsupported --size),
+    /// the program exits with an error then, instead of serving a stream that never gets any
+    /// frames.
+    #[arg(long)]
+    no_startup_check: bool,
+
+    /// Like --capture-one, but write the frame to stdout, for use in shell pipelines.
+    #[arg(long, conflicts_with_all = ["hls", "capture_one"])]
+    once: bool,
+
+    /// Content-Type to label each frame of the stream with, instead of the usual one for the
+    /// codec (`image/jpeg` for MJPEG), for clients that expect something else.
+    #[arg(long)]
+    part_content_type: Option<HeaderValue>,
+
+    /// Send only Content-Type and Content-Length with each frame of the stream, leaving out
+    /// X-Timestamp and the rest, for clients that choke on anything extra.
+    #[arg(long, conflicts_with = "frame_numbers")]
+    minimal_headers: bool,
+
+    /// Send an X-Frame-Number header with each frame of the stream. The numbers count up from
+    /// zero when the video is started, so gaps show where frames were dropped.
+    #[arg(long)]
+    frame_numbers: bool,
+
+    /// Send each frame of the stream with its buffer's timing from the pipeline, in nanoseconds:
+    /// X-PTS, X-DTS, and X-Duration, for digging into latency.
+    #[arg(long, conflicts_with = "minimal_headers")]
+    debug_timing: bool,
+
+    /// Follow each frame of the stream with an application/json part giving its timestamp,
+    /// frame number, and resolution, for analytics clients. Browsers and most viewers don't
+    /// understand these extra parts, so only use this for clients that expect them.
+    #[arg(long)]
+    stream_metadata: bool,
+
+    /// Change how the stream is sent to clients whose User-Agent contains some text (ignoring
+    /// case), written like `ESP32=trailing-crlf,minimal-headers`. Quirks are `minimal-headers`
+    /// (as with --minimal-headers), `no-timestamp` (leave out X-Timestamp), and `trailing-crlf`
+    /// (end each frame's data with a CRLF before the next boundary). Can be given more than once;
+    /// every entry that matches applies.
+    #[arg(long)]
+    client_quirks: Vec<ClientQuirks>,
+
+    /// Log each request when its response is done, in this format, instead of the usual line when
+    /// it arrives. Tokens are {remote}, {method}, {path}, {status}, {user-agent}, {bytes} (of
+    /// body sent), and {duration} (in milliseconds); `{{` and `}}` are literal braces. For
+    /// example `{remote} - - "{method} {path}" {status} {bytes} "-" "{user-agent}"` for
+    /// combined-log style lines.
+    #[arg(long)]
+    access_log_format: Option<AccessLogFormat>,
+
+    /// Require this user name and password (as USER:PASS), with HTTP Basic authentication, for
+    /// everything but the index page. They're sent in the clear, so only use this on a network you
+    /// trust, or behind a proxy that adds TLS. Putting this in the config file instead keeps the
+    /// password out of `ps`.
+    #[arg(long)]
+    auth: Option<Credentials>,
+
+    /// Only require --auth for paths under this prefix (like "/control" or "/metrics"), leaving
+    /// the rest public. Can be given more than once. Without it, --auth covers everything but
+    /// the index page.
+    #[arg(long, requires = "auth")]
+    auth_path: Vec<String>,
+
+    /// Don't require --auth from clients on this machine (connecting from 127.0.0.1 or ::1), like
+    /// a local dashboard. This goes by the connection itself, never X-Forwarded-For, so a
+    /// reverse proxy on this machine lets everyone through it in too.
+    #[arg(long, requires = "auth")]
+    auth_allow_localhost: bool,
+
+    /// Let scripts on pages from this origin (e.g. "https://example.com", or "*" for any) fetch
+    /// the stream, thumbnails, snapshots, and status, by sending CORS headers and answering preflight
+    /// OPTIONS requests. Without this, no CORS headers are sent.
+    #[arg(long)]
+    cors_origin: Option<HeaderValue>,
+}
+
+fn parse_property(s: &str) -> anyhow::Result<(String, String)> {
+    match s.split_once('=') {
+        Some((name, value)) if !name.is_empty() => Ok((name.to_owned(), value.to_owned())),
+        _ => bail!("must be NAME=VALUE"),
+    }
+}
+
+fn parse_camera(s: &str) -> anyhow::Result<(String, String)> {
+    let Some((name, device)) = s.split_once('=') else {
+        bail!("must be NAME=DEVICE");
+    };
+    if name.is_empty()
+        || !name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        bail!("camera name must be letters, digits, '-', and '_'");
+    }
+    Ok((name.to_owned(), device.to_owned()))
+}
+
+fn parse_exif_comment(s: &str) -> anyhow::Result<String> {
+    if s.len() > exif::MAX_COMMENT_BYTES {
+        bail!("must be at most {} bytes", exif::MAX_COMMENT_BYTES);
+    }
+    Ok(s.to_owned())
+}
+
+fn parse_snapshot_comment(s: &str) -> anyhow::Result<String> {
+    if s.len() > exif::MAX_COM_BYTES {
+        bail!("must be at most {} bytes", exif::MAX_COM_BYTES);
+    }
+    Ok(s.to_owned())
+}
+
+fn parse_fps(s: &str) -> anyhow::Result<f64> {
+    match s.parse::<f64>() {
+        Ok(fps) if fps > 0. && fps.is_finite() => Ok(fps),
+        _ => bail!("must be a positive number"),
+    }
+}
+
+/// Parse the command line, along with the config file it names, if any.
+fn load_args(cli: &[OsString]) -> anyhow::Result<Args> {
+    let args = Args::try_parse_from(cli)?;
+    let Some(path) = &args.config else {
+        return Ok(args);
+    };
+    // Options from the file go first, so the command line overrides them. Ones that can be given
+    // more than once would be added to instead, so leave out any that are on the command line.
+    let mut argv = vec![cli[0].clone()];
+    argv.extend(config_file_args(path, &cli[1..])?);
+    argv.extend(cli[1..].iter().cloned());
+    Args::try_parse_from(argv).with_context(|| format!("invalid options in config file {path:?}"))
+}
+
+/// Translate a TOML config file into the equivalent command-line arguments, except for any
+/// options that are in `skip`.
+fn config_file_args(path: &Path, skip: &[OsString]) -> anyhow::Result<Vec<OsString>> {
+    let text = std::fs::read_to_string(path)
+        .with_context(|| format!("failed to read config file {path:?}"))?;
+    let table: toml::Table =
+        toml::from_str(&text).with_context(|| format!("failed to parse config file {path:?}"))?;
+    let mut argv = vec![];
+    for (key, value) in table {
+        let flag = format!("--{}", key.replace('_', "-"));
+        let on_cli = skip.iter().any(|arg| {
+            let arg = arg.to_string_lossy();
+            arg == flag || arg.starts_with(&format!("{flag}="))
+        });
+        if on_cli {
+            continue;
+        }
+        let values = match value {
+            toml::Value::Array(values) => values,
+            value => vec![value],
+        };
+        for value in values {
+            match value {
+                toml::Value::Boolean(true) => argv.push(flag.clone().into()),
+                toml::Value::Boolean(false) => (),
+                toml::Value::String(s) => {
+                    argv.push(flag.clone().into());
+                    argv.push(s.into());
+                }
+                toml::Value::Integer(_) | toml::Value::Float(_) => {
+                    argv.push(flag.clone().into());
+                    argv.push(value.to_string().into());
+                }
+                _ => bail!("unsupported value for {key:?} in config file {path:?}"),
+            }
+        }
+    }
+    Ok(argv)
+}
+
+fn frames_config(args: &Args) -> frames::Config {
+    frames::Config {
+        pre_record: Duration::from_secs(args.pre_record_seconds),
+        buffer_frames: args.buffer_frames,
+        buffer_bytes: args.buffer_bytes,
+        decimate: args.decimate,
+        warmup: Duration::from_millis(args.warmup_ms),
+        size_cap: args.max_frame_bytes.map(|max_bytes| frames::SizeCap {
+            max_bytes,
+            min_quality: args.min_quality,
+            max_quality: args.max_quality,
+        }),
+        smooth_output: args.smooth_output,
+        check_frames: !args.no_frame_check,
+        idle_timeout: Duration::from_secs(args.idle_timeout),
+    }
+}
+
+/// Settings for one of the extra --camera ones: the main camera's, but with its own device, and
+/// without what only the main camera does.
+fn camera_args(args: &Args, device: &str) -> Args {
+    let mut args = args.clone();
+    args.device = device.to_owned();
+    args.test_video = None;
+    args.test_clock = false;
+    args.file = None;
+    args.libcamera = None;
+    args.record = None;
+    args.preview = false;
+    args
+}
+
+/// Start one of the extra --camera ones, with a task of its own to rebuild its pipeline when
+/// asked to (or when it fails, with --auto-restart). Returns it, and the lock on its device.
+fn start_camera(
+    args: &Args,
+    device: &str,
+    events: &Events,
+    exit: &Arc<Notify>,
+    auto_restarts: Arc<Mutex<AutoRestarts>>,
+) -> anyhow::Result<(Camera, Option<std::fs::File>)> {
+    let args = camera_args(args, device);
+    let lock = match video_config(&args, &None).source {
+        VideoSource::V4L(device) if !args.share_device => video::lock_device(&device)?,
+        _ => None,
+    };
+    let (restart_tx, mut restart_rx): (RestartSender, _) = mpsc::channel(1);
+    let video = start_video(&args, &None, events, &restart_tx, exit, &auto_restarts)?;
+    let frames = Arc::new(Frames::new(video, events.clone(), &frames_config(&args)));
+    let camera = Camera {
+        frames: frames.clone(),
+        restart: args.allow_restart.then(|| restart_tx.clone()),
+    };
+    let hold_open = needs_hold_open(&args, &None);
+    let events = events.clone();
+    let exit = exit.clone();
+    tokio::spawn(async move {
+        while let Some(reply) = restart_rx.recv().await {
+            info!("restarting the pipeline for {device}", device = args.device);
+            let result = async {
+                let video = start_video(&args, &None, &events, &restart_tx, &exit, &auto_restarts)?;
+                frames.replace_video(video, hold_open).await
+            }
+            .await;
+            if let Err(e) = &result {
+                error!("failed to restart the pipeline for {}: {e:#}", args.device);
+            }
+            let _ = reply.send(result);
+        }
+    });
+    Ok((camera, lock))
+}
+
+fn video_config(args: &Args, hls_dir: &Option<PathBuf>) -> video::Config {
+    video::Config {
+        source: args
+            .test_video
+            .clone()
+            .map(VideoSource::Test)
+            .or_else(|| args.test_clock.then_some(VideoSource::TestClock))
+            .or_else(|| args.file.clone().map(VideoSource::File))
+            .or_else(|| args.libcamera.clone().map(VideoSource::LibCamera))
+            .unwrap_or_else(|| VideoSource::V4L(args.device.clone())),
+        v4l2_props: args
+            .v4l2_io_mode
+            .iter()
+            .map(|mode| ("io-mode".to_owned(), mode.clone()))
+            .chain(args.v4l2_prop.iter().cloned())
+            .collect(),
+        size: args.size.as_ref().map(|s| (s.width, s.height)),
+        scale: !args.no_scale,
+        filter: args.filter.clone(),
+        flip: args.flip,
+        rotation: args.rotate_stream,
+        framerate: args.framerate,
+        aspect: args.aspect.map(|a| (a.width, a.height)),
+        pad_color: args.pad_color.clone(),
+        grayscale: args.grayscale,
+        caption_file: args.caption_file.clone(),
+        timestamp_overlay: args.timestamp_overlay,
+        timestamp_format: args.timestamp_format.clone(),
+        preview: args.preview,
+        sync: !args.no_sync,
+        raw_frames: args.debug_endpoints,
+        motion: args.motion,
+        thumbnail_width: args.thumbnail_width,
+        passthrough: args.passthrough,
+        codec: args.codec,
+        encoder: args.encoder.clone(),
+        encode_pipeline: args.encode_pipeline.clone(),
+        jpeg_idct: args.jpeg_idct.clone(),
+        quality: args.quality,
+        chroma_subsampling: args.chroma_subsampling,
+        encode_queue: args.encode_queue,
+        sink_queue: args.sink_queue,
+        eos_timeout: Duration::from_millis(args.eos_timeout_ms),
+        latency: args.latency_ms.map(Duration::from_millis),
+        system_clock: args.system_clock,
+        hls_dir: hls_dir.clone(),
+        record: args.record.clone(),
+        record_overflow: args.record_overflow,
+    }
+}
+
+fn http_config(args: &Args, hls_dir: &Option<PathBuf>) -> Config {
+    Config {
+        paths: Paths {
+            stream: if args.no_stream {
+                vec![]
+            } else {
+                args.stream_path.clone()
+            },
+            snapshot: if args.no_snapshot {
+                vec![]
+            } else {
+                args.snapshot_path.clone()
+            },
+            status: args.status_path.clone(),
+            devices: args.devices_path.clone(),
+        },
+        multipart_type: args.multipart_type,
+        slow_client: match args.slow_client.as_str() {
+            "disconnect" => SlowClient::Disconnect {
+                lags: args.slow_client_lags,
+            },
+            _ => SlowClient::Drop,
+        },
+        stream_max_fps: args.stream_max_fps,
+        thumbnail_max_fps: args.thumbnail_max_fps,
+        max_clients: args.max_clients,
+        boundary: args.boundary.clone(),
+        frame_numbers: args.frame_numbers,
+        part_content_type: args.part_content_type.clone(),
+        minimal_headers: args.minimal_headers,
+        client_quirks: args.client_quirks.clone(),
+        access_log_format: args.access_log_format.clone(),
+        auth: args.auth.clone(),
+        auth_paths: args.auth_path.clone(),
+        auth_allow_localhost: args.auth_allow_localhost,
+        cors_origin: args.cors_origin.clone(),
+        debug_timing: args.debug_timing,
+        stream_metadata: args.stream_metadata,
+        snapshot_timeout: Duration::from_secs(args.snapshot_timeout),
+        snapshot_retries: args.snapshot_retries,
+        snapshot_max_fps: args.snapshot_max_fps,
+        snapshot_cache: Duration::from_millis(args.snapshot_cache_ms),
+        snapshot_exif: args.snapshot_exif,
+        snapshot_exif_comment: args.snapshot_exif_comment.clone(),
+        snapshot_comment: args.snapshot_comment.clone(),
+        snapshot_rotation: args.rotate_snapshot.unwrap_or(args.rotate_stream) - args.rotate_stream,
+        placeholder: args.placeholder.clone(),
+        hls_dir: hls_dir.clone(),
+        clip_dir: (args.pre_record_seconds > 0).then(|| args.clip_dir.clone()),
+        post_record: Duration::from_secs(args.post_record_seconds),
+        trusted_proxies: args.trusted_proxy.clone(),
+    }
+}
+
+/// Re-read the config file, and apply whatever changes can be applied while running.
+fn reload(
+    cli: &[OsString],
+    old: &Args,
+    hls_dir: &Option<PathBuf>,
+    http_tx: &watch::Sender<Arc<Config>>,
+) -> anyhow::Result<Args> {
+    let new = load_args(cli)?;
+
+    let new_http = http_config(&new, hls_dir);
+    if **http_tx.borrow() != new_http {
+        info!("applying new HTTP settings: {new_http:?}");
+        http_tx.send_replace(Arc::new(new_http));
+    } else {
+        info!("HTTP settings unchanged");
+    }
+
+    if new.port != old.port
+        || new.bind != old.bind
+        || new.max_header_bytes != old.max_header_bytes
+        || new.header_read_timeout != old.header_read_timeout
+        || new.http_idle_timeout != old.http_idle_timeout
+        || new.max_accepts_per_sec != old.max_accepts_per_sec
+    {
+        warn!("HTTP server settings changed; restart to apply them");
+    }
+    Ok(new)
+}
+
+/// Apply changes in the video settings to the running pipeline, where that can be done without
+/// rebuilding it. Returns whether anything else changed, which needs a rebuild.
+fn apply_video_changes(video: &Video, old: &video::Config, new: &video::Config) -> bool {
+    let mut unapplied = new.clone();
+    if let Some(quality) = new.quality.filter(|_| new.quality != old.quality) {
+        match video.set_quality(quality.into()) {
+            Ok(()) => {
+                info!("applied new encoder quality: {quality}");
+                unapplied.quality = old.quality;
+            }
+            Err(e) => debug!("can't change the quality without a rebuild: {e:#}"),
+        }
+    }
+    if let (Some(_), Some(path)) = (&old.caption_file, &new.caption_file) {
+        if old.caption_file != new.caption_file {
+            match video.set_caption_file(path.clone()) {
+                Ok(()) => {
+                    info!("applied new caption file: {path:?}");
+                    unapplied.caption_file = old.caption_file.clone();
+                }
+                Err(e) => warn!("{e:#}"),
+            }
+        }
+    }
+    unapplied != *old
+}
+
+/// How long the pipeline has to go without trouble after an automatic restart for the count of
+/// tries to start over.
+const AUTO_RESTART_RESET: Duration = Duration::from_secs(60);
+
+/// When `--auto-restart` (or `--reconnect-max-attempts`) tries to rebuild the pipeline.
+#[derive(Debug, Clone, Copy)]
+struct RestartPolicy {
+    /// Wait before the first try; each one after waits twice as long.
+    initial: Duration,
+    /// The longest wait between tries.
+    max_delay: Duration,
+    /// Tries in a row before giving up, or 0 to never give up.
+    max_attempts: u32,
+}
+
+impl RestartPolicy {
+    /// The policy the arguments ask for, if automatic restarts are turned on.
+    fn from_args(args: &Args) -> Option<Self> {
+        Some(Self {
+            initial: Duration::from_millis(args.reconnect_initial_ms),
+            max_delay: Duration::from_millis(args.reconnect_max_ms),
+            max_attempts: args.auto_restart.or(args.reconnect_max_attempts)?,
+        })
+    }
+
+    /// How long to wait before the given try, counting from 1.
+    fn delay(&self, attempt: u32) -> Duration {
+        self.initial
+            .saturating_mul(1 << (attempt - 1).min(31))
+            .min(self.max_delay)
+    }
+
+    /// Every wait in turn, for logging.
+    fn schedule(&self) -> String {
+        let mut delays = vec![];
+        for attempt in 1.. {
+            let delay = self.delay(attempt);
+            delays.push(format!("{delay:?}"));
+            if attempt == self.max_attempts {
+                return format!("{}, then give up", delays.join(", "));
+            } else if delay == self.delay(attempt + 1) {
+                // It's as long as it gets.
+                let rest = match self.max_attempts {
+                    0 => "forever".to_owned(),
+                    max if max - attempt == 1 => "once more, then give up".to_owned(),
+                    max => format!("{} more times, then give up", max - attempt),
+                };
+                return format!("{}, then {delay:?} {rest}", delays.join(", "));
+            }
+        }
+        unreachable!()
+    }
+}
+
+/// Automatic restarts in a row, for `--auto-restart`. This outlives each pipeline.
+#[derive(Debug, Default)]
+struct AutoRestarts {
+    count: u32,
+    /// When the last one finished.
+    last: Option<Instant>,
+    /// Whether one is waiting to happen. An error usually comes with several messages, and they
+    /// shouldn't each count.
+    pending: bool,
+    /// Whether the last try failed, and the server was told to exit.
+    gave_up: bool,
+}
+
+impl AutoRestarts {
+    /// Count another try, starting over if the last one was long enough ago to have worked, and
+    /// say which try it is. `None` means `max` tries in a row have failed (0 is no limit).
+    fn next_attempt(&mut self, max: u32) -> Option<u32> {
+        if self.last.is_some_and(|t| t.elapsed() >= AUTO_RESTART_RESET) {
+            self.count = 0;
+        }
+        if max != 0 && self.count >= max {
+            return None;
+        }
+        self.count += 1;
+        Some(self.count)
+    }
+}
+
+/// Rebuild the pipeline after a while, unless that's already about to happen. If it's been tried
+/// too many times in a row, tell the server to exit instead.
+fn auto_restart(
+    state: &Arc<Mutex<AutoRestarts>>,
+    policy: RestartPolicy,
+    restart: &RestartSender,
+    exit: &Arc<Notify>,
+) {
+    let mut restarts = state.lock().unwrap();
+    if restarts.pending || restarts.gave_up {
+        return;
+    }
+    let max = policy.max_attempts;
+    let Some(attempt) = restarts.next_attempt(max) else {
+        error!("pipeline still failing after {max} automatic restarts; giving up");
+        restarts.gave_up = true;
+        exit.notify_one();
+        return;
+    };
+    restarts.pending = true;
+    let delay = policy.delay(attempt);
+    match max {
+        0 => info!("restarting the pipeline in {delay:?} (try {attempt})"),
+        _ => info!("restarting the pipeline in {delay:?} (try {attempt} of {max})"),
+    }
+    let state = state.clone();
+    let restart = restart.clone();
+    let exit = exit.clone();
+    tokio::spawn(async move {
+        tokio::time::sleep(delay).await;
+        let (reply, result) = oneshot::channel();
+        let result = match restart.send(reply).await {
+            Ok(()) => result.await.unwrap_or_else(|_| Ok(())),
+            Err(_) => return,
+        };
+        {
+            let mut restarts = state.lock().unwrap();
+            restarts.pending = false;
+            restarts.last = Some(Instant::now());
+        }
+        // The failure itself is logged by whoever did the restart.
+        if result.is_err() {
+            auto_restart(&state, policy, &restart, &exit);
+        }
+    });
+}
+
+/// Build the video pipeline, and start watching it for messages. `restart` and `exit` are used
+/// for `--on-eos`, and `auto_restarts` for `--auto-restart`.
+fn start_video(
+    args: &Args,
+    hls_dir: &Option<PathBuf>,
+    events: &Events,
+    restart: &RestartSender,
+    exit: &Arc<Notify>,
+    auto_restarts: &Arc<Mutex<AutoRestarts>>,
+) -> anyhow::Result<Arc<Video>> {
+    let mut config = video_config(args, hls_dir);
+    Video::check_elements(&config)?;
+    let mut video = Video::new(&config)?;
+    if args.max_resolution {
+        let (w, h, mode) = video
+            .largest_camera_mode()?
+            .context("the camera didn't report any sizes")?;
+        info!("using the camera's largest mode: {mode}");
+        config.size = Some((w, h));
+        video = Video::new(&config)?;
+    }
+    let video = Arc::new(video);
+
+    let bus_events = events.clone();
+    let on_eos = args.on_eos.or(args.loop_file.then_some(OnEos::Loop));
+    let restart = restart.clone();
+    let exit = exit.clone();
+    let restart_policy = RestartPolicy::from_args(args);
+    let auto_restarts = auto_restarts.clone();
+    video.spawn(
+        video
+            .clone()
+            .foreach_message(move |video, msg| match msg.view() {
+                MessageView::Eos(..) => match on_eos {
+                    Some(OnEos::Loop) => {
+                        info!("end of video; starting over");
+                        if let Err(e) = video.rewind() {
+                            error!("{e:#}");
+                        }
+                    }
+                    Some(OnEos::Restart) => {
+                        info!("end of video; restarting the pipeline");
+                        // Nobody's waiting for the result; it's logged either way.
+                        let (reply, _) = oneshot::channel();
+                        if restart.try_send(reply).is_err() {
+                            warn!("a pipeline restart is already pending");
+                        }
+                    }
+                    Some(OnEos::Exit) => {
+                        info!("end of video; exiting");
+                        exit.notify_one();
+                    }
+                    None => {
+                        error!("got EOS from video");
+                        if let Some(policy) = restart_policy {
+                            auto_restart(&auto_restarts, policy, &restart, &exit);
+                        }
+                    }
+                },
+                MessageView::Error(e) => {
+                    let source = e.src().map(|s| s.path_string());
+                    let debug = e.debug();
+                    if video::is_negotiation_error(&e.error(), debug.as_deref()) {
+                        video.set_not_negotiated();
+                    }
+                    match video::explain_error(&e.error(), debug.as_deref()) {
+                        Some(explained) => {
+                            error!("Error from {source:?}: {explained} ({})", e.error());
+                            debug!("error details: {debug:?}");
+                            video.set_last_error(explained.to_owned());
+                        }
+                        None => {
+                            error!("Error from {:?}: {} ({:?})", source, e.error(), debug);
+                            video.set_last_error(e.error().to_string());
+                        }
+                    }
+                    bus_events.send(Event::Error {
+                        source: source.map(|s| s.to_string()).unwrap_or_default(),
+                        message: e.error().to_string(),
+                    });
+                    if let Some(policy) = restart_policy {
+                        auto_restart(&auto_restarts, policy, &restart, &exit);
+                    }
+                }
+                MessageView::Warning(w) => {
+                    let source = w.src().map(|s| s.path_string());
+                    warn!("Warning from {:?}: {} ({:?})", source, w.error(), w.debug());
+                    bus_events.send(Event::Warning {
+                        source: source.map(|s| s.to_string()).unwrap_or_default(),
+                        message: w.error().to_string(),
+                    });
+                }
+                MessageView::StateChanged(change) if video.is_pipeline(msg.src()) => {
+                    info!(
+                        "pipeline state changed from {:?} to {:?}",
+                        change.old(),
+                        change.current()
+                    );
+                    bus_events.send(Event::StateChanged {
+                        old: format!("{:?}", change.old()),
+                        new: format!("{:?}", change.current()),
+                    });
+                }
+                MessageView::Qos(qos) => {
+                    // These come with every frame dropped for being late, so they're only worth
+                    // logging when looking closely.
+                    let (processed, dropped) = qos.stats();
+                    debug!(
+                        "{:?} is dropping late frames: {dropped} dropped, {processed} processed",
+                        qos.src().map(|s| s.path_string())
+                    );
+                }
+                MessageView::Buffering(b) => debug!("buffering: {}%", b.percent()),
+                _ => (),
+            }),
+    );
+
+    if args.caption_file.is_some() {
+        video.spawn(video.clone().watch_caption_file());
+    }
+
+    Ok(video)
+}
+
+/// The sizes `--adaptive-resolution` steps through: `start`, then the camera's smaller sizes,
+/// largest first. If any of those have the same aspect ratio as `start`, only they are used, so
+/// the picture doesn't change shape.
+fn resolution_steps(start: (u32, u32), sizes: &[(u32, u32)]) -> Vec<(u32, u32)> {
+    let area = |(w, h): (u32, u32)| u64::from(w) * u64::from(h);
+    let smaller: Vec<(u32, u32)> = sizes
+        .iter()
+        .copied()
+        .filter(|&s| area(s) < area(start))
+        .collect();
+    let same_shape: Vec<(u32, u32)> = smaller
+        .iter()
+        .copied()
+        .filter(|&(w, h)| u64::from(w) * u64::from(start.1) == u64::from(h) * u64::from(start.0))
+        .collect();
+    let steps = if same_shape.is_empty() {
+        smaller
+    } else {
+        same_shape
+    };
+    std::iter::once(start).chain(steps).collect()
+}
+
+/// Whether the video has to be held open to keep working after dropping privileges.
+fn needs_hold_open(args: &Args, hls_dir: &Option<PathBuf>) -> bool {
+    (args.user.is_some() || args.group.is_some())
+        && matches!(
+            video_config(args, hls_dir).source,
+            VideoSource::V4L(_) | VideoSource::LibCamera(_)
+        )
 }
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    let args = Args::parse();
+    let cli: Vec<OsString> = std::env::args_os().collect();
+    let args = match load_args(&cli) {
+        Ok(args) => args,
+        Err(e) => match e.downcast::<clap::Error>() {
+            Ok(e) => e.exit(),
+            Err(e) => return Err(e),
+        },
+    };
     if args.verbose > 0 {
         dbg!(&args);
     }
     stderrlog::new()
         .module(module_path!())
+        .modules(args.log_module.iter().cloned())
         .verbosity(args.verbose as usize + 1)
         .init()
         .unwrap();
 
+    if args.aspect.is_some() && args.size.is_none() && !args.max_resolution {
+        bail!("--aspect needs --size or --max-resolution, to work out the borders");
+    }
+
     Video::gst_init()?;
-    let video = Arc::new(Video::new(
-        args.test_video
-            .map(VideoSource::Test)
-            .unwrap_or_else(|| VideoSource::V4L(args.device.clone())),
-        args.size.map(|s| (s.width, s.height)),
-        args.filter.as_deref(),
-    )?);
 
-    tokio::spawn(
-        video
+    let hls_dir = if args.hls {
+        let dir = std::env::temp_dir().join(format!("gst-mjpg-hls-{}", std::process::id()));
+        std::fs::create_dir_all(&dir)
+            .with_context(|| format!("failed to create HLS directory {dir:?}"))?;
+        info!("writing HLS segments to {dir:?}");
+        Some(dir)
+    } else {
+        None
+    };
+    // Held until exiting. Only the device it started with is locked, even if reloading changes it.
+    let _device_lock = match video_config(&args, &hls_dir).source {
+        VideoSource::V4L(device) if !args.share_device => video::lock_device(&device)?,
+        _ => None,
+    };
+    if let Some(policy) = RestartPolicy::from_args(&args) {
+        if policy.initial.is_zero() {
+            // Otherwise a device that keeps failing would be restarted in a tight loop.
+            bail!("--reconnect-initial-ms has to be more than 0");
+        }
+        if policy.max_delay < policy.initial {
+            bail!("--reconnect-max-ms can't be less than --reconnect-initial-ms");
+        }
+        info!("automatic restarts wait {}", policy.schedule());
+    }
+    let events = Events::new();
+    let (restart_tx, mut restart_rx): (RestartSender, _) = mpsc::channel(1);
+    let exit = Arc::new(Notify::new());
+    let auto_restarts = Arc::new(Mutex::new(AutoRestarts::default()));
+    // Every camera's, to see whether one gave up.
+    let mut all_restarts = vec![auto_restarts.clone()];
+    let video = start_video(&args, &hls_dir, &events, &restart_tx, &exit, &auto_restarts)?;
+
+    if args.verbose > 0 {
+        if let Err(e) = video.log_camera_modes() {
+            warn!("couldn't get camera modes: {e:#}");
+        }
+    }
+
+    if args.check {
+        let timeout = Duration::from_secs(args.snapshot_timeout);
+        let checked = video.clone();
+        let result = tokio::task::spawn_blocking(move || checked.check(timeout))
+            .await
+            .context("check task failed")?;
+        video.shutdown().await?;
+        println!("{}", result.context("pipeline check failed")?);
+        return Ok(());
+    }
+
+    // Capturing a single frame fails on its own if the video can't start.
+    if !args.no_startup_check && args.capture_one.is_none() && !args.once {
+        let timeout = Duration::from_secs(args.snapshot_timeout);
+        let checked = video.clone();
+        let result = tokio::task::spawn_blocking(move || checked.check(timeout))
+            .await
+            .context("check task failed")?;
+        if result.is_err() {
+            // The error that says why comes through the message loop, which may not have seen
+            // it yet.
+            let _ = tokio::time::timeout(Duration::from_secs(1), video.failed()).await;
+        }
+        match result {
+            Ok(caps) => debug!("video starts fine, with {caps}"),
+            // Other failures (like the camera not being plugged in yet) can clear up on their
+            // own, so only this one is fatal.
+            Err(e) if video.not_negotiated() => {
+                video.shutdown().await?;
+                return Err(e.context("the video can't be started with these settings"));
+            }
+            Err(e) => warn!("video didn't start at startup: {e:#}"),
+        }
+        video.stop().await?;
+    }
+
+    // Asked now, while the camera isn't in use.
+    let resolutions = if args.adaptive_resolution {
+        let sizes = video
+            .camera_sizes()
+            .context("couldn't get camera sizes for --adaptive-resolution")?;
+        let start = match (&args.size, sizes.first()) {
+            (Some(size), _) => (size.width, size.height),
+            (None, Some(&largest)) if args.max_resolution => largest,
+            (None, None) if args.max_resolution => bail!("the camera didn't report any sizes"),
+            (None, _) => bail!("--adaptive-resolution needs --size or --max-resolution"),
+        };
+        resolution_steps(start, &sizes)
+    } else {
+        vec![]
+    };
+
+    let (http_tx, http_rx) = watch::channel(Arc::new(http_config(&args, &hls_dir)));
+    let frames = Arc::new(Frames::new(
+        video.clone(),
+        events.clone(),
+        &frames_config(&args),
+    ));
+
+    if args.capture_one.is_some() || args.once {
+        let frame = frames
             .clone()
-            .foreach_message(move |_video, msg| match msg.view() {
-                MessageView::Eos(..) => {
-                    error!("got EOS from video");
+            .capture_one(Duration::from_secs(args.snapshot_timeout))
+            .await;
+        frames.video().shutdown().await?;
+        let frame = frame?;
+        match &args.capture_one {
+            Some(path) => {
+                std::fs::write(path, &frame.data)
+                    .with_context(|| format!("failed to write frame to {path:?}"))?;
+                info!("wrote {} bytes to {path:?}", frame.data.len());
+            }
+            None => {
+                let mut stdout = std::io::stdout().lock();
+                stdout
+                    .write_all(&frame.data)
+                    .and_then(|()| stdout.flush())
+                    .context("failed to write frame to stdout")?;
+            }
+        }
+        return Ok(());
+    }
+
+    if hls_dir.is_some() || args.pre_record_seconds > 0 || args.preview || args.record.is_some() {
+        // HLS clients just fetch files, so there's no way to tell when they come and go, and
+        // pre-recording, recording, and the preview window need frames whether or not anyone
+        // is watching.
+        // Keep the video running all the time instead.
+        // This isn't tied to the video, so it carries on if the video is replaced.
+        let mut stream = frames.clone().stream().await;
+        tokio::spawn(async move { while stream.next().await.is_some() {} });
+    }
+
+    if (args.adaptive_quality || args.max_frame_bytes.is_some())
+        && args.min_quality > args.max_quality
+    {
+        bail!("--min-quality can't be more than --max-quality");
+    }
+    if resolutions.len() > 1 {
+        let (w, h) = resolutions[0];
+        info!(
+            "adaptive resolution: {w}x{h} and {} smaller sizes",
+            resolutions.len() - 1
+        );
+        let hold_open = needs_hold_open(&args, &hls_dir);
+        let args = args.clone();
+        let hls_dir = hls_dir.clone();
+        let events = events.clone();
+        let restart_tx = restart_tx.clone();
+        let exit = exit.clone();
+        let auto_restarts = auto_restarts.clone();
+        let build = move |(width, height)| {
+            let mut args = args.clone();
+            args.size = Some(Size { width, height });
+            args.max_resolution = false;
+            start_video(&args, &hls_dir, &events, &restart_tx, &exit, &auto_restarts)
+        };
+        tokio::spawn(
+            frames
+                .clone()
+                .adapt_resolution(resolutions, hold_open, build),
+        );
+    } else if args.adaptive_resolution {
+        warn!("the camera has no smaller sizes to switch to; not adapting the resolution");
+    }
+
+    if args.adaptive_quality {
+        tokio::spawn(
+            frames
+                .clone()
+                .adapt_quality(args.min_quality, args.max_quality),
+        );
+    }
+
+    if let Some(path) = &args.latest_file {
+        let interval = args
+            .latest_file_max_fps
+            .map(|fps| Duration::from_secs_f64(1. / fps));
+        tokio::spawn(frames.clone().write_latest(path.clone(), interval));
+    }
+
+    let mut cameras = HashMap::new();
+    // Held until exiting, like the main camera's.
+    let mut _camera_locks = vec![];
+    for (name, device) in &args.camera {
+        if http::shadows_path(name, &http_rx.borrow().paths) {
+            bail!("camera name {name:?} is the same as the start of one of the server's paths");
+        }
+        if cameras.contains_key(name) {
+            bail!("there's more than one camera named {name:?}");
+        }
+        let restarts = Arc::new(Mutex::new(AutoRestarts::default()));
+        all_restarts.push(restarts.clone());
+        let (camera, lock) = start_camera(&args, device, &events, &exit, restarts)
+            .with_context(|| format!("failed to start camera {name:?}"))?;
+        info!("serving {device} under /{name}");
+        cameras.insert(name.clone(), camera);
+        _camera_locks.extend(lock);
+    }
+    let cameras = Arc::new(cameras);
+
+    let mut hangup = signal(SignalKind::hangup()).context("failed to install SIGHUP handler")?;
+    let mut interrupt =
+        signal(SignalKind::interrupt()).context("failed to install SIGINT handler")?;
+    let mut terminate =
+        signal(SignalKind::terminate()).context("failed to install SIGTERM handler")?;
+    let mut user1 =
+        signal(SignalKind::user_defined1()).context("failed to install SIGUSR1 handler")?;
+    let ports = args.port.clone();
+    let bind = args.bind;
+    let raw_frame_port = args.raw_frame_port;
+    let ignore_bind_errors = args.ignore_bind_errors;
+    let mdns_name = args.mdns_name.clone();
+    let stream_path = (!args.no_stream)
+        .then(|| args.stream_path.first().cloned())
+        .flatten();
+    let user = args.user.clone();
+    let group = args.group.clone();
+    let hold_open = needs_hold_open(&args, &hls_dir);
+    let eos_restart_tx = restart_tx.clone();
+    let eos_exit = exit.clone();
+    let restart_tx = args.allow_restart.then_some(restart_tx);
+    let max_header_bytes = args.max_header_bytes;
+    let header_read_timeout = args.header_read_timeout;
+    let http_idle_timeout = args.http_idle_timeout;
+    let max_accepts_per_sec = args.max_accepts_per_sec;
+    let reload_hls_dir = hls_dir.clone();
+    let reload_frames = frames.clone();
+    tokio::spawn(async move {
+        let mut args = args;
+        loop {
+            tokio::select! {
+                Some(()) = hangup.recv() => {
+                    info!("got SIGHUP; reloading configuration");
+                    match reload(&cli, &args, &reload_hls_dir, &http_tx) {
+                        Ok(new) => {
+                            let old_config = video_config(&args, &reload_hls_dir);
+                            let new_config = video_config(&new, &reload_hls_dir);
+                            let rebuild = apply_video_changes(
+                                &reload_frames.video(),
+                                &old_config,
+                                &new_config,
+                            );
+                            let result = async {
+                                if rebuild {
+                                    info!("rebuilding the pipeline for new video settings: {new_config:?}");
+                                    let video = start_video(
+                                        &new,
+                                        &reload_hls_dir,
+                                        &events,
+                                        &eos_restart_tx,
+                                        &eos_exit,
+                                        &auto_restarts,
+                                    )?;
+                                    reload_frames.replace_video(video, hold_open).await?;
+                                } else if old_config == new_config {
+                                    info!("video settings unchanged");
+                                }
+                                anyhow::Ok(())
+                            }
+                            .await;
+                            match result {
+                                Ok(()) => args = new,
+                                // Keep the old ones, so the next reload tries again.
+                                Err(e) => error!("failed to apply new video settings: {e:#}"),
+                            }
+                        }
+                        Err(e) => error!("failed to reload configuration: {e:#}"),
+                    }
                 }
-                MessageView::Error(e) => {
-                    error!(
-                        "Error from {:?}: {} ({:?})",
-                        e.src().map(|s| s.path_string()),
-                        e.error(),
-                        e.debug(),
-                    );
+                Some(()) = user1.recv() => {
+                    info!("got SIGUSR1; resetting the video pipeline");
+                    let (reply, _) = oneshot::channel();
+                    if eos_restart_tx.try_send(reply).is_err() {
+                        warn!("a pipeline restart is already pending");
+                    }
                 }
-                _ => (),
-            }),
-    );
+                Some(reply) = restart_rx.recv() => {
+                    info!("restarting video pipeline by request");
+                    let result = async {
+                        let new = reload(&cli, &args, &reload_hls_dir, &http_tx)?;
+                        let video =
+                            start_video(
+                                &new,
+                                &reload_hls_dir,
+                                &events,
+                                &eos_restart_tx,
+                                &eos_exit,
+                                &auto_restarts,
+                            )?;
+                        reload_frames.replace_video(video, hold_open).await?;
+                        args = new;
+                        anyhow::Ok(())
+                    }
+                    .await;
+                    if let Err(e) = &result {
+                        error!("failed to restart video pipeline: {e:#}");
+                    }
+                    let _ = reply.send(result);
+                }
+                else => break,
+            }
+        }
+    });
 
-    let paths = Arc::new(Paths {
-        stream: args.stream_path,
-        snapshot: args.snapshot_path,
+    let limits = Limits {
+        max_header_bytes: max_header_bytes as usize,
+        header_read_timeout: Duration::from_secs(header_read_timeout),
+        idle_timeout: Duration::from_secs(http_idle_timeout),
+        max_accepts_per_sec,
+    };
+    let mut incomings = vec![];
+    for &port in &ports {
+        match http::bind(bind, port) {
+            Ok(incoming) => incomings.push((port, incoming)),
+            Err(e) if ignore_bind_errors => error!("{e:#}"),
+            Err(e) => return Err(e),
+        }
+    }
+    let Some(&(port, _)) = incomings.first() else {
+        bail!("couldn't bind to any port");
+    };
+    let raw_listener = match raw_frame_port {
+        Some(port) => Some((port, tcp::bind(bind, port).await?)),
+        None => None,
+    };
+    if user.is_some() || group.is_some() {
+        if hold_open {
+            video.hold_open()?;
+        }
+        // They're all V4L cameras.
+        for camera in cameras.values() {
+            camera.frames.video().hold_open()?;
+        }
+        privs::drop_privileges(user.as_deref(), group.as_deref())?;
+    }
+
+    let mdns = match &mdns_name {
+        Some(name) => {
+            let responder = libmdns::Responder::spawn(&tokio::runtime::Handle::current())
+                .context("failed to start mDNS responder")?;
+            let txt = stream_path
+                .iter()
+                .map(|p| format!("path={p}"))
+                .collect::<Vec<_>>();
+            let txt = txt.iter().map(String::as_str).collect::<Vec<_>>();
+            let service = responder.register("_http._tcp".into(), name.clone(), port, &txt);
+            info!("advertising {name:?} on port {port} via mDNS");
+            Some((responder, service))
+        }
+        None => None,
+    };
+
+    let shared = Arc::new(Shared::default());
+    let (shutdown_tx, shutdown_rx) = watch::channel(());
+    let servers = incomings.into_iter().map(|(port, incoming)| {
+        info!("listening on port {port}");
+        let mut shutdown_rx = shutdown_rx.clone();
+        http::serve(
+            incoming,
+            limits.clone(),
+            http_rx.clone(),
+            Camera {
+                frames: frames.clone(),
+                restart: restart_tx.clone(),
+            },
+            cameras.clone(),
+            shared.clone(),
+            async move {
+                let _ = shutdown_rx.changed().await;
+            },
+        )
+    });
+    let raw_server = raw_listener.map(|(port, listener)| {
+        info!("sending raw frames on port {port}");
+        let mut shutdown_rx = shutdown_rx.clone();
+        tokio::spawn(tcp::serve(listener, frames.clone(), async move {
+            let _ = shutdown_rx.changed().await;
+        }))
     });
-    let frames = Arc::new(Frames::new(video));
-    http::serve(args.port, paths, frames).await?;
+    // If any of them fails, stop.
+    let servers = futures::future::try_join_all(servers);
+    tokio::pin!(servers);
+    let stop = async {
+        tokio::select! {
+            () = exit.notified() => (),
+            Some(()) = interrupt.recv() => info!("got SIGINT"),
+            Some(()) = terminate.recv() => info!("got SIGTERM"),
+        }
+    };
+    let failed = tokio::select! {
+        result = &mut servers => Some(result),
+        () = stop => None,
+    };
+    let result = match failed {
+        Some(result) => result.map(drop),
+        None => {
+            // Shutting down can take a while; don't make another Ctrl-C wait for it.
+            tokio::spawn(async move {
+                tokio::select! {
+                    Some(()) = interrupt.recv() => (),
+                    Some(()) = terminate.recv() => (),
+                }
+                warn!("got another signal while shutting down; exiting now");
+                std::process::exit(1);
+            });
+            info!("shutting down HTTP server");
+            shutdown_tx.send_replace(());
+            servers.await.map(drop)
+        }
+    };
+    if let Some(task) = raw_server {
+        // If the HTTP server failed, nothing else has told this one to stop.
+        shutdown_tx.send_replace(());
+        if let Err(e) = task.await {
+            warn!("raw frame server failed: {e}");
+        }
+    }
+    // Dropping the service unregisters it.
+    drop(mdns);
+    frames.video().shutdown().await?;
+    for camera in cameras.values() {
+        camera.frames.video().shutdown().await?;
+    }
+    if let Some(dir) = hls_dir {
+        if let Err(e) = std::fs::remove_dir_all(&dir) {
+            warn!("failed to remove HLS directory {dir:?}: {e}");
+        }
+    }
+    result?;
+    if all_restarts.iter().any(|r| r.lock().unwrap().gave_up) {
+        bail!("gave up restarting the pipeline");
+    }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(max_attempts: u32) -> RestartPolicy {
+        RestartPolicy {
+            initial: Duration::from_secs(1),
+            max_delay: Duration::from_secs(30),
+            max_attempts,
+        }
+    }
+
+    #[test]
+    fn restart_delays_double_up_to_the_max() {
+        let delays: Vec<u64> = (1..=8).map(|n| policy(0).delay(n).as_secs()).collect();
+        assert_eq!(delays, [1, 2, 4, 8, 16, 30, 30, 30]);
+        assert_eq!(policy(0).delay(1000), Duration::from_secs(30));
+    }
+
+    #[test]
+    fn restart_schedules() {
+        assert_eq!(policy(3).schedule(), "1s, 2s, 4s, then give up");
+        assert_eq!(
+            policy(7).schedule(),
+            "1s, 2s, 4s, 8s, 16s, 30s, then 30s once more, then give up"
+        );
+        assert_eq!(
+            policy(10).schedule(),
+            "1s, 2s, 4s, 8s, 16s, 30s, then 30s 4 more times, then give up"
+        );
+        assert_eq!(
+            policy(0).schedule(),
+            "1s, 2s, 4s, 8s, 16s, 30s, then 30s forever"
+        );
+    }
+
+    #[test]
+    fn restarts_give_up_after_max_in_a_row() {
+        let mut restarts = AutoRestarts::default();
+        assert_eq!(restarts.next_attempt(2), Some(1));
+        restarts.last = Some(Instant::now());
+        assert_eq!(restarts.next_attempt(2), Some(2));
+        assert_eq!(restarts.next_attempt(2), None);
+    }
+
+    #[test]
+    fn restarts_that_worked_for_a_while_start_the_count_over() {
+        let mut restarts = AutoRestarts {
+            count: 2,
+            last: Instant::now().checked_sub(AUTO_RESTART_RESET * 2),
+            ..AutoRestarts::default()
+        };
+        // The limit was reached long ago, but the pipeline has worked since.
+        assert_eq!(restarts.next_attempt(2), Some(1));
+    }
+
+    #[test]
+    fn restarts_without_a_limit_keep_going() {
+        let mut restarts = AutoRestarts {
+            count: 1000,
+            last: Some(Instant::now()),
+            ..AutoRestarts::default()
+        };
+        assert_eq!(restarts.next_attempt(0), Some(1001));
+    }
+}
@@ -0,0 +1,63 @@
+use std::ffi::CString;
+
+use anyhow::{bail, Context};
+
+/// Switch to running as the given user and/or group.
+///
+/// If only a user is given, their primary group is used. The user's supplementary groups are
+/// kept, so membership in e.g. the "video" group still works.
+pub fn drop_privileges(user: Option<&str>, group: Option<&str>) -> anyhow::Result<()> {
+    let passwd = match user {
+        Some(name) => {
+            let cname = CString::new(name).context("invalid user name")?;
+            let pw = unsafe { libc::getpwnam(cname.as_ptr()) };
+            if pw.is_null() {
+                bail!("no such user {name:?}");
+            }
+            Some((cname, unsafe { (*pw).pw_uid }, unsafe { (*pw).pw_gid }))
+        }
+        None => None,
+    };
+
+    let gid = match group {
+        Some(name) => {
+            let cname = CString::new(name).context("invalid group name")?;
+            let gr = unsafe { libc::getgrnam(cname.as_ptr()) };
+            if gr.is_null() {
+                bail!("no such group {name:?}");
+            }
+            Some(unsafe { (*gr).gr_gid })
+        }
+        None => passwd.as_ref().map(|(_, _, gid)| *gid),
+    };
+
+    // Groups have to be changed first, while we still have the privileges to do so.
+    if let Some(gid) = gid {
+        let result = match &passwd {
+            Some((cname, _, _)) => unsafe { libc::initgroups(cname.as_ptr(), gid) },
+            None => unsafe { libc::setgroups(1, &gid) },
+        };
+        if result != 0 {
+            return Err(std::io::Error::last_os_error()).context("failed to set groups");
+        }
+        if unsafe { libc::setgid(gid) } != 0 {
+            return Err(std::io::Error::last_os_error())
+                .with_context(|| format!("failed to set group ID {gid}"));
+        }
+    }
+
+    if let Some((cname, uid, _)) = &passwd {
+        if unsafe { libc::setuid(*uid) } != 0 {
+            return Err(std::io::Error::last_os_error()).with_context(|| {
+                format!("failed to switch to user {:?}", cname.to_string_lossy())
+            });
+        }
+    }
+
+    info!(
+        "now running as uid {}, gid {}",
+        unsafe { libc::getuid() },
+        unsafe { libc::getgid() }
+    );
+    Ok(())
+}
@@ -0,0 +1,104 @@
+use std::str::FromStr;
+
+use anyhow::bail;
+use bytes::{BufMut, Bytes, BytesMut};
+
+/// An uncompressed frame of 8-bit RGB pixels.
+#[derive(Debug, Clone)]
+pub struct RawFrame {
+    pub width: usize,
+    pub height: usize,
+    /// Bytes from the start of one row to the start of the next. Rows may be padded.
+    pub stride: usize,
+    pub data: Bytes,
+}
+
+impl RawFrame {
+    fn rows(&self) -> impl DoubleEndedIterator<Item = &[u8]> {
+        let row_bytes = self.width * 3;
+        self.data
+            .chunks(self.stride)
+            .take(self.height)
+            .map(move |row| &row[..row_bytes])
+    }
+}
+
+/// Uncompressed image formats that snapshots can be served in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RawFormat {
+    Ppm,
+    Bmp,
+}
+
+impl RawFormat {
+    pub fn mime_type(self) -> &'static str {
+        match self {
+            RawFormat::Ppm => "image/x-portable-pixmap",
+            RawFormat::Bmp => "image/bmp",
+        }
+    }
+
+    pub fn encode(self, frame: &RawFrame) -> Bytes {
+        match self {
+            RawFormat::Ppm => encode_ppm(frame),
+            RawFormat::Bmp => encode_bmp(frame),
+        }
+    }
+}
+
+impl FromStr for RawFormat {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "ppm" => RawFormat::Ppm,
+            "bmp" => RawFormat::Bmp,
+            _ => bail!("format must be one of jpeg, datauri, ppm, or bmp"),
+        })
+    }
+}
+
+/// Binary ("P6") PPM: a short text header, then the RGB rows top to bottom.
+fn encode_ppm(frame: &RawFrame) -> Bytes {
+    let header = format!("P6\n{} {}\n255\n", frame.width, frame.height);
+    let mut out = BytesMut::with_capacity(header.len() + frame.width * frame.height * 3);
+    out.put_slice(header.as_bytes());
+    for row in frame.rows() {
+        out.put_slice(row);
+    }
+    out.freeze()
+}
+
+/// 24-bit BMP: BGR rows from bottom to top, each padded to a multiple of 4 bytes.
+fn encode_bmp(frame: &RawFrame) -> Bytes {
+    const HEADER_BYTES: usize = 14 + 40;
+    let row_bytes = frame.width * 3;
+    let padding = (4 - row_bytes % 4) % 4;
+    let image_bytes = (row_bytes + padding) * frame.height;
+
+    let mut out = BytesMut::with_capacity(HEADER_BYTES + image_bytes);
+    // File header.
+    out.put_slice(b"BM");
+    out.put_u32_le((HEADER_BYTES + image_bytes) as u32);
+    out.put_u32_le(0);
+    out.put_u32_le(HEADER_BYTES as u32);
+    // BITMAPINFOHEADER.
+    out.put_u32_le(40);
+    out.put_i32_le(frame.width as i32);
+    out.put_i32_le(frame.height as i32);
+    out.put_u16_le(1); // planes
+    out.put_u16_le(24); // bits per pixel
+    out.put_u32_le(0); // no compression
+    out.put_u32_le(image_bytes as u32);
+    out.put_i32_le(2835); // 72 DPI, in pixels per meter
+    out.put_i32_le(2835);
+    out.put_u32_le(0); // palette colors
+    out.put_u32_le(0); // important colors
+
+    for row in frame.rows().rev() {
+        for px in row.chunks(3) {
+            out.put_slice(&[px[2], px[1], px[0]]);
+        }
+        out.put_bytes(0, padding);
+    }
+    out.freeze()
+}
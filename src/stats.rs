@@ -0,0 +1,90 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// How far back frame sizes are remembered, for computing averages.
+const HISTORY: Duration = Duration::from_secs(10);
+
+/// Frame rate and size, averaged over some window of time.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Throughput {
+    pub frames_per_sec: f64,
+    pub bytes_per_frame: f64,
+    pub bits_per_sec: f64,
+    /// Average time from capture to encoded frame, if known.
+    pub latency: Option<Duration>,
+}
+
+/// A snapshot of the statistics about frames that have been captured.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Stats {
+    pub total_frames: u64,
+    pub total_bytes: u64,
+    pub last_frame_bytes: usize,
+    /// Over the last second.
+    pub current: Throughput,
+    /// Over the last ten seconds.
+    pub average: Throughput,
+}
+
+#[derive(Debug, Default)]
+pub struct FrameStats {
+    total_frames: u64,
+    total_bytes: u64,
+    recent: VecDeque<(Instant, usize, Option<Duration>)>,
+}
+
+impl FrameStats {
+    pub fn record(&mut self, size: usize, latency: Option<Duration>) {
+        let now = Instant::now();
+        self.total_frames += 1;
+        self.total_bytes += size as u64;
+        self.recent.push_back((now, size, latency));
+        while let Some((t, _, _)) = self.recent.front() {
+            if now.duration_since(*t) <= HISTORY {
+                break;
+            }
+            self.recent.pop_front();
+        }
+    }
+
+    pub fn get(&self) -> Stats {
+        Stats {
+            total_frames: self.total_frames,
+            total_bytes: self.total_bytes,
+            last_frame_bytes: self.recent.back().map(|(_, size, _)| *size).unwrap_or(0),
+            current: self.throughput(Duration::from_secs(1)),
+            average: self.throughput(HISTORY),
+        }
+    }
+
+    fn throughput(&self, window: Duration) -> Throughput {
+        let now = Instant::now();
+        let mut frames = 0usize;
+        let mut bytes = 0usize;
+        let mut latencies = 0u32;
+        let mut latency_total = Duration::ZERO;
+        for (_, size, latency) in self
+            .recent
+            .iter()
+            .rev()
+            .take_while(|(t, _, _)| now.duration_since(*t) <= window)
+        {
+            frames += 1;
+            bytes += size;
+            if let Some(latency) = latency {
+                latencies += 1;
+                latency_total += *latency;
+            }
+        }
+        if frames == 0 {
+            return Throughput::default();
+        }
+        let secs = window.as_secs_f64();
+        Throughput {
+            frames_per_sec: frames as f64 / secs,
+            bytes_per_frame: bytes as f64 / frames as f64,
+            bits_per_sec: (bytes * 8) as f64 / secs,
+            latency: (latencies > 0).then(|| latency_total / latencies),
+        }
+    }
+}
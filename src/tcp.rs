@@ -0,0 +1,65 @@
+use std::future::Future;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+
+use anyhow::Context;
+use futures::StreamExt;
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinSet;
+
+use crate::frames::Frames;
+
+/// Open the listening socket for sending raw frames.
+pub async fn bind(ip: IpAddr, port: u16) -> anyhow::Result<TcpListener> {
+    let addr = SocketAddr::from((ip, port));
+    TcpListener::bind(addr)
+        .await
+        .with_context(|| format!("failed to bind to {addr}"))
+}
+
+/// Send frames to everyone who connects, until `shutdown` completes. Each frame is its length
+/// as a 4-byte big-endian number, then its data; there's nothing else on the connection, and
+/// anything the client sends is ignored.
+pub async fn serve(listener: TcpListener, frames: Arc<Frames>, shutdown: impl Future<Output = ()>) {
+    let mut clients = JoinSet::new();
+    tokio::pin!(shutdown);
+    loop {
+        tokio::select! {
+            result = listener.accept() => match result {
+                Ok((conn, remote)) => {
+                    info!("raw frame client connected from {remote}");
+                    clients.spawn(send_frames(conn, remote, frames.clone()));
+                }
+                Err(e) => warn!("failed to accept raw frame client: {e}"),
+            },
+            // Reap finished clients as they go, so the set doesn't grow forever.
+            Some(_) = clients.join_next(), if !clients.is_empty() => (),
+            () = &mut shutdown => break,
+        }
+    }
+    clients.shutdown().await;
+}
+
+async fn send_frames(mut conn: TcpStream, remote: SocketAddr, frames: Arc<Frames>) {
+    // Frames are written whole, so there's no use waiting to fill up packets.
+    if let Err(e) = conn.set_nodelay(true) {
+        debug!("failed to set TCP_NODELAY for {remote}: {e}");
+    }
+    let mut stream = frames.stream().await.for_client(remote.ip());
+    while let Some(frame) = stream.next().await {
+        let Ok(len) = u32::try_from(frame.data.len()) else {
+            warn!("frame of {} bytes is too big to send raw", frame.data.len());
+            continue;
+        };
+        let result = async {
+            conn.write_u32(len).await?;
+            conn.write_all(&frame.data).await
+        }
+        .await;
+        if let Err(e) = result {
+            debug!("stopped sending raw frames to {remote}: {e}");
+            break;
+        }
+    }
+}
@@ -1,86 +1,1420 @@
-use std::sync::Arc;
+use std::fs::File;
+use std::future::Future;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
 
-use anyhow::Context;
+use anyhow::{bail, Context};
+use bytes::Bytes;
 use futures::StreamExt;
+use gstreamer::glib::ParamFlags;
 use gstreamer::prelude::*;
 use gstreamer::{
-    Bin, BufferRef, Caps, DebugLevel, Element, ElementFactory, Message, Pipeline, Sample, State,
+    Bin, Buffer, BufferRef, Caps, ClockTime, ClockType, DebugLevel, DeviceMonitor, Element,
+    ElementFactory, Format, Fraction, GhostPad, IntRange, Message, MessageView, Pipeline,
+    ResourceError, Sample, SeekFlags, State, StateChangeSuccess, StreamError, SystemClock,
 };
-use gstreamer_app::AppSink;
+use gstreamer_app::{AppSink, AppSrc};
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
 
-#[derive(Debug, Clone)]
+use crate::raw::RawFrame;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum VideoSource {
     V4L(String),
     Test(String),
+    /// A test pattern with the running time drawn on it in milliseconds, for measuring latency.
+    TestClock,
+    /// A video file, decoded with whatever plugins are available.
+    File(String),
+    /// A camera handled by libcamera (e.g. a Raspberry Pi CSI camera), optionally picked by name.
+    LibCamera(Option<String>),
+}
+
+/// Rotation to apply to frames, in degrees clockwise.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum Rotation {
+    #[default]
+    None,
+    Clockwise90,
+    Rotate180,
+    Clockwise270,
+}
+
+impl Rotation {
+    fn degrees(self) -> u32 {
+        match self {
+            Rotation::None => 0,
+            Rotation::Clockwise90 => 90,
+            Rotation::Rotate180 => 180,
+            Rotation::Clockwise270 => 270,
+        }
+    }
+
+    fn from_degrees(degrees: u32) -> Self {
+        match degrees % 360 {
+            90 => Rotation::Clockwise90,
+            180 => Rotation::Rotate180,
+            270 => Rotation::Clockwise270,
+            _ => Rotation::None,
+        }
+    }
+
+    /// The name of the corresponding `videoflip` "method" property value.
+    fn videoflip_method(self) -> &'static str {
+        match self {
+            Rotation::None => "none",
+            Rotation::Clockwise90 => "clockwise",
+            Rotation::Rotate180 => "rotate-180",
+            Rotation::Clockwise270 => "counterclockwise",
+        }
+    }
+}
+
+/// Rotating by one and then the other.
+impl std::ops::Add for Rotation {
+    type Output = Self;
+    fn add(self, other: Self) -> Self {
+        Self::from_degrees(self.degrees() + other.degrees())
+    }
+}
+
+/// The rotation that, after `other`, ends up the same as `self`.
+impl std::ops::Sub for Rotation {
+    type Output = Self;
+    fn sub(self, other: Self) -> Self {
+        Self::from_degrees(360 + self.degrees() - other.degrees())
+    }
+}
+
+impl FromStr for Rotation {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "0" => Rotation::None,
+            "90" => Rotation::Clockwise90,
+            "180" => Rotation::Rotate180,
+            "270" => Rotation::Clockwise270,
+            _ => bail!("rotation must be one of 0, 90, 180, or 270"),
+        })
+    }
+}
+
+/// Corner of the video to draw something in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Corner {
+    TopLeft,
+    TopRight,
+    BottomLeft,
+    BottomRight,
+}
+
+impl Corner {
+    /// The overlay elements' "valignment" and "halignment" property values for this corner.
+    fn alignment(self) -> (&'static str, &'static str) {
+        match self {
+            Corner::TopLeft => ("top", "left"),
+            Corner::TopRight => ("top", "right"),
+            Corner::BottomLeft => ("bottom", "left"),
+            Corner::BottomRight => ("bottom", "right"),
+        }
+    }
+}
+
+impl FromStr for Corner {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "top-left" => Corner::TopLeft,
+            "top-right" => Corner::TopRight,
+            "bottom-left" => Corner::BottomLeft,
+            "bottom-right" => Corner::BottomRight,
+            _ => bail!("corner must be one of top-left, top-right, bottom-left, or bottom-right"),
+        })
+    }
+}
+
+/// Mirroring to apply to frames.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum Flip {
+    #[default]
+    None,
+    /// Left to right.
+    Horizontal,
+    /// Top to bottom.
+    Vertical,
+}
+
+impl Flip {
+    /// The name of the corresponding `videoflip` "method" property value.
+    fn videoflip_method(self) -> &'static str {
+        match self {
+            Flip::None => "none",
+            Flip::Horizontal => "horizontal-flip",
+            Flip::Vertical => "vertical-flip",
+        }
+    }
+}
+
+impl FromStr for Flip {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "none" => Flip::None,
+            "horizontal" => Flip::Horizontal,
+            "vertical" => Flip::Vertical,
+            _ => bail!("flip must be one of none, horizontal, or vertical"),
+        })
+    }
+}
+
+/// What the recording does when the disk can't keep up with the video.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum RecordOverflow {
+    /// Leave frames out of the recording, so it stays real-time and streams don't wait for it.
+    #[default]
+    Drop,
+    /// Wait for the disk, so the recording has every frame, holding up streams meanwhile.
+    Block,
+}
+
+impl FromStr for RecordOverflow {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "drop" => RecordOverflow::Drop,
+            "block" => RecordOverflow::Block,
+            _ => bail!("must be one of drop or block"),
+        })
+    }
+}
+
+/// Changes to make to a JPEG frame by re-encoding it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct Reencode {
+    pub rotation: Rotation,
+    /// Scale to this width, keeping the aspect ratio unless `height` is given too.
+    pub width: Option<u32>,
+    /// Scale to this height, keeping the aspect ratio unless `width` is given too.
+    pub height: Option<u32>,
+    /// JPEG quality, from 0 to 100.
+    pub quality: Option<i32>,
+}
+
+impl Reencode {
+    /// Whether this leaves the frame as it is, so re-encoding can be skipped.
+    pub fn is_noop(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+/// Which encoding the video is delivered in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Codec {
+    Mjpeg,
+    H264,
+}
+
+impl Codec {
+    /// The MIME type of a single encoded frame.
+    pub fn mime_type(self) -> &'static str {
+        match self {
+            Codec::Mjpeg => "image/jpeg",
+            Codec::H264 => "video/x-h264",
+        }
+    }
+
+    /// The encoder element used when none is specified.
+    fn default_encoder(self) -> &'static str {
+        match self {
+            Codec::Mjpeg => "jpegenc",
+            Codec::H264 => "x264enc",
+        }
+    }
+
+    fn sink_caps(self) -> gstreamer::caps::Builder<gstreamer::caps::NoFeature> {
+        let b = Caps::builder(self.mime_type());
+        match self {
+            Codec::Mjpeg => b,
+            // One whole access unit per buffer, each with its own start codes, so every frame
+            // sent out can be handled by itself.
+            Codec::H264 => b
+                .field("stream-format", "byte-stream")
+                .field("alignment", "au"),
+        }
+    }
+}
+
+impl FromStr for Codec {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "mjpeg" => Codec::Mjpeg,
+            "h264" => Codec::H264,
+            _ => bail!("codec must be either \"mjpeg\" or \"h264\""),
+        })
+    }
 }
 
+/// How much color resolution to keep relative to brightness, as a YUV `J:a:b` ratio.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChromaSubsampling {
+    /// Half the resolution both horizontally and vertically.
+    S420,
+    /// Half the resolution horizontally.
+    S422,
+    /// Full resolution.
+    S444,
+}
+
+impl ChromaSubsampling {
+    /// The raw video format with this subsampling to give the encoder.
+    fn format(self) -> &'static str {
+        match self {
+            ChromaSubsampling::S420 => "I420",
+            ChromaSubsampling::S422 => "Y42B",
+            ChromaSubsampling::S444 => "Y444",
+        }
+    }
+}
+
+impl FromStr for ChromaSubsampling {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "420" => ChromaSubsampling::S420,
+            "422" => ChromaSubsampling::S422,
+            "444" => ChromaSubsampling::S444,
+            _ => bail!("must be one of 420, 422, or 444"),
+        })
+    }
+}
+
+/// Color adjustments applied by the `videobalance` element.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Balance {
+    pub brightness: f64,
+    pub contrast: f64,
+    pub saturation: f64,
+    pub hue: f64,
+}
+
+impl Balance {
+    /// The names of the adjustments, with the range of values `videobalance` accepts for each.
+    pub const RANGES: [(&'static str, f64, f64); 4] = [
+        ("brightness", -1., 1.),
+        ("contrast", 0., 2.),
+        ("saturation", 0., 2.),
+        ("hue", -1., 1.),
+    ];
+
+    /// Check that the named adjustment exists and the value is in its range.
+    pub fn check(name: &str, value: f64) -> anyhow::Result<()> {
+        let Some((_, min, max)) = Self::RANGES.iter().find(|(n, _, _)| *n == name) else {
+            bail!("unknown color adjustment {name:?}");
+        };
+        if !(*min..=*max).contains(&value) {
+            bail!("{name} must be between {min} and {max}");
+        }
+        Ok(())
+    }
+}
+
+/// What the encoded video came out as.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct OutputFormat {
+    pub width: i32,
+    pub height: i32,
+    /// Frames per second, if the video has a fixed rate.
+    pub framerate: Option<f64>,
+}
+
+impl std::fmt::Display for OutputFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}x{}", self.width, self.height)?;
+        match self.framerate {
+            Some(fps) => write!(f, " at {fps:.2} fps"),
+            None => write!(f, " at a variable frame rate"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Config {
+    pub source: VideoSource,
+    /// Extra properties to set on v4l2src, as names and values in gst-launch syntax.
+    pub v4l2_props: Vec<(String, String)>,
+    /// Output width and height. If unset, use whatever the camera produces.
+    pub size: Option<(u32, u32)>,
+    /// Whether to scale the video to `size` if the camera can't produce it natively. If false,
+    /// the camera has to support `size` or the pipeline will fail to negotiate.
+    pub scale: bool,
+    /// Additional filter(s), in gst-launch syntax, to insert between the camera and encoder.
+    pub filter: Option<String>,
+    /// Mirror the video, before any filter.
+    pub flip: Flip,
+    /// Rotate the video, after any filter and before scaling it to `size`.
+    pub rotation: Rotation,
+    /// Most frames per second to encode. Extra frames from the camera are dropped before scaling
+    /// and encoding.
+    pub framerate: Option<u32>,
+    /// Add borders to make the video this shape (width to height), if `size` is known, instead
+    /// of stretching it.
+    pub aspect: Option<(u32, u32)>,
+    /// Color of the borders added for `aspect`, as a `videobox` "fill" value.
+    pub pad_color: String,
+    /// Convert the video to grayscale before encoding.
+    pub grayscale: bool,
+    pub codec: Codec,
+    /// Encoder element to use instead of the codec's default one (e.g. `v4l2h264enc`).
+    pub encoder: Option<String>,
+    /// Elements, in gst-launch syntax, to encode with instead of a single encoder element. The
+    /// last one has to output the codec's format.
+    pub encode_pipeline: Option<String>,
+    /// DCT method for jpegenc to use (`islow`, `ifast`, or `float`), if not its default.
+    pub jpeg_idct: Option<String>,
+    /// Encoder quality, from 0 to 100, if not its default.
+    pub quality: Option<u8>,
+    /// Convert the video to this chroma subsampling before encoding, instead of letting the
+    /// encoder pick.
+    pub chroma_subsampling: Option<ChromaSubsampling>,
+    /// Hand frames to the encoder through a queue of this many frames, so encoding runs in a
+    /// thread of its own.
+    pub encode_queue: Option<u32>,
+    /// Hand encoded frames to the appsink through a queue of this many frames.
+    pub sink_queue: Option<u32>,
+    /// When stopping, how long to wait for an end-of-stream to make it through the pipeline, so
+    /// sinks can finish what they're writing. Zero stops right away.
+    pub eos_timeout: Duration,
+    /// Fixed latency for the pipeline to use, instead of what its elements ask for.
+    pub latency: Option<Duration>,
+    /// Run the pipeline on the system's wall clock, so several instances on machines synced by
+    /// NTP agree on what time it is.
+    pub system_clock: bool,
+    /// If set, overlay the contents of this file on the video, updating whenever it changes.
+    pub caption_file: Option<PathBuf>,
+    /// If set, draw the local date and time in this corner of the video.
+    pub timestamp_overlay: Option<Corner>,
+    /// strftime-style format for `timestamp_overlay`.
+    pub timestamp_format: String,
+    /// Also show the video in a window on the local display.
+    pub preview: bool,
+    /// Whether the appsink delivers frames in step with the clock. Turning this off lets a file
+    /// source go as fast as it can be decoded.
+    pub sync: bool,
+    /// Keep uncompressed RGB frames available, for raw snapshots.
+    pub raw_frames: bool,
+    /// Keep small grayscale copies of recent frames, for measuring motion.
+    pub motion: bool,
+    /// Also encode a JPEG copy of the video scaled down to this width, for thumbnails.
+    pub thumbnail_width: Option<u32>,
+    /// If set, also encode the video as HLS and write the playlist and segments into this
+    /// directory.
+    pub hls_dir: Option<PathBuf>,
+    /// If set, also save the encoded video to this file, as AVI.
+    pub record: Option<PathBuf>,
+    /// What to do when the recording falls behind.
+    pub record_overflow: RecordOverflow,
+    /// Send the camera's own JPEG frames straight to the appsink, without decoding and
+    /// re-encoding them, when nothing else in the configuration needs raw video.
+    pub passthrough: bool,
+}
+
+impl Config {
+    /// What, if anything, in this configuration needs raw video, and so rules out `passthrough`.
+    fn passthrough_blocker(&self) -> Option<&'static str> {
+        Some(if !matches!(self.source, VideoSource::V4L(_)) {
+            "the source isn't a V4L camera"
+        } else if self.codec != Codec::Mjpeg {
+            "the codec isn't mjpeg"
+        } else if self.filter.is_some() {
+            "--filter needs raw video"
+        } else if self.flip != Flip::None {
+            "--flip needs raw video"
+        } else if self.rotation != Rotation::None {
+            "rotating needs raw video"
+        } else if self.framerate.is_some() {
+            "--framerate needs raw video"
+        } else if self.aspect.is_some() {
+            "--aspect needs raw video"
+        } else if self.grayscale {
+            "--grayscale needs raw video"
+        } else if self.caption_file.is_some() {
+            "captions need raw video"
+        } else if self.timestamp_overlay.is_some() {
+            "--timestamp-overlay needs raw video"
+        } else if self.chroma_subsampling.is_some() {
+            "--chroma-subsampling needs re-encoding"
+        } else if self.encoder.is_some() || self.encode_pipeline.is_some() {
+            "a custom encoder is set"
+        } else if self.quality.is_some() || self.jpeg_idct.is_some() {
+            "encoder settings are set"
+        } else if self.hls_dir.is_some()
+            || self.preview
+            || self.raw_frames
+            || self.motion
+            || self.thumbnail_width.is_some()
+        {
+            "HLS, previews, raw snapshots, motion, and thumbnails need raw video"
+        } else {
+            return None;
+        })
+    }
+
+    /// Names of the elements this configuration needs.
+    fn required_elements(&self) -> Vec<&str> {
+        let mut elts = vec![
+            match &self.source {
+                VideoSource::V4L(_) => "v4l2src",
+                VideoSource::Test(_) | VideoSource::TestClock => "videotestsrc",
+                VideoSource::File(_) => "decodebin",
+                VideoSource::LibCamera(_) => "libcamerasrc",
+            },
+            "videoconvert",
+            "videobalance",
+            "capsfilter",
+            "appsink",
+        ];
+        match self.source {
+            VideoSource::File(_) => elts.push("filesrc"),
+            VideoSource::TestClock => elts.push("timeoverlay"),
+            _ => (),
+        }
+        if self.size.is_some() && self.scale {
+            elts.push("videoscale");
+        }
+        if self.rotation != Rotation::None || self.flip != Flip::None {
+            elts.push("videoflip");
+        }
+        if self.framerate.is_some() {
+            elts.push("videorate");
+        }
+        if self.aspect.is_some() {
+            elts.push("videobox");
+        }
+        if self.caption_file.is_some() {
+            elts.push("textoverlay");
+        }
+        if self.timestamp_overlay.is_some() {
+            elts.push("clockoverlay");
+        }
+        if self.hls_dir.is_some() {
+            elts.extend(["x264enc", "h264parse", "hlssink2"]);
+        }
+        if self.preview {
+            elts.push("autovideosink");
+        }
+        if self.motion {
+            elts.push("videoscale");
+        }
+        if self.thumbnail_width.is_some() {
+            elts.extend(["videoscale", "jpegenc"]);
+        }
+        if self.hls_dir.is_some()
+            || self.preview
+            || self.raw_frames
+            || self.motion
+            || self.thumbnail_width.is_some()
+        {
+            elts.extend(["tee", "queue"]);
+        }
+        if self.encode_queue.is_some() || self.sink_queue.is_some() {
+            elts.push("queue");
+        }
+        if self.record.is_some() {
+            elts.extend(["tee", "queue", "avimux", "filesink"]);
+        }
+        // A missing custom encoder falls back to the default one.
+        match self.encoder.as_deref() {
+            _ if self.encode_pipeline.is_some() => (),
+            Some(name) if ElementFactory::find(name).is_some() => (),
+            _ => elts.push(self.codec.default_encoder()),
+        }
+        elts
+    }
+}
+
+/// Make a bin from a gst-launch description, to encode video into `caps`. Its unlinked pads get
+/// ghosted, and have to take raw video in and put `caps` out.
+fn encode_bin(desc: &str, caps: &Caps) -> anyhow::Result<Element> {
+    let bin = gstreamer::parse_bin_from_description(desc, true)
+        .with_context(|| format!("failed to create elements described by {desc:?}"))?;
+    bin.set_property("name", "encode_pipeline");
+    let Some(sink) = bin.static_pad("sink") else {
+        bail!("encoder pipeline {desc:?} has no unlinked sink pad for its input");
+    };
+    let accepted = sink.query_caps(None);
+    if !accepted.is_any() && !accepted.iter().any(|s| s.name() == "video/x-raw") {
+        bail!("encoder pipeline {desc:?} doesn't take raw video (only {accepted})");
+    }
+    let Some(src) = bin.static_pad("src") else {
+        bail!("encoder pipeline {desc:?} has no unlinked src pad for its output");
+    };
+    let produced = src.query_caps(None);
+    if !produced.can_intersect(caps) {
+        bail!("encoder pipeline {desc:?} must output {caps}, but makes {produced}");
+    }
+    Ok(bin.upcast())
+}
+
+/// The package that usually provides an element, for suggesting what to install.
+fn element_package(element: &str) -> &'static str {
+    match element {
+        "filesrc" | "filesink" | "capsfilter" | "tee" | "queue" => "gstreamer (core elements)",
+        "videotestsrc" | "decodebin" | "videoconvert" | "videoscale" | "videobalance"
+        | "videorate" | "textoverlay" | "timeoverlay" | "clockoverlay" | "appsink" | "appsrc" => {
+            "gst-plugins-base"
+        }
+        "v4l2src" | "jpegenc" | "jpegdec" | "videoflip" | "autovideosink" | "avimux" => {
+            "gst-plugins-good"
+        }
+        "h264parse" | "hlssink2" => "gst-plugins-bad",
+        "x264enc" => "gst-plugins-ugly",
+        "libcamerasrc" => "libcamera's GStreamer plugin",
+        _ => "the package that provides it",
+    }
+}
+
+/// Size that frames are shrunk to for measuring motion.
+const MOTION_WIDTH: usize = 64;
+const MOTION_HEIGHT: usize = 48;
+
+/// Least time to give an end-of-stream to finish a recording when stopping, even if the
+/// configured timeout is shorter; without it, the file is left without an index.
+const RECORD_EOS_TIMEOUT: Duration = Duration::from_secs(2);
+
 pub struct Video {
     pipeline: Pipeline,
+    codec: Codec,
     appsink: AppSink,
+    /// The color adjustments, unless the camera's frames are passed through.
+    balance: Option<Element>,
+    /// The encoder, unless the camera's frames are passed through.
+    encoder: Option<Element>,
+    caption: Option<Element>,
+    /// The file the caption is read from.
+    caption_file: Mutex<Option<PathBuf>>,
+    /// Holds the latest uncompressed frame, if raw frames are enabled.
+    raw_appsink: Option<AppSink>,
+    /// Holds the two latest small grayscale frames, if motion measurement is enabled.
+    motion_appsink: Option<AppSink>,
+    /// Where thumbnail JPEGs come out, if they're enabled.
+    thumb_appsink: Option<AppSink>,
+    cancel: CancellationToken,
+    tasks: Mutex<Vec<JoinHandle<()>>>,
+    /// Whether to keep the device open when stopped, instead of fully closing it.
+    hold_open: AtomicBool,
+    /// The last error reported by the pipeline since it was started.
+    last_error: Mutex<Option<String>>,
+    /// Whether the pipeline has failed to negotiate formats since it was started, which no
+    /// amount of retrying will fix.
+    not_negotiated: AtomicBool,
+    eos_timeout: Duration,
+    /// Whether the encoded video is also being saved to a file.
+    recording: bool,
+    /// Frames left out of the recording because it fell behind.
+    record_drops: Arc<AtomicU64>,
+    /// Set while stopping, when an end-of-stream is expected and isn't the video ending.
+    stopping: AtomicBool,
+    /// Notified when the end-of-stream sent while stopping comes out the other end.
+    stopped: tokio::sync::Notify,
+    /// Whether the pipeline has reported an error since it was started.
+    failed: tokio::sync::watch::Sender<bool>,
 }
 
 impl Video {
     pub fn gst_init() -> anyhow::Result<()> {
         gstreamer::init().context("failed to init gstreamer")?;
+        info!("using {}", gstreamer::version_string());
         gstreamer::debug_set_active(true);
         gstreamer::debug_set_colored(true);
         gstreamer::debug_set_default_threshold(DebugLevel::Warning);
         Ok(())
     }
 
-    pub fn new(source: VideoSource, size: Option<(u32, u32)>, filter: Option<&str>) -> anyhow::Result<Self> {
+    /// Check that all the elements the configuration needs are installed, so a missing one is
+    /// reported clearly up front instead of as a failure to build or link the pipeline.
+    pub fn check_elements(config: &Config) -> anyhow::Result<()> {
+        let missing: Vec<String> = config
+            .required_elements()
+            .into_iter()
+            .filter(|name| ElementFactory::find(name).is_none())
+            .map(|name| format!("{name} (install {})", element_package(name)))
+            .collect();
+        if !missing.is_empty() {
+            bail!("missing GStreamer plugins: {}", missing.join(", "));
+        }
+        Ok(())
+    }
+
+    pub fn new(config: &Config) -> anyhow::Result<Self> {
         let pipeline = Pipeline::new(Some("pipeline"));
-        let mut elts = vec![];
+        if let Some(latency) = config.latency {
+            pipeline.set_latency(ClockTime::from_nseconds(latency.as_nanos() as u64));
+        }
+        if config.system_clock {
+            let clock = SystemClock::obtain();
+            clock.set_property("clock-type", ClockType::Realtime);
+            pipeline.use_clock(Some(&clock));
+        }
+        let mut elts: Vec<Element> = vec![];
 
-        let camera = match source {
-            VideoSource::V4L(device) => ElementFactory::make("v4l2src")
+        let camera = match &config.source {
+            VideoSource::V4L(device) => {
+                let src = ElementFactory::make("v4l2src")
+                    .name("camera")
+                    .property_from_str("device", &resolve_device(device))
+                    .build()
+                    .context("failed to make v4l2src")?;
+                for (name, value) in &config.v4l2_props {
+                    set_property_checked(&src, name, value)?;
+                }
+                src
+            }
+            VideoSource::Test(pattern) => ElementFactory::make("videotestsrc")
                 .name("camera")
-                .property_from_str("device", &device)
+                .property_from_str("pattern", pattern)
                 .build()
-                .context("failed to make v4l2src")?,
-            VideoSource::Test(pattern) => ElementFactory::make("videotestsrc")
+                .context("failed to make videotestsrc")?,
+            VideoSource::TestClock => ElementFactory::make("videotestsrc")
                 .name("camera")
-                .property_from_str("pattern", &pattern)
+                .property_from_str("pattern", "black")
+                // Live, so frames are made when they're due rather than as fast as possible,
+                // like a real camera.
+                .property("is-live", true)
                 .build()
                 .context("failed to make videotestsrc")?,
+            VideoSource::LibCamera(name) => {
+                let mut src = ElementFactory::make("libcamerasrc").name("camera");
+                if let Some(name) = name {
+                    src = src.property("camera-name", name);
+                }
+                src.build().context("failed to make libcamerasrc")?
+            }
+            VideoSource::File(path) => {
+                let filesrc = ElementFactory::make("filesrc")
+                    .property("location", path)
+                    .build()
+                    .context("failed to make filesrc")?;
+                decoding_bin(filesrc, "decodebin")?.upcast()
+            }
         };
-        elts.push(&camera);
+        if config.passthrough {
+            match config.passthrough_blocker() {
+                Some(why) => warn!("can't pass the camera's JPEG through ({why}); re-encoding"),
+                None => {
+                    let mut wanted = Caps::builder("image/jpeg");
+                    if let Some((w, h)) = config.size {
+                        wanted = wanted
+                            .field("width", i32::try_from(w).context("width out of range")?)
+                            .field("height", i32::try_from(h).context("height out of range")?);
+                    }
+                    let wanted = wanted.build();
+                    match query_camera_caps(&camera) {
+                        Ok(caps) if caps.can_intersect(&wanted) => {
+                            return Self::passthrough(config, pipeline, camera, &wanted);
+                        }
+                        Ok(_) => warn!("the camera doesn't offer {wanted}; re-encoding"),
+                        Err(e) => warn!("couldn't ask the camera for JPEG: {e:#}; re-encoding"),
+                    }
+                }
+            }
+        }
+        elts.push(camera);
 
-        let filt: Bin;
-        if let Some(desc) = filter {
-            filt = gstreamer::parse_bin_from_description(desc, true)
+        if let VideoSource::LibCamera(_) = &config.source {
+            // libcamera's formats (e.g. NV12, or ones with odd strides) aren't always ones the
+            // rest of the pipeline accepts, so always give it a converter to negotiate with.
+            elts.push(make_element("videoconvert")?);
+        }
+
+        if let VideoSource::TestClock = &config.source {
+            elts.push(
+                ElementFactory::make("timeoverlay")
+                    .property_from_str("time-mode", "running-time")
+                    .property_from_str("halignment", "center")
+                    .property_from_str("valignment", "center")
+                    .property("font-desc", "Monospace 48")
+                    .build()
+                    .context("failed to make timeoverlay")?,
+            );
+        }
+
+        if config.flip != Flip::None {
+            // Before the filter, so anything it draws isn't mirrored.
+            elts.push(
+                ElementFactory::make("videoflip")
+                    .property_from_str("method", config.flip.videoflip_method())
+                    .build()
+                    .context("failed to make videoflip")?,
+            );
+        }
+
+        if let Some(desc) = &config.filter {
+            let filt = gstreamer::parse_bin_from_description(desc, true)
                 .with_context(|| format!("failed to create elements described by {desc:?}"))?;
-            elts.push(filt.upcast_ref());
+            // So linking errors can say it's the filter.
+            filt.set_property("name", "filter");
+            // Unlinked pads get ghosted onto the bin. Without both, it can't go in the middle of
+            // the pipeline, and linking would fail with a much less helpful error.
+            for (name, role) in [("sink", "input"), ("src", "output")] {
+                let Some(pad) = filt.static_pad(name) else {
+                    bail!("filter {desc:?} has no unlinked {name} pad for its video {role}");
+                };
+                let caps = pad.query_caps(None);
+                if !caps.is_any() && !caps.iter().any(|s| s.name().starts_with("video/")) {
+                    bail!("filter {desc:?} {name} pad doesn't handle video (only {caps})");
+                }
+            }
+            elts.push(filt.upcast());
+        }
+
+        if config.rotation != Rotation::None {
+            elts.push(
+                ElementFactory::make("videoflip")
+                    .property_from_str("method", config.rotation.videoflip_method())
+                    .build()
+                    .context("failed to make videoflip")?,
+            );
+        }
+
+        if let Some(fps) = config.framerate {
+            // Only dropping keeps a camera that's slower than this from having frames repeated.
+            elts.push(
+                ElementFactory::make("videorate")
+                    .property("drop-only", true)
+                    .build()
+                    .context("failed to make videorate")?,
+            );
+            let fps = i32::try_from(fps).context("framerate out of range")?;
+            let caps = Caps::builder("video/x-raw")
+                .field("framerate", Fraction::new(fps, 1))
+                .build();
+            elts.push(capsfilter(&caps)?);
+        }
+
+        let size = match config.size {
+            Some((w, h)) => Some((
+                i32::try_from(w).context("width out of range")?,
+                i32::try_from(h).context("height out of range")?,
+            )),
+            None => None,
+        };
+
+        if let (Some((w, h)), true) = (size, config.scale) {
+            // If the camera can produce this size natively, videoscale just passes it through.
+            elts.push(
+                ElementFactory::make("videoscale")
+                    .build()
+                    .context("failed to make videoscale")?,
+            );
+            let caps = Caps::builder("video/x-raw")
+                .field("width", w)
+                .field("height", h)
+                .build();
+            elts.push(capsfilter(&caps)?);
+        }
+
+        // With everything at the defaults, this just passes the video through.
+        let balance = make_element("videobalance")?;
+        elts.push(balance.clone());
+
+        if config.grayscale {
+            // jpegenc accepts GRAY8 directly and produces a single-component JPEG.
+            elts.push(
+                ElementFactory::make("videoconvert")
+                    .build()
+                    .context("failed to make videoconvert")?,
+            );
+            let caps = Caps::builder("video/x-raw")
+                .field("format", "GRAY8")
+                .build();
+            elts.push(capsfilter(&caps)?);
+        }
+
+        let caption = match &config.caption_file {
+            Some(path) => {
+                let overlay = ElementFactory::make("textoverlay")
+                    .property("text", read_caption(path).unwrap_or_default())
+                    .property_from_str("valignment", "top")
+                    .property_from_str("halignment", "left")
+                    .build()
+                    .context("failed to make textoverlay")?;
+                elts.push(overlay.clone());
+                Some(overlay)
+            }
+            None => None,
+        };
+
+        if let Some(corner) = config.timestamp_overlay {
+            // After scaling, so the text is the same size whatever the camera's resolution.
+            let (valign, halign) = corner.alignment();
+            elts.push(
+                ElementFactory::make("clockoverlay")
+                    .property("time-format", &config.timestamp_format)
+                    .property_from_str("valignment", valign)
+                    .property_from_str("halignment", halign)
+                    .property("shaded-background", true)
+                    .build()
+                    .context("failed to make clockoverlay")?,
+            );
         }
 
-        let enc = ElementFactory::make("jpegenc")
-            .build()
-            .context("failed to make jpegenc")?;
-        elts.push(&enc);
+        // Without a size, there's nothing to work out the borders from; main makes sure there's
+        // one, except while finding the camera's largest mode.
+        let padded = match (config.aspect, size) {
+            (Some(aspect), Some((w, h))) => {
+                let (pw, ph) = letterbox((w as u32, h as u32), aspect);
+                let (left, top) = ((pw - w as u32) / 2, (ph - h as u32) / 2);
+                // Negative values add borders, rather than cropping.
+                elts.push(
+                    ElementFactory::make("videobox")
+                        .property("left", -(left as i32))
+                        .property("right", -((pw - w as u32 - left) as i32))
+                        .property("top", -(top as i32))
+                        .property("bottom", -((ph - h as u32 - top) as i32))
+                        .property_from_str("fill", &config.pad_color)
+                        .build()
+                        .context("failed to make videobox")?,
+                );
+                Some((pw as i32, ph as i32))
+            }
+            _ => None,
+        };
+
+        // Branches that split off the raw video before the main encoder, each starting with a
+        // queue fed by a tee.
+        let mut raw_branches: Vec<Vec<Element>> = vec![];
+
+        if let Some(dir) = &config.hls_dir {
+            // hlssink2 does the MPEG-TS muxing itself.
+            let hlssink = ElementFactory::make("hlssink2")
+                .property("location", dir.join("segment%05d.ts").to_str())
+                .property("playlist-location", dir.join("playlist.m3u8").to_str())
+                .property("target-duration", 2u32)
+                .property("playlist-length", 5u32)
+                .property("max-files", 10u32)
+                .build()
+                .context("failed to make hlssink2")?;
+            raw_branches.push(vec![
+                make_element("videoconvert")?,
+                ElementFactory::make("x264enc")
+                    .property_from_str("tune", "zerolatency")
+                    .property("key-int-max", 30u32)
+                    .build()
+                    .context("failed to make x264enc")?,
+                make_element("h264parse")?,
+                hlssink,
+            ]);
+        }
+
+        if config.preview {
+            if std::env::var_os("DISPLAY").is_none()
+                && std::env::var_os("WAYLAND_DISPLAY").is_none()
+            {
+                bail!("no display for the preview; neither DISPLAY nor WAYLAND_DISPLAY is set");
+            }
+            raw_branches.push(vec![
+                make_element("videoconvert")?,
+                // Don't let a slow display hold up everything else.
+                ElementFactory::make("autovideosink")
+                    .property("sync", false)
+                    .build()
+                    .context("failed to make autovideosink")?,
+            ]);
+        }
+
+        let raw_appsink = if config.raw_frames {
+            // Only ever hold the newest frame, so this never holds up the rest of the pipeline.
+            let sink = AppSink::builder()
+                .caps(&Caps::builder("video/x-raw").field("format", "RGB").build())
+                .name("raw_appsink")
+                .max_buffers(1)
+                .drop(true)
+                .sync(false)
+                .build();
+            raw_branches.push(vec![make_element("videoconvert")?, sink.clone().upcast()]);
+            Some(sink)
+        } else {
+            None
+        };
+
+        let motion_appsink = if config.motion {
+            // Tiny, so comparing frames is cheap no matter the resolution. The aspect ratio
+            // doesn't matter for that.
+            let sink = AppSink::builder()
+                .caps(
+                    &Caps::builder("video/x-raw")
+                        .field("format", "GRAY8")
+                        .field("width", MOTION_WIDTH as i32)
+                        .field("height", MOTION_HEIGHT as i32)
+                        .build(),
+                )
+                .name("motion_appsink")
+                .max_buffers(2)
+                .drop(true)
+                .sync(false)
+                .build();
+            raw_branches.push(vec![
+                make_element("videoconvert")?,
+                make_element("videoscale")?,
+                sink.clone().upcast(),
+            ]);
+            Some(sink)
+        } else {
+            None
+        };
+
+        let thumb_appsink = if let Some(width) = config.thumbnail_width {
+            // Dropping rather than blocking, so the main video carries on when nobody's taking
+            // thumbnails.
+            let sink = AppSink::builder()
+                .caps(&Caps::builder("image/jpeg").build())
+                .name("thumb_appsink")
+                .max_buffers(2)
+                .drop(true)
+                .sync(false)
+                .build();
+            // With only the width fixed, videoscale picks the height that keeps the shape.
+            let caps = Caps::builder("video/x-raw")
+                .field("width", width as i32)
+                .build();
+            raw_branches.push(vec![
+                make_element("videoscale")?,
+                capsfilter(&caps)?,
+                make_element("jpegenc")?,
+                sink.clone().upcast(),
+            ]);
+            Some(sink)
+        } else {
+            None
+        };
+
+        let tee = if raw_branches.is_empty() {
+            None
+        } else {
+            let tee = make_element("tee")?;
+            elts.push(tee.clone());
+            // The encoder is on a branch of its own, so it needs a queue anyway.
+            elts.push(match config.encode_queue {
+                Some(n) => sized_queue(n)?,
+                None => make_element("queue")?,
+            });
+            Some(tee)
+        };
+        if let (None, Some(n)) = (&tee, config.encode_queue) {
+            elts.push(sized_queue(n)?);
+        }
+
+        if let Some(chroma) = config.chroma_subsampling {
+            elts.push(make_element("videoconvert")?);
+            let caps = Caps::builder("video/x-raw")
+                .field("format", chroma.format())
+                .build();
+            elts.push(capsfilter(&caps)?);
+        }
+
+        let (enc, encoder) = if let Some(desc) = &config.encode_pipeline {
+            info!("using encoder pipeline {desc:?}");
+            let caps = config.codec.sink_caps().build();
+            (encode_bin(desc, &caps)?, format!("{desc:?}"))
+        } else {
+            let encoder = match config.encoder.as_deref() {
+                Some(name) if ElementFactory::find(name).is_none() => {
+                    let default = config.codec.default_encoder();
+                    warn!("encoder {name:?} isn't available; falling back to {default}");
+                    default
+                }
+                Some(name) => name,
+                None => config.codec.default_encoder(),
+            };
+            info!("using encoder {encoder}");
+            let mut enc = ElementFactory::make(encoder);
+            if encoder == "x264enc" {
+                // Without this, x264enc buffers a couple seconds of frames before outputting any.
+                enc = enc.property_from_str("tune", "zerolatency");
+            }
+            let enc = enc
+                .build()
+                .with_context(|| format!("failed to make {encoder}"))?;
+            (enc, encoder.to_owned())
+        };
+        if let Some(method) = &config.jpeg_idct {
+            if enc.has_property("idct-method", None) {
+                enc.set_property_from_str("idct-method", method);
+            } else {
+                warn!("encoder {encoder} has no DCT method setting; ignoring --jpeg-idct");
+            }
+        }
+        let has_quality = enc.has_property("quality", Some(i32::static_type()));
+        if let Some(quality) = config.quality {
+            if has_quality {
+                enc.set_property("quality", i32::from(quality));
+            } else {
+                warn!("encoder {encoder} has no quality setting; ignoring --quality");
+            }
+        }
+        if has_quality {
+            info!("encoding with quality {}", enc.property::<i32>("quality"));
+        }
+        if let Some(chroma) = config.chroma_subsampling {
+            let format = chroma.format();
+            let accepted = enc
+                .static_pad("sink")
+                .map(|pad| pad.pad_template_caps())
+                .context("encoder has no sink pad")?;
+            let wanted = Caps::builder("video/x-raw").field("format", format).build();
+            if !accepted.can_intersect(&wanted) {
+                bail!("encoder {encoder} doesn't accept {format} video for --chroma-subsampling");
+            }
+        }
+        elts.push(enc.clone());
 
         let sink_caps = {
-            let mut b = Caps::builder("image/jpeg");
-            if let Some((w, h)) = size {
-                b = b
-                    .field("width", i32::try_from(w).context("width out of range")?)
-                    .field("height", i32::try_from(h).context("height out of range")?);
+            let mut b = config.codec.sink_caps();
+            if let (Some((w, h)), false) = (padded.or(size), config.scale) {
+                b = b.field("width", w).field("height", h);
             }
             b.build()
         };
 
-        let appsink = AppSink::builder().caps(&sink_caps).name("appsink").build();
-        elts.push(appsink.upcast_ref());
+        let record_drops = Arc::new(AtomicU64::new(0));
+        let recorder = sink_queue(config, &mut elts, &record_drops)?;
+
+        let appsink = AppSink::builder()
+            .caps(&sink_caps)
+            .name("appsink")
+            .sync(config.sync)
+            .build();
+        elts.push(appsink.clone().upcast());
+
+        let elts: Vec<&Element> = elts.iter().collect();
+        pipeline
+            .add_many(&elts)
+            .context("failed to add elements to pipeline")?;
+        link_chain(&elts)?;
+
+        if let Some(tee) = &tee {
+            for branch in raw_branches {
+                add_branch(&pipeline, tee, branch)?;
+            }
+        }
+        if let Some((tee, queue, branch)) = recorder {
+            link_branch(&pipeline, &tee, queue, branch)?;
+        }
 
+        Ok(Self {
+            pipeline,
+            codec: config.codec,
+            appsink,
+            balance: Some(balance),
+            encoder: Some(enc),
+            caption,
+            caption_file: Mutex::new(config.caption_file.clone()),
+            raw_appsink,
+            motion_appsink,
+            thumb_appsink,
+            cancel: CancellationToken::new(),
+            tasks: Mutex::new(vec![]),
+            hold_open: AtomicBool::new(false),
+            last_error: Mutex::new(None),
+            not_negotiated: AtomicBool::new(false),
+            eos_timeout: config.eos_timeout,
+            recording: config.record.is_some(),
+            record_drops,
+            stopping: AtomicBool::new(false),
+            stopped: tokio::sync::Notify::new(),
+            failed: tokio::sync::watch::channel(false).0,
+        })
+    }
+
+    /// Make a pipeline that sends the camera's frames, already in `caps`, straight to the appsink.
+    fn passthrough(
+        config: &Config,
+        pipeline: Pipeline,
+        camera: Element,
+        caps: &Caps,
+    ) -> anyhow::Result<Self> {
+        info!("passing the camera's {caps} through without re-encoding");
+        let mut elts = vec![camera, capsfilter(caps)?];
+        let record_drops = Arc::new(AtomicU64::new(0));
+        let recorder = sink_queue(config, &mut elts, &record_drops)?;
+        let appsink = AppSink::builder()
+            .caps(caps)
+            .name("appsink")
+            .sync(config.sync)
+            .build();
+        elts.push(appsink.clone().upcast());
+
+        let elts: Vec<&Element> = elts.iter().collect();
         pipeline
             .add_many(&elts)
             .context("failed to add elements to pipeline")?;
-        Element::link_many(&elts).context("failed to link elements")?;
+        link_chain(&elts)?;
+        if let Some((tee, queue, branch)) = recorder {
+            link_branch(&pipeline, &tee, queue, branch)?;
+        }
+
+        Ok(Self {
+            pipeline,
+            codec: config.codec,
+            appsink,
+            balance: None,
+            encoder: None,
+            caption: None,
+            caption_file: Mutex::new(None),
+            raw_appsink: None,
+            motion_appsink: None,
+            thumb_appsink: None,
+            cancel: CancellationToken::new(),
+            tasks: Mutex::new(vec![]),
+            hold_open: AtomicBool::new(false),
+            last_error: Mutex::new(None),
+            not_negotiated: AtomicBool::new(false),
+            eos_timeout: config.eos_timeout,
+            recording: config.record.is_some(),
+            record_drops,
+            stopping: AtomicBool::new(false),
+            stopped: tokio::sync::Notify::new(),
+            failed: tokio::sync::watch::channel(false).0,
+        })
+    }
+
+    /// Whether the given object (e.g. the source of a bus message) is the pipeline itself.
+    pub fn is_pipeline(&self, obj: Option<&gstreamer::Object>) -> bool {
+        obj == Some(self.pipeline.upcast_ref())
+    }
+
+    /// The size and frame rate of the encoded video, once the pipeline has negotiated them.
+    pub fn output_format(&self) -> Option<OutputFormat> {
+        let caps = self.appsink.static_pad("sink")?.current_caps()?;
+        let s = caps.structure(0)?;
+        Some(OutputFormat {
+            width: s.get("width").ok()?,
+            height: s.get("height").ok()?,
+            // Zero means variable, which is as good as unknown.
+            framerate: s
+                .get::<Fraction>("framerate")
+                .ok()
+                .filter(|f| f.numer() > 0 && f.denom() > 0)
+                .map(|f| f64::from(f.numer()) / f64::from(f.denom())),
+        })
+    }
+
+    /// The width and height of the encoded video, once the pipeline has negotiated them.
+    pub fn resolution(&self) -> Option<(i32, i32)> {
+        self.output_format().map(|f| (f.width, f.height))
+    }
+
+    pub fn codec(&self) -> Codec {
+        self.codec
+    }
 
-        Ok(Self { pipeline, appsink })
+    /// The current color adjustments, or `None` if the camera's frames are passed through
+    /// without any.
+    pub fn balance(&self) -> Option<Balance> {
+        let balance = self.balance.as_ref()?;
+        Some(Balance {
+            brightness: balance.property("brightness"),
+            contrast: balance.property("contrast"),
+            saturation: balance.property("saturation"),
+            hue: balance.property("hue"),
+        })
+    }
+
+    /// Change one of the color adjustments, by its name in [`Balance::RANGES`]. This takes effect
+    /// on the running pipeline.
+    pub fn set_balance(&self, name: &str, value: f64) -> anyhow::Result<()> {
+        Balance::check(name, value)?;
+        let Some(balance) = &self.balance else {
+            bail!("color adjustments aren't available when passing the camera's frames through");
+        };
+        balance.set_property(name, value);
+        Ok(())
+    }
+
+    /// The name of the encoder element in use, "custom" for an `encode_pipeline`, or
+    /// "passthrough" if the camera's frames aren't re-encoded.
+    pub fn encoder_name(&self) -> String {
+        match &self.encoder {
+            Some(encoder) => encoder
+                .factory()
+                .map(|f| f.name().to_string())
+                .unwrap_or_else(|| "custom".to_owned()),
+            None => "passthrough".to_owned(),
+        }
+    }
+
+    /// The encoder, if it has a quality setting (like jpegenc does).
+    fn quality_encoder(&self) -> Option<&Element> {
+        self.encoder
+            .as_ref()
+            .filter(|enc| enc.has_property("quality", Some(i32::static_type())))
+    }
+
+    /// The encoder's current quality setting, if it has one.
+    pub fn quality(&self) -> Option<i32> {
+        self.quality_encoder().map(|enc| enc.property("quality"))
+    }
+
+    /// Change the encoder's quality setting, if it has one. This takes effect on the running
+    /// pipeline.
+    pub fn set_quality(&self, quality: i32) -> anyhow::Result<()> {
+        let Some(enc) = self.quality_encoder() else {
+            bail!("the encoder has no quality setting");
+        };
+        enc.set_property("quality", quality);
+        Ok(())
+    }
+
+    /// Bring the pipeline up just far enough for its formats to be negotiated, and return the
+    /// format of the encoded video, or why it couldn't get there. Live sources (like cameras)
+    /// don't negotiate until they're playing, so those are played until the first frame comes
+    /// out. This blocks for up to `timeout`, and leaves the pipeline running.
+    pub fn check(&self, timeout: Duration) -> anyhow::Result<Caps> {
+        let timeout = ClockTime::try_from(timeout).context("timeout out of range")?;
+        let failed = |what: String| match self.last_error() {
+            Some(e) => anyhow::anyhow!("{what}: {e}"),
+            None => anyhow::anyhow!(what),
+        };
+        self.pipeline
+            .set_state(State::Paused)
+            .map_err(|_| failed("failed to set pipeline to Paused state".into()))?;
+        let sample = match self.pipeline.state(timeout).0 {
+            Ok(StateChangeSuccess::NoPreroll) => {
+                self.pipeline
+                    .set_state(State::Playing)
+                    .map_err(|_| failed("failed to set pipeline to Playing state".into()))?;
+                self.appsink.try_pull_sample(timeout)
+            }
+            Ok(StateChangeSuccess::Success) => self.appsink.try_pull_preroll(timeout),
+            Ok(_) => {
+                return Err(failed(format!(
+                    "pipeline didn't get ready within {timeout}"
+                )))
+            }
+            Err(_) => return Err(failed("pipeline failed to start".into())),
+        };
+        sample
+            .ok_or_else(|| failed(format!("no frame came out within {timeout}")))?
+            .caps()
+            .map(|caps| caps.to_owned())
+            .context("frame has no format")
+    }
+
+    /// Whether uncompressed frames can be had from [`Video::pull_raw_frame`].
+    pub fn has_raw_frames(&self) -> bool {
+        self.raw_appsink.is_some()
+    }
+
+    /// Get an uncompressed RGB frame from the running pipeline, waiting up to `timeout` for one.
+    /// This blocks, so call it from a blocking-friendly context.
+    pub fn pull_raw_frame(&self, timeout: Duration) -> anyhow::Result<RawFrame> {
+        let sink = self
+            .raw_appsink
+            .as_ref()
+            .context("raw frames aren't enabled")?;
+        let timeout = ClockTime::try_from(timeout).context("timeout out of range")?;
+        let sample = sink
+            .try_pull_sample(timeout)
+            .context("timed out waiting for a raw frame")?;
+        let s = sample
+            .caps()
+            .and_then(|caps| caps.structure(0))
+            .context("raw frame has no caps")?;
+        let width: i32 = s.get("width").context("raw frame has no width")?;
+        let height: i32 = s.get("height").context("raw frame has no height")?;
+        let (width, height) = (width as usize, height as usize);
+        let buf = sample.buffer().context("raw sample has no buffer")?;
+        let map = buf.map_readable().context("failed to map raw buffer")?;
+        if height == 0 || map.len() / height < width * 3 {
+            bail!("raw frame is too small for {width}x{height}");
+        }
+        Ok(RawFrame {
+            width,
+            height,
+            stride: map.len() / height,
+            data: Bytes::copy_from_slice(map.as_slice()),
+        })
+    }
+
+    pub fn has_motion(&self) -> bool {
+        self.motion_appsink.is_some()
+    }
+
+    /// How different the two most recent frames are, from 0 (identical) to 1 (every pixel went
+    /// from black to white or back): the average difference in brightness between them, on
+    /// copies shrunk to a fixed small size. The frames are used up, so calling this again soon
+    /// waits for two new ones, for up to `timeout` each.
+    pub fn motion_score(&self, timeout: Duration) -> anyhow::Result<f64> {
+        let sink = self
+            .motion_appsink
+            .as_ref()
+            .context("motion measurement isn't enabled")?;
+        let timeout = ClockTime::try_from(timeout).context("timeout out of range")?;
+        let pull = || -> anyhow::Result<Vec<u8>> {
+            let sample = sink
+                .try_pull_sample(timeout)
+                .context("timed out waiting for a frame to compare")?;
+            let buf = sample.buffer().context("motion sample has no buffer")?;
+            let map = buf.map_readable().context("failed to map motion buffer")?;
+            let stride = map.len() / MOTION_HEIGHT;
+            if stride < MOTION_WIDTH {
+                bail!("motion frame is too small");
+            }
+            Ok(map
+                .chunks(stride)
+                .flat_map(|row| &row[..MOTION_WIDTH])
+                .copied()
+                .collect())
+        };
+        let (a, b) = (pull()?, pull()?);
+        let total: u64 = a
+            .iter()
+            .zip(&b)
+            .map(|(&a, &b)| u64::from(a.abs_diff(b)))
+            .sum();
+        Ok(total as f64 / (255. * (MOTION_WIDTH * MOTION_HEIGHT) as f64))
+    }
+
+    /// Spawn a task (typically one of the `foreach_*` loops) tied to this video, which
+    /// [`Video::shutdown`] will wait for.
+    pub fn spawn(&self, task: impl Future<Output = ()> + Send + 'static) {
+        let mut tasks = self.tasks.lock().unwrap();
+        tasks.retain(|t| !t.is_finished());
+        tasks.push(tokio::spawn(task));
     }
 
     pub async fn foreach_frame(self: Arc<Self>, f: impl Fn(&Video, &Sample, &BufferRef)) {
-        while let Some(sample) = self.appsink.stream().next().await {
+        self.foreach_sample(&self.appsink, |sample, buf| f(self.as_ref(), sample, buf))
+            .await;
+        println!("no more frames");
+    }
+
+    pub fn has_thumbnails(&self) -> bool {
+        self.thumb_appsink.is_some()
+    }
+
+    /// Like [`Video::foreach_frame`], but for thumbnails. This returns right away if they aren't
+    /// enabled.
+    pub async fn foreach_thumbnail(self: Arc<Self>, f: impl Fn(&BufferRef)) {
+        if let Some(sink) = &self.thumb_appsink {
+            self.foreach_sample(sink, |_, buf| f(buf)).await;
+        }
+    }
+
+    async fn foreach_sample(&self, sink: &AppSink, f: impl Fn(&Sample, &BufferRef)) {
+        let mut stream = sink.stream();
+        loop {
+            let sample = tokio::select! {
+                Some(sample) = stream.next() => sample,
+                _ = self.cancel.cancelled() => break,
+                else => break,
+            };
             let buf = match sample.buffer() {
                 Some(buf) => buf,
                 None => {
@@ -89,30 +1423,906 @@ impl Video {
                 }
             };
 
-            f(self.as_ref(), &sample, buf);
+            f(&sample, buf);
         }
-        println!("no more frames");
     }
 
     pub async fn foreach_message(self: Arc<Self>, f: impl Fn(&Video, Message)) {
         let bus = self.pipeline.bus().unwrap();
-        while let Some(msg) = bus.stream().next().await {
+        let mut stream = bus.stream();
+        loop {
+            let msg = tokio::select! {
+                Some(msg) = stream.next() => msg,
+                _ = self.cancel.cancelled() => break,
+                else => break,
+            };
+            if self.stopping.load(Ordering::SeqCst) && matches!(msg.view(), MessageView::Eos(..)) {
+                // Asked for by stop(), rather than the video ending.
+                self.stopped.notify_waiters();
+                continue;
+            }
+            let failed = matches!(msg.view(), MessageView::Error(..));
             f(self.as_ref(), msg);
+            if failed {
+                self.failed.send_replace(true);
+            }
+        }
+    }
+
+    /// Keep the caption overlay up to date with the contents of the given file, until the video
+    /// is shut down.
+    ///
+    /// The file is polled for changes, and a change is only applied once the file has stopped
+    /// changing for a moment, so a burst of writes only updates the text once. If the file is
+    /// switched with `set_caption_file`, the new one is watched from then on.
+    pub async fn watch_caption_file(self: Arc<Self>) {
+        const POLL_INTERVAL: Duration = Duration::from_millis(250);
+        let Some(caption) = &self.caption else {
+            return;
+        };
+        let Some(mut path) = self.caption_file.lock().unwrap().clone() else {
+            return;
+        };
+        let mut applied = modified_time(&path);
+        let mut pending = None;
+        loop {
+            tokio::select! {
+                _ = tokio::time::sleep(POLL_INTERVAL) => (),
+                _ = self.cancel.cancelled() => break,
+            }
+            let current = self.caption_file.lock().unwrap().clone();
+            if let Some(current) = current.filter(|p| *p != path) {
+                // set_caption_file already showed what's in it.
+                path = current;
+                applied = modified_time(&path);
+                pending = None;
+                continue;
+            }
+            let mtime = modified_time(&path);
+            if mtime == applied {
+                pending = None;
+                continue;
+            }
+            if pending != Some(mtime) {
+                // Changed since the last poll; wait for it to settle.
+                pending = Some(mtime);
+                continue;
+            }
+            applied = mtime;
+            pending = None;
+            match read_caption(&path) {
+                Ok(text) => {
+                    debug!("caption changed to {text:?}");
+                    caption.set_property("text", text);
+                }
+                Err(e) => warn!("{e:#}"),
+            }
         }
     }
 
+    /// Show the contents of a different file in the caption overlay, and watch that one for
+    /// changes instead. This takes effect on the running pipeline.
+    pub fn set_caption_file(&self, path: PathBuf) -> anyhow::Result<()> {
+        let Some(caption) = &self.caption else {
+            bail!("the video has no caption overlay");
+        };
+        caption.set_property("text", read_caption(&path)?);
+        *self.caption_file.lock().unwrap() = Some(path);
+        Ok(())
+    }
+
+    /// Frames left out of the recording because the disk couldn't keep up, if recording.
+    pub fn record_drops(&self) -> Option<u64> {
+        self.recording
+            .then(|| self.record_drops.load(Ordering::Relaxed))
+    }
+
+    /// The latency the pipeline was told to use, if it was given a fixed one.
+    pub fn latency(&self) -> Option<Duration> {
+        self.pipeline
+            .latency()
+            .map(|l| Duration::from_nanos(l.nseconds()))
+    }
+
     pub fn start(&self) -> anyhow::Result<()> {
+        self.last_error.lock().unwrap().take();
+        self.not_negotiated.store(false, Ordering::SeqCst);
+        self.failed.send_replace(false);
         self.pipeline
             .set_state(State::Playing)
             .context("failed to set pipeline to Playing state")?;
         Ok(())
     }
 
-    pub fn stop(&self) -> anyhow::Result<()> {
-        //self.pipeline.send_event(gstreamer::event::Eos::new());
+    /// Log the formats, sizes, and frame rates the camera says it supports.
+    ///
+    /// This has to open the device, so the pipeline must not be running.
+    pub fn log_camera_modes(&self) -> anyhow::Result<()> {
+        let caps = self.camera_caps()?;
+        info!("camera supports {} modes:", caps.size());
+        for s in caps.iter() {
+            info!("  {s}");
+        }
+        Ok(())
+    }
+
+    /// Find the camera's mode with the most pixels, returning its width, height, and full
+    /// description. Of modes with the same size, the first one listed wins.
+    ///
+    /// This has to open the device, so the pipeline must not be running.
+    pub fn largest_camera_mode(&self) -> anyhow::Result<Option<(u32, u32, String)>> {
+        let caps = self.camera_caps()?;
+        let mut best: Option<(u32, u32, String)> = None;
+        for s in caps.iter() {
+            // Sizes are either exact, or (e.g. for scaling sources) a range.
+            let dimension = |name| {
+                s.get::<i32>(name)
+                    .ok()
+                    .or_else(|| s.get::<IntRange<i32>>(name).ok().map(|r| r.max()))
+                    .and_then(|v| u32::try_from(v).ok())
+            };
+            let (Some(w), Some(h)) = (dimension("width"), dimension("height")) else {
+                continue;
+            };
+            let area = |w: u32, h: u32| u64::from(w) * u64::from(h);
+            if area(w, h) > best.as_ref().map_or(0, |(bw, bh, _)| area(*bw, *bh)) {
+                best = Some((w, h, s.to_string()));
+            }
+        }
+        Ok(best)
+    }
+
+    /// What the camera can produce, asked of the running pipeline. Unlike the startup queries,
+    /// this doesn't touch the camera's state, so the video must already be running.
+    pub fn running_camera_caps(&self) -> anyhow::Result<Caps> {
+        let camera = self
+            .pipeline
+            .by_name("camera")
+            .context("this video source has no camera to ask")?;
+        Ok(camera
+            .static_pad("src")
+            .context("camera has no src pad")?
+            .query_caps(None))
+    }
+
+    /// The exact sizes the camera supports, largest first, each listed once.
+    ///
+    /// This has to open the device, so the pipeline must not be running.
+    pub fn camera_sizes(&self) -> anyhow::Result<Vec<(u32, u32)>> {
+        let caps = self.camera_caps()?;
+        let mut sizes: Vec<(u32, u32)> = caps
+            .iter()
+            .filter_map(|s| {
+                let w = u32::try_from(s.get::<i32>("width").ok()?).ok()?;
+                let h = u32::try_from(s.get::<i32>("height").ok()?).ok()?;
+                Some((w, h))
+            })
+            .collect();
+        sizes.sort_by_key(|&(w, h)| (std::cmp::Reverse(u64::from(w) * u64::from(h)), w, h));
+        sizes.dedup();
+        Ok(sizes)
+    }
+
+    /// Query what the camera can produce, by briefly opening it.
+    fn camera_caps(&self) -> anyhow::Result<Caps> {
+        let camera = self
+            .pipeline
+            .by_name("camera")
+            .context("pipeline has no camera element")?;
+        query_camera_caps(&camera)
+    }
+
+    /// Remember an error from the pipeline, to tell clients why the video isn't working.
+    pub fn set_last_error(&self, error: String) {
+        *self.last_error.lock().unwrap() = Some(error);
+    }
+
+    /// Wait until the pipeline reports an error, which usually means it's stopped producing
+    /// frames (e.g. the camera was unplugged). This returns right away if it already has, since it
+    /// was last started.
+    pub async fn failed(&self) {
+        let mut failed = self.failed.subscribe();
+        // The sender is part of self, so it can't go away while this waits.
+        let _ = failed.wait_for(|&failed| failed).await;
+    }
+
+    /// Remember that the pipeline failed to negotiate formats, as told by
+    /// [`is_negotiation_error`].
+    pub fn set_not_negotiated(&self) {
+        self.not_negotiated.store(true, Ordering::SeqCst);
+    }
+
+    /// Whether the pipeline has failed to negotiate formats since it was started.
+    pub fn not_negotiated(&self) -> bool {
+        self.not_negotiated.load(Ordering::SeqCst)
+    }
+
+    /// The last error reported by the pipeline since it was started, if any.
+    pub fn last_error(&self) -> Option<String> {
+        self.last_error.lock().unwrap().clone()
+    }
+
+    /// Seek back to the beginning, for looping a file source.
+    pub fn rewind(&self) -> anyhow::Result<()> {
+        self.pipeline
+            .seek_simple(SeekFlags::FLUSH | SeekFlags::KEY_UNIT, ClockTime::ZERO)
+            .context("failed to seek to start")?;
+        Ok(())
+    }
+
+    /// How long the pipeline has been running, on the same clock as frame timestamps.
+    pub fn running_time(&self) -> Option<Duration> {
+        let time = self.pipeline.current_running_time()?;
+        Some(Duration::from(time))
+    }
+
+    /// The pipeline's current state.
+    pub fn pipeline_state(&self) -> State {
+        self.pipeline.current_state()
+    }
+
+    /// Wait up to `timeout` for the pipeline to reach the given state, returning whether it did.
+    pub async fn wait_for_state(&self, state: State, timeout: Duration) -> bool {
+        const POLL_INTERVAL: Duration = Duration::from_millis(20);
+        let deadline = tokio::time::Instant::now() + timeout;
+        loop {
+            if self.pipeline.current_state() == state {
+                return true;
+            }
+            if tokio::time::Instant::now() >= deadline {
+                return false;
+            }
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+    }
+
+    /// Transition the pipeline to an arbitrary state.
+    pub fn set_pipeline_state(&self, state: State) -> anyhow::Result<()> {
+        self.pipeline
+            .set_state(state)
+            .with_context(|| format!("failed to set pipeline to {state:?} state"))?;
+        Ok(())
+    }
+
+    /// Open the video device now, and keep it open even while the video is stopped (the pipeline
+    /// goes to the Ready state instead of Null). This lets the device be used after dropping the
+    /// privileges needed to open it.
+    pub fn hold_open(&self) -> anyhow::Result<()> {
+        self.hold_open.store(true, Ordering::SeqCst);
+        self.pipeline
+            .set_state(State::Ready)
+            .context("failed to set pipeline to Ready state")?;
+        Ok(())
+    }
+
+    pub async fn stop(&self) -> anyhow::Result<()> {
+        let timeout = if self.recording {
+            self.eos_timeout.max(RECORD_EOS_TIMEOUT)
+        } else {
+            self.eos_timeout
+        };
+        if !timeout.is_zero() && self.pipeline.current_state() == State::Playing {
+            self.finish(timeout).await;
+        }
+        let state = if self.hold_open.load(Ordering::SeqCst) {
+            State::Ready
+        } else {
+            State::Null
+        };
         self.pipeline
-            .set_state(State::Null)
-            .context("failed to set pipeline to Null state")?;
+            .set_state(state)
+            .with_context(|| format!("failed to set pipeline to {state:?} state"))?;
         Ok(())
     }
+
+    /// Send an end-of-stream through the pipeline, and wait up to `timeout` for it to come out,
+    /// letting sinks (like hlssink2) finish their files properly. This relies on the
+    /// [`Video::foreach_message`] loop still running to see it arrive.
+    async fn finish(&self, timeout: Duration) {
+        let stopped = self.stopped.notified();
+        tokio::pin!(stopped);
+        stopped.as_mut().enable();
+        self.stopping.store(true, Ordering::SeqCst);
+        if !self.pipeline.send_event(gstreamer::event::Eos::new()) {
+            warn!("pipeline didn't take the end-of-stream; stopping it right away");
+        } else if tokio::time::timeout(timeout, stopped).await.is_err() {
+            warn!("video didn't finish within {timeout:?} of being told to; stopping it anyway");
+        }
+        self.stopping.store(false, Ordering::SeqCst);
+    }
+
+    /// Stop the pipeline for good, signal all the tasks started with [`Video::spawn`] to exit,
+    /// and wait for them to finish.
+    ///
+    /// The video can't be started again afterwards; make a new one instead.
+    pub async fn shutdown(&self) -> anyhow::Result<()> {
+        info!("shutting down video");
+        self.hold_open.store(false, Ordering::SeqCst);
+        // Before cancelling, so the message loop is still there to see the end-of-stream.
+        let stop_result = self.stop().await;
+        self.cancel.cancel();
+        let tasks = std::mem::take(&mut *self.tasks.lock().unwrap());
+        for task in tasks {
+            if let Err(e) = task.await {
+                error!("video task failed: {e}");
+            }
+        }
+        stop_result
+    }
+}
+
+/// Explain the pipeline errors that commonly come from a misconfigured camera, more plainly than
+/// GStreamer's own messages, and with what to do about them.
+pub fn explain_error(error: &gstreamer::glib::Error, debug: Option<&str>) -> Option<&'static str> {
+    if let Some(kind) = error.kind::<ResourceError>() {
+        return match kind {
+            ResourceError::Busy => {
+                Some("device busy: another program is probably using the camera")
+            }
+            ResourceError::NotFound => Some("device not found: check the --device path"),
+            ResourceError::OpenRead | ResourceError::OpenReadWrite => Some(
+                "couldn't open the device: check its permissions (is the user in the video group?)",
+            ),
+            ResourceError::Settings => {
+                Some("the device doesn't support the requested settings: try another --size")
+            }
+            _ => None,
+        };
+    }
+    is_negotiation_error(error, debug)
+        .then_some("requested format unsupported: run with -v to list the camera's modes")
+}
+
+/// Whether a pipeline error means its elements couldn't agree on a format, e.g. because the
+/// camera can't produce the requested size.
+pub fn is_negotiation_error(error: &gstreamer::glib::Error, debug: Option<&str>) -> bool {
+    // Caps negotiation failing shows up as a generic streaming error from the source.
+    matches!(error.kind::<StreamError>(), Some(StreamError::Format))
+        || matches!(debug, Some(d) if d.contains("not-negotiated"))
+}
+
+/// Make a bin named "camera" that decodes the output of the given source element with a
+/// decodebin-like element, and has a static src pad for the raw video.
+fn decoding_bin(src: Element, decoder: &str) -> anyhow::Result<Bin> {
+    let bin = Bin::new(Some("camera"));
+    let decode = make_element(decoder)?;
+    let convert = make_element("videoconvert")?;
+    bin.add_many(&[&src, &decode, &convert])
+        .context("failed to add elements to decoding bin")?;
+    src.link(&decode)
+        .with_context(|| format!("failed to link source to {decoder}"))?;
+
+    // The decoder only makes its src pads once it knows what's in the stream.
+    let convert_sink = convert.static_pad("sink").unwrap();
+    decode.connect_pad_added(move |_decode, pad| {
+        let is_video = pad
+            .current_caps()
+            .and_then(|caps| caps.structure(0).map(|s| s.name().starts_with("video/")))
+            .unwrap_or(false);
+        if !is_video || convert_sink.is_linked() {
+            return;
+        }
+        if let Err(e) = pad.link(&convert_sink) {
+            error!("failed to link decoded video: {e}");
+        }
+    });
+
+    let src_pad = convert.static_pad("src").unwrap();
+    let ghost = GhostPad::with_target(Some("src"), &src_pad)
+        .context("failed to make decoding bin src pad")?;
+    bin.add_pad(&ghost)
+        .context("failed to add decoding bin src pad")?;
+    Ok(bin)
+}
+
+/// Read the caption text from a file, without any trailing newline.
+fn read_caption(path: &Path) -> anyhow::Result<String> {
+    let text = std::fs::read_to_string(path)
+        .with_context(|| format!("failed to read caption file {path:?}"))?;
+    Ok(text.trim_end_matches(['\r', '\n']).to_owned())
+}
+
+/// A file's modification time, or `None` if it can't be read (e.g. the file doesn't exist yet).
+fn modified_time(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+/// Set a property from a string, like gst-launch does, but with an error instead of a panic if the
+/// element has no such property or the value doesn't fit it.
+fn set_property_checked(elt: &Element, name: &str, value: &str) -> anyhow::Result<()> {
+    let factory = elt
+        .factory()
+        .map(|f| f.name().to_string())
+        .unwrap_or_else(|| elt.name().to_string());
+    let Some(pspec) = elt.find_property(name) else {
+        bail!("{factory} has no property {name:?}; see `gst-inspect-1.0 {factory}`");
+    };
+    if !pspec.flags().contains(ParamFlags::WRITABLE) {
+        bail!("{factory} property {name:?} can't be set");
+    }
+    let parsed = gstreamer::glib::Value::deserialize(value, pspec.value_type()).map_err(|_| {
+        anyhow::anyhow!(
+            "invalid value {value:?} for {factory} property {name:?}, which is a {}",
+            pspec.value_type()
+        )
+    })?;
+    elt.set_property_from_value(name, &parsed);
+    Ok(())
+}
+
+/// The smallest size with the given aspect ratio that `size` fits in, with even dimensions.
+fn letterbox((w, h): (u32, u32), (aw, ah): (u32, u32)) -> (u32, u32) {
+    let even = |n: u64| (n + n % 2) as u32;
+    let (w64, h64) = (u64::from(w), u64::from(h));
+    if w64 * u64::from(ah) < h64 * u64::from(aw) {
+        // Too narrow: add borders at the sides.
+        (
+            even((h64 * u64::from(aw)).div_ceil(u64::from(ah))).max(w),
+            h,
+        )
+    } else {
+        (
+            w,
+            even((w64 * u64::from(ah)).div_ceil(u64::from(aw))).max(h),
+        )
+    }
+}
+
+fn make_element(factory: &str) -> anyhow::Result<Element> {
+    ElementFactory::make(factory)
+        .build()
+        .with_context(|| format!("failed to make {factory}"))
+}
+
+/// Query what a camera element can produce, by briefly opening it. It has to be stopped.
+fn query_camera_caps(camera: &Element) -> anyhow::Result<Caps> {
+    camera
+        .set_state(State::Ready)
+        .context("failed to set camera to Ready state")?;
+    let caps = camera
+        .static_pad("src")
+        .context("camera has no src pad")?
+        .query_caps(None);
+    if let Err(e) = camera.set_state(State::Null) {
+        warn!("failed to set camera back to Null state: {e}");
+    }
+    Ok(caps)
+}
+
+/// A camera found by GStreamer's device monitor.
+pub struct CameraDevice {
+    pub name: String,
+    /// Its device node, if it has one (V4L2 cameras do; libcamera ones don't).
+    pub path: Option<String>,
+    /// The formats it can produce.
+    pub caps: Option<Caps>,
+}
+
+/// List the cameras attached to the system, for picking one to use. This probes each of them, so
+/// it's best called off the async threads.
+pub fn list_cameras() -> Vec<CameraDevice> {
+    let monitor = DeviceMonitor::new();
+    monitor.add_filter(Some("Video/Source"), None);
+    monitor
+        .devices()
+        .iter()
+        .map(|device| CameraDevice {
+            name: device.display_name().into(),
+            path: device
+                .properties()
+                .and_then(|props| props.get::<String>("device.path").ok()),
+            caps: device.caps(),
+        })
+        .collect()
+}
+
+/// Find the device node for a camera, given either a path (like `/dev/video0`, or one of the
+/// stable links under `/dev/v4l/by-id/` or `/dev/v4l/by-path/`), just the name of one of those
+/// links, or a camera's name or serial number as GStreamer's device monitor reports it. This is
+/// done each time the pipeline is built, so a camera that comes back under a different number is
+/// still found. Anything that doesn't match is used as a path as it is.
+fn resolve_device(spec: &str) -> String {
+    if spec.starts_with('/') {
+        return spec.to_owned();
+    }
+    for dir in ["/dev/v4l/by-id", "/dev/v4l/by-path"] {
+        let path = Path::new(dir).join(spec);
+        if path.exists() {
+            info!("using device {path:?} for {spec:?}");
+            return path.to_string_lossy().into_owned();
+        }
+    }
+    let monitor = DeviceMonitor::new();
+    monitor.add_filter(Some("Video/Source"), None);
+    for device in monitor.devices() {
+        let Some(props) = device.properties() else {
+            continue;
+        };
+        let Ok(path) = props.get::<String>("device.path") else {
+            continue;
+        };
+        let serial = props.get::<String>("device.serial").ok();
+        if device.display_name() == spec || serial.as_deref() == Some(spec) {
+            info!("using device {path} for {spec:?}");
+            return path;
+        }
+    }
+    warn!("no camera named {spec:?} found; using it as a path");
+    spec.to_owned()
+}
+
+/// Take an exclusive advisory lock on a camera's device node, so that a second instance pointed
+/// at the same camera fails right away instead of fighting over it. The lock lasts until the
+/// returned file is dropped. Only other programs that lock the device too are kept out. A camera
+/// that isn't plugged in yet isn't locked.
+pub fn lock_device(spec: &str) -> anyhow::Result<Option<File>> {
+    let path = resolve_device(spec);
+    let file = match File::open(&path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            warn!("device {path} doesn't exist yet; not locking it");
+            return Ok(None);
+        }
+        Err(e) => return Err(e).with_context(|| format!("failed to open device {path}")),
+    };
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+        let e = std::io::Error::last_os_error();
+        if e.kind() != std::io::ErrorKind::WouldBlock {
+            return Err(e).with_context(|| format!("failed to lock device {path}"));
+        }
+        let pids = device_users(Path::new(&path));
+        if pids.is_empty() {
+            bail!("device {path} is already in use by another program (use --share-device to open it anyway)");
+        }
+        let pids: Vec<String> = pids.iter().map(u32::to_string).collect();
+        bail!(
+            "device {path} is already in use by PID {} (use --share-device to open it anyway)",
+            pids.join(", ")
+        );
+    }
+    debug!("locked device {path}");
+    Ok(Some(file))
+}
+
+/// The other processes that have a device open, as far as we're allowed to see.
+fn device_users(path: &Path) -> Vec<u32> {
+    let Ok(target) = path.canonicalize() else {
+        return vec![];
+    };
+    let Ok(procs) = std::fs::read_dir("/proc") else {
+        return vec![];
+    };
+    let me = std::process::id();
+    let mut pids: Vec<u32> = procs
+        .flatten()
+        .filter_map(|entry| entry.file_name().to_str()?.parse().ok())
+        .filter(|&pid| pid != me)
+        .filter(|pid| {
+            let Ok(fds) = std::fs::read_dir(format!("/proc/{pid}/fd")) else {
+                return false;
+            };
+            fds.flatten()
+                .any(|fd| std::fs::read_link(fd.path()).is_ok_and(|link| link == target))
+        })
+        .collect();
+    pids.sort_unstable();
+    pids
+}
+
+/// Link each element to the next, saying which pair failed if one does. GStreamer's own error
+/// for this doesn't.
+fn link_chain(elts: &[&Element]) -> anyhow::Result<()> {
+    for pair in elts.windows(2) {
+        pair[0].link(pair[1]).with_context(|| {
+            format!(
+                "failed to link {} -> {}",
+                describe(pair[0]),
+                describe(pair[1])
+            )
+        })?;
+    }
+    Ok(())
+}
+
+/// An element's name and what kind it is, like "videoscale0 (videoscale)", for error messages.
+fn describe(elt: &Element) -> String {
+    match elt.factory() {
+        Some(factory) => format!("{} ({})", elt.name(), factory.name()),
+        None => elt.name().to_string(),
+    }
+}
+
+/// Add a chain of elements to the pipeline, fed from a new queue on the given tee.
+fn add_branch(pipeline: &Pipeline, tee: &Element, elts: Vec<Element>) -> anyhow::Result<()> {
+    link_branch(pipeline, tee, make_element("queue")?, elts)
+}
+
+/// Add a chain of elements to the pipeline, fed from the given queue on the given tee.
+fn link_branch(
+    pipeline: &Pipeline,
+    tee: &Element,
+    queue: Element,
+    elts: Vec<Element>,
+) -> anyhow::Result<()> {
+    let mut branch = vec![queue];
+    branch.extend(elts);
+    let branch: Vec<&Element> = branch.iter().collect();
+    pipeline
+        .add_many(&branch)
+        .context("failed to add branch elements to pipeline")?;
+    link_chain(&branch)?;
+    tee.link(branch[0])
+        .context("failed to link tee to branch")?;
+    Ok(())
+}
+
+/// Add what goes between the encoder and the appsink: the sink queue, if there is one, and the
+/// tee for recording, if that's enabled. Returns the tee, and the queue and rest of the recording
+/// branch to add to it once the rest is linked.
+fn sink_queue(
+    config: &Config,
+    elts: &mut Vec<Element>,
+    record_drops: &Arc<AtomicU64>,
+) -> anyhow::Result<Option<(Element, Element, Vec<Element>)>> {
+    let Some(path) = &config.record else {
+        if let Some(n) = config.sink_queue {
+            elts.push(sized_queue(n)?);
+        }
+        return Ok(None);
+    };
+    let tee = make_element("tee")?;
+    elts.push(tee.clone());
+    // The appsink is on a branch of its own, so it needs a queue anyway.
+    elts.push(match config.sink_queue {
+        Some(n) => sized_queue(n)?,
+        None => make_element("queue")?,
+    });
+    let filesink = ElementFactory::make("filesink")
+        .property("location", path.to_str())
+        .build()
+        .context("failed to make filesink")?;
+    info!("recording to {path:?}");
+    Ok(Some((
+        tee,
+        record_queue(config.record_overflow, record_drops)?,
+        vec![make_element("avimux")?, filesink],
+    )))
+}
+
+/// The queue the recording is fed through, which holds queue's usual second or so of video.
+/// With `RecordOverflow::Block`, a full queue makes the tee wait, and so the appsink and every
+/// stream with it. Otherwise, frames that don't fit are left out of the recording, and counted
+/// in `drops`.
+fn record_queue(overflow: RecordOverflow, drops: &Arc<AtomicU64>) -> anyhow::Result<Element> {
+    let queue = make_element("queue")?;
+    if overflow == RecordOverflow::Drop {
+        queue.set_property_from_str("leaky", "upstream");
+        let drops = drops.clone();
+        // With leaky=upstream, there's one of these for each frame that doesn't fit.
+        queue.connect("overrun", false, move |_| {
+            drops.fetch_add(1, Ordering::Relaxed);
+            None
+        });
+    }
+    Ok(queue)
+}
+
+/// A queue that holds up to `buffers` frames, however big they are or however much time they
+/// cover.
+fn sized_queue(buffers: u32) -> anyhow::Result<Element> {
+    ElementFactory::make("queue")
+        .property("max-size-buffers", buffers)
+        .property("max-size-bytes", 0u32)
+        .property("max-size-time", 0u64)
+        .build()
+        .context("failed to make queue")
+}
+
+fn capsfilter(caps: &Caps) -> anyhow::Result<Element> {
+    ElementFactory::make("capsfilter")
+        .property("caps", caps)
+        .build()
+        .context("failed to make capsfilter")
+}
+
+/// Decode a JPEG frame and encode it again with the given changes applied.
+///
+/// This uses its own short-lived pipeline, so it doesn't affect the main one. It blocks until the
+/// frame is done, so call it from a blocking-friendly context.
+pub fn reencode_jpeg(jpeg: Bytes, changes: &Reencode) -> anyhow::Result<Bytes> {
+    let pipeline = Pipeline::new(None);
+
+    let src = AppSrc::builder()
+        .caps(&Caps::builder("image/jpeg").build())
+        .format(Format::Time)
+        .build();
+    let mut elts = vec![src.clone().upcast(), make_element("jpegdec")?];
+    if changes.rotation != Rotation::None {
+        elts.push(
+            ElementFactory::make("videoflip")
+                .property_from_str("method", changes.rotation.videoflip_method())
+                .build()
+                .context("failed to make videoflip")?,
+        );
+    }
+    if changes.width.is_some() || changes.height.is_some() {
+        // videoscale picks whichever isn't given to keep the aspect ratio. With both, it adds
+        // borders rather than stretching, as long as the pixels have to stay square.
+        let mut caps = Caps::builder("video/x-raw");
+        if let Some(width) = changes.width {
+            caps = caps.field("width", i32::try_from(width).context("width out of range")?);
+        }
+        if let Some(height) = changes.height {
+            caps = caps.field(
+                "height",
+                i32::try_from(height).context("height out of range")?,
+            );
+        }
+        if changes.width.is_some() && changes.height.is_some() {
+            caps = caps.field("pixel-aspect-ratio", Fraction::new(1, 1));
+        }
+        elts.push(make_element("videoscale")?);
+        elts.push(capsfilter(&caps.build())?);
+    }
+    let mut enc = ElementFactory::make("jpegenc");
+    if let Some(quality) = changes.quality {
+        enc = enc.property("quality", quality);
+    }
+    elts.push(enc.build().context("failed to make jpegenc")?);
+    let sink = AppSink::builder()
+        .caps(&Caps::builder("image/jpeg").build())
+        .sync(false)
+        .build();
+    elts.push(sink.clone().upcast());
+
+    let elts: Vec<&Element> = elts.iter().collect();
+    pipeline
+        .add_many(&elts)
+        .context("failed to add elements to re-encode pipeline")?;
+    link_chain(&elts).context("failed to link re-encode pipeline")?;
+
+    pipeline
+        .set_state(State::Playing)
+        .context("failed to set re-encode pipeline to Playing state")?;
+
+    let result = src
+        .push_buffer(Buffer::from_slice(jpeg))
+        .and_then(|_| src.end_of_stream())
+        .context("failed to push frame into re-encode pipeline")
+        .and_then(|_| {
+            sink.try_pull_sample(ClockTime::from_seconds(5))
+                .context("re-encode pipeline produced no frame")
+        })
+        .and_then(|sample| {
+            let buf = sample.buffer().context("re-encoded sample has no buffer")?;
+            let map = buf
+                .map_readable()
+                .context("failed to map re-encoded buffer")?;
+            Ok(Bytes::copy_from_slice(map.as_slice()))
+        });
+
+    if let Err(e) = pipeline.set_state(State::Null) {
+        warn!("failed to stop re-encode pipeline: {e}");
+    }
+
+    result
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// A test pattern, with nothing else turned on.
+    pub(crate) fn test_config() -> Config {
+        Config {
+            source: VideoSource::Test("smpte".to_owned()),
+            v4l2_props: vec![],
+            size: Some((320, 240)),
+            scale: true,
+            filter: None,
+            flip: Flip::None,
+            rotation: Rotation::None,
+            framerate: None,
+            aspect: None,
+            pad_color: "black".to_owned(),
+            grayscale: false,
+            codec: Codec::Mjpeg,
+            encoder: None,
+            encode_pipeline: None,
+            jpeg_idct: None,
+            quality: None,
+            chroma_subsampling: None,
+            encode_queue: None,
+            sink_queue: None,
+            eos_timeout: Duration::ZERO,
+            latency: None,
+            system_clock: false,
+            caption_file: None,
+            timestamp_overlay: None,
+            timestamp_format: String::new(),
+            preview: false,
+            sync: true,
+            raw_frames: false,
+            motion: false,
+            thumbnail_width: None,
+            hls_dir: None,
+            record: None,
+            record_overflow: RecordOverflow::Drop,
+            passthrough: false,
+        }
+    }
+
+    /// Whether GStreamer and the elements `config` needs are installed. Tests that build a real
+    /// pipeline pass without checking anything when they aren't.
+    pub(crate) fn can_build(config: &Config) -> bool {
+        let ok = gstreamer::init().is_ok() && Video::check_elements(config).is_ok();
+        if !ok {
+            eprintln!("GStreamer or some of its plugins aren't installed; skipping");
+        }
+        ok
+    }
+
+    /// The elements in the pipeline made by the given factory, however deep in bins they are.
+    fn elements_from(video: &Video, factory: &str) -> Vec<Element> {
+        video
+            .pipeline
+            .iterate_recurse()
+            .into_iter()
+            .filter_map(Result::ok)
+            .filter(|e| e.factory().is_some_and(|f| f.name() == factory))
+            .collect()
+    }
+
+    #[test]
+    fn framerate_adds_a_linked_videorate() {
+        let config = Config {
+            framerate: Some(5),
+            ..test_config()
+        };
+        if !can_build(&config) {
+            return;
+        }
+        let video = Video::new(&config).unwrap();
+        let [videorate] = &elements_from(&video, "videorate")[..] else {
+            panic!("expected one videorate");
+        };
+        let next = videorate
+            .static_pad("src")
+            .and_then(|pad| pad.peer())
+            .and_then(|peer| peer.parent_element())
+            .expect("videorate isn't linked to anything");
+        assert_eq!(next.factory().unwrap().name(), "capsfilter");
+        let caps = next.property::<Caps>("caps");
+        let framerate = caps.structure(0).unwrap().get::<Fraction>("framerate");
+        assert_eq!(framerate, Ok(Fraction::new(5, 1)));
+
+        let video = Video::new(&test_config()).unwrap();
+        assert!(elements_from(&video, "videorate").is_empty());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn recording_is_finished_when_stopping() {
+        let path = std::env::temp_dir().join(format!("gst-mjpg-test-{}.avi", std::process::id()));
+        let config = Config {
+            record: Some(path.clone()),
+            ..test_config()
+        };
+        if !can_build(&config) {
+            return;
+        }
+        let video = Arc::new(Video::new(&config).unwrap());
+        video.spawn(video.clone().foreach_message(|_, _| ()));
+        video.start().unwrap();
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        video.shutdown().await.unwrap();
+
+        let avi = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(avi.starts_with(b"RIFF"), "not an AVI file");
+        // avimux only writes the index, which players need to seek, once it sees the end.
+        assert!(
+            avi.windows(4).any(|w| w == b"idx1"),
+            "the recording has no index"
+        );
+    }
 }